@@ -51,7 +51,7 @@ impl SocketServer {
 
         pyo3_asyncio::tokio::into_coroutine(py, async move {
             let (socket, _) = listener.accept().await?;
-            let socket = Socket::new(socket);
+            let socket = Socket::new(socket.into());
 
             Ok(Python::with_gil(|gil| socket.into_py(gil)))
         })
@@ -1,6 +1,7 @@
 use crate::socket::SocketAddress;
 use pyo3::exceptions::PyOSError;
 use pyo3::prelude::*;
+use socksx::SocksStream;
 use std::os::unix::io::AsRawFd;
 use std::sync::Arc;
 use tokio::io::AsyncWriteExt;
@@ -9,7 +10,7 @@ use tokio::sync::RwLock;
 
 #[pyclass]
 pub struct Socket {
-    pub(crate) inner: Arc<RwLock<TcpStream>>,
+    pub(crate) inner: Arc<RwLock<SocksStream>>,
     pub(crate) function: Option<PyObject>,
 }
 
@@ -17,7 +18,7 @@ impl Socket {
     ///
     ///
     ///
-    pub fn new(socket: TcpStream) -> Self {
+    pub fn new(socket: SocksStream) -> Self {
         let inner = Arc::new(RwLock::new(socket));
         Self { inner, function: None }
     }
@@ -37,7 +38,7 @@ impl Socket {
             let socket = TcpStream::connect(address.inner)
                 .await
                 .map_err(|_| PyOSError::new_err("TODO: custom errors"))
-                .map(Socket::new)?;
+                .map(|socket| Socket::new(socket.into()))?;
 
             Ok(Python::with_gil(|gil| socket.into_py(gil)))
         })
@@ -122,9 +123,9 @@ impl Socket {
         pyo3_asyncio::tokio::into_coroutine(py, async move {
             // TODO: try to use socksx::try_read_initial_data.
             let mut initial_data = Vec::with_capacity(2usize.pow(14)); // 16KB is the max
-            inner.read().await.readable().await?;
+            inner.read().await.tcp().readable().await?;
 
-            let bytes: Vec<u8> = match inner.read().await.try_read_buf(&mut initial_data) {
+            let bytes: Vec<u8> = match inner.read().await.tcp().try_read_buf(&mut initial_data) {
                 Ok(0) => vec![],
                 Ok(_) => initial_data,
                 Err(e) => {
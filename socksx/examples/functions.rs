@@ -5,7 +5,7 @@ use chacha20::{ChaCha20, Key, Nonce};
 use clap::Parser;
 use dotenv::dotenv;
 use pin_project_lite::pin_project;
-use socksx::{self, Socks5Handler, Socks6Handler, SocksHandler};
+use socksx::{self, Socks5Handler, Socks6Handler, SocksHandler, SocksStream};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
@@ -74,7 +74,7 @@ async fn process(
     handler: Handler,
     function: Function,
 ) -> Result<()> {
-    let mut source = source;
+    let mut source: SocksStream = source.into();
     let mut destination = handler.setup(&mut source).await?;
 
     // Apply a function to ingress traffic.
@@ -0,0 +1,302 @@
+use crate::metrics::{MetricsHook, Protocol};
+use crate::Address;
+use anyhow::Result;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, TrySendError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Describes a proxied connection, once it's closed. Fields the handler didn't get to, e.g.,
+/// the destination of a client that failed to authenticate, are left empty.
+#[derive(Clone, Debug)]
+pub struct AccessRecord {
+    pub client: SocketAddr,
+    pub user: Option<String>,
+    pub protocol: Protocol,
+    pub command: Option<String>,
+    pub destination: Option<Address>,
+    pub resolved: Option<IpAddr>,
+    pub chain: Vec<String>,
+    pub reply: Option<u16>,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub duration: Duration,
+}
+
+impl AccessRecord {
+    /// Creates a record for a client that just connected.
+    pub fn new(
+        protocol: Protocol,
+        client: SocketAddr,
+    ) -> Self {
+        AccessRecord {
+            client,
+            user: None,
+            protocol,
+            command: None,
+            destination: None,
+            resolved: None,
+            chain: vec![],
+            reply: None,
+            bytes_up: 0,
+            bytes_down: 0,
+            duration: Duration::default(),
+        }
+    }
+
+    /// The fields of the record, in order, with the values as text. Empty fields are `None`.
+    fn fields(&self) -> Vec<(&'static str, Option<String>)> {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let chain = Some(self.chain.join(",")).filter(|c| !c.is_empty());
+
+        vec![
+            ("time", Some(format!("{:.3}", time.as_secs_f64()))),
+            ("client", Some(self.client.to_string())),
+            ("user", self.user.clone()),
+            ("protocol", Some(self.protocol.as_str().to_string())),
+            ("command", self.command.clone()),
+            ("destination", self.destination.as_ref().map(Address::to_string)),
+            ("resolved", self.resolved.map(|ip| ip.to_string())),
+            ("chain", chain),
+            ("reply", self.reply.map(|code| code.to_string())),
+            ("bytes_up", Some(self.bytes_up.to_string())),
+            ("bytes_down", Some(self.bytes_down.to_string())),
+            ("duration_ms", Some(self.duration.as_millis().to_string())),
+        ]
+    }
+
+    /// Formats the record as a JSON object, on a single line.
+    pub fn to_json(&self) -> String {
+        let numeric = ["time", "reply", "bytes_up", "bytes_down", "duration_ms"];
+
+        let mut json = String::from("{");
+        for (i, (key, value)) in self.fields().into_iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            let _ = match value {
+                Some(value) if numeric.contains(&key) => write!(json, "\"{}\":{}", key, value),
+                Some(value) => write!(json, "\"{}\":\"{}\"", key, escape_json(&value)),
+                None => write!(json, "\"{}\":null", key),
+            };
+        }
+        json.push('}');
+
+        json
+    }
+
+    /// Formats the record as logfmt, e.g., `client=10.0.0.1:5000 user=alice ...`. Empty fields
+    /// are left out.
+    pub fn to_logfmt(&self) -> String {
+        self.fields()
+            .into_iter()
+            .filter_map(|(key, value)| value.map(|v| format!("{}={}", key, quote_logfmt(&v))))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+fn escape_json(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+fn quote_logfmt(value: &str) -> String {
+    if value.is_empty() || value.chars().any(|c| c == ' ' || c == '=' || c == '"' || c.is_control()) {
+        format!("\"{}\"", escape_json(value))
+    } else {
+        value.to_string()
+    }
+}
+
+/// How access records are written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Json,
+    Logfmt,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format {
+            "json" => Ok(LogFormat::Json),
+            "logfmt" => Ok(LogFormat::Logfmt),
+            _ => bail!("Unknown access log format: {}, use \"json\" or \"logfmt\".", format),
+        }
+    }
+}
+
+/// Upper bound on the number of lines waiting to be written, past it lines are dropped.
+const MAX_PENDING_LINES: usize = 1024;
+
+/// Writes a line per proxied connection, once it's closed. Give it to the handlers with
+/// `with_metrics`. Lines are written on a dedicated thread, so a slow writer doesn't hold up
+/// the handlers; if it falls too far behind, lines are dropped, with a warning. Dropping the
+/// log waits for the pending lines to be written.
+pub struct AccessLog {
+    format: LogFormat,
+    lines: mpsc::SyncSender<String>,
+    dropped: Arc<AtomicU64>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl AccessLog {
+    /// Creates an access log that writes to stdout.
+    pub fn new(format: LogFormat) -> Self {
+        Self::with_writer(format, io::stdout())
+    }
+
+    /// Creates an access log that writes to `writer`, e.g., a file.
+    pub fn with_writer<W: Write + Send + 'static>(
+        format: LogFormat,
+        mut writer: W,
+    ) -> Self {
+        let (lines, received) = mpsc::sync_channel::<String>(MAX_PENDING_LINES);
+        let dropped = Arc::new(AtomicU64::new(0));
+
+        let counter = dropped.clone();
+        let writer = thread::spawn(move || {
+            for line in received {
+                if let Err(error) = writeln!(writer, "{}", line).and_then(|_| writer.flush()) {
+                    warn!("Failed to write the access log: {}", error);
+                }
+
+                let dropped = counter.swap(0, Ordering::Relaxed);
+                if dropped > 0 {
+                    warn!("Dropped {} lines of the access log, it couldn't be written fast enough", dropped);
+                }
+            }
+        });
+
+        AccessLog {
+            format,
+            lines,
+            dropped,
+            writer: Some(writer),
+        }
+    }
+}
+
+impl Drop for AccessLog {
+    fn drop(&mut self) {
+        // The thread ends once the sender is gone, and the lines before it are written.
+        drop(std::mem::replace(&mut self.lines, mpsc::sync_channel(0).0));
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl MetricsHook for AccessLog {
+    fn access(
+        &self,
+        record: &AccessRecord,
+    ) {
+        let line = match self.format {
+            LogFormat::Json => record.to_json(),
+            LogFormat::Logfmt => record.to_logfmt(),
+        };
+
+        // Otherwise, the thread is gone, e.g., because the writer panicked.
+        if let Err(TrySendError::Full(_)) = self.lines.try_send(line) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Collects what's written, so it can be checked after the log is dropped.
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(
+            &mut self,
+            data: &[u8],
+        ) -> io::Result<usize> {
+            self.0.lock().unwrap().write(data)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    pub fn formats() -> Result<()> {
+        let mut record = AccessRecord::new(Protocol::Socks5, "10.0.0.1:5000".parse()?);
+        record.user = Some(String::from("alice \"a\""));
+        record.command = Some(String::from("Connect"));
+        record.destination = Some(Address::new("example.com", 443));
+        record.chain = vec![String::from("socks6://10.0.0.2:1080")];
+        record.reply = Some(0);
+        record.bytes_up = 10;
+
+        let json = record.to_json();
+        assert!(json.starts_with("{\"time\":"));
+        assert!(json.contains(",\"client\":\"10.0.0.1:5000\",\"user\":\"alice \\\"a\\\"\",\"protocol\":\"socks5\","));
+        assert!(json.contains(",\"resolved\":null,\"chain\":\"socks6://10.0.0.2:1080\",\"reply\":0,\"bytes_up\":10,"));
+
+        let logfmt = record.to_logfmt();
+        assert!(logfmt.contains(" client=10.0.0.1:5000 user=\"alice \\\"a\\\"\" protocol=socks5 command=Connect "));
+        assert!(logfmt.contains(" destination=example.com:443 chain=socks6://10.0.0.2:1080 reply=0 bytes_up=10 "));
+
+        assert_eq!("logfmt".parse::<LogFormat>()?, LogFormat::Logfmt);
+        assert!("xml".parse::<LogFormat>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn writes() -> Result<()> {
+        let buffer = Buffer::default();
+        let log = AccessLog::with_writer(LogFormat::Logfmt, buffer.clone());
+
+        let record = AccessRecord::new(Protocol::Socks4, "10.0.0.1:5000".parse()?);
+        log.access(&record);
+        log.access(&record);
+        drop(log);
+
+        let written = String::from_utf8(buffer.0.lock().unwrap().clone())?;
+        assert_eq!(written.lines().count(), 2);
+        assert!(written.lines().all(|line| line.contains(" client=10.0.0.1:5000 protocol=socks4 ")));
+
+        // A writer that falls behind loses lines, rather than holding up the handlers.
+        let buffer = Buffer::default();
+        let log = AccessLog::with_writer(LogFormat::Json, buffer.clone());
+        let stalled = buffer.0.lock().unwrap();
+        for _ in 0..MAX_PENDING_LINES + 10 {
+            log.access(&record);
+        }
+        assert!(log.dropped.load(Ordering::Relaxed) >= 9);
+        drop(stalled);
+        drop(log);
+
+        let written = String::from_utf8(buffer.0.lock().unwrap().clone())?;
+        assert!(written.lines().count() <= MAX_PENDING_LINES + 1);
+
+        Ok(())
+    }
+}
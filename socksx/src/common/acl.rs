@@ -0,0 +1,384 @@
+use crate::{Address, Error};
+use anyhow::Result;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+pub use ipnet::IpNet;
+
+/// Whether a rule lets a request through or not.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+/// The commands rules can be restricted to. HTTP CONNECT, and forwarded HTTP requests, count as
+/// CONNECT.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    Connect,
+    Bind,
+    UdpAssociate,
+}
+
+/// A destination a rule applies to: a network, or a domain name pattern where `*` matches any
+/// sequence of characters, e.g., `*.example.com`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DestinationPattern {
+    Network(IpNet),
+    Domain(String),
+}
+
+impl FromStr for DestinationPattern {
+    type Err = anyhow::Error;
+
+    fn from_str(pattern: &str) -> Result<Self> {
+        if let Ok(network) = parse_network(pattern) {
+            return Ok(DestinationPattern::Network(network));
+        }
+
+        let valid = |c: char| c.is_ascii_alphanumeric() || ['-', '.', '*', '_'].contains(&c);
+        ensure!(
+            !pattern.is_empty() && pattern.chars().all(valid),
+            "Destination pattern is neither a network nor a domain name: {}",
+            pattern
+        );
+
+        Ok(DestinationPattern::Domain(pattern.to_ascii_lowercase()))
+    }
+}
+
+impl DestinationPattern {
+    fn matches(
+        &self,
+        destination: &Address,
+        resolved: Option<IpAddr>,
+    ) -> bool {
+        match (self, destination) {
+            (DestinationPattern::Network(network), Address::Ip(addr)) => network.contains(&addr.ip()),
+            (DestinationPattern::Network(network), Address::Domainname { .. }) => {
+                resolved.map(|ip| network.contains(&ip)).unwrap_or(false)
+            }
+            (DestinationPattern::Domain(pattern), Address::Domainname { host, .. }) => {
+                glob_match(pattern.as_bytes(), host.to_ascii_lowercase().as_bytes())
+            }
+            (DestinationPattern::Domain(_), Address::Ip(_)) => false,
+        }
+    }
+}
+
+/// Parses a network, also accepting a single address (e.g. `10.0.0.1`).
+pub fn parse_network(network: &str) -> Result<IpNet> {
+    if let Ok(ip) = network.parse::<IpAddr>() {
+        return Ok(IpNet::from(ip));
+    }
+
+    network
+        .parse()
+        .map_err(|_| anyhow!("Not a network or address: {}", network))
+}
+
+/// Parses a port range, e.g., `8000-8080`, or a single port.
+pub fn parse_ports(ports: &str) -> Result<RangeInclusive<u16>> {
+    let range = match ports.split_once('-') {
+        Some((start, end)) => start.trim().parse()?..=end.trim().parse()?,
+        None => {
+            let port = ports.trim().parse()?;
+            port..=port
+        }
+    };
+    ensure!(!range.is_empty(), "Port range is empty: {}", ports);
+
+    Ok(range)
+}
+
+/// A rule matches a request if every condition it has matches, e.g., a rule without sources
+/// matches any source. Within a condition, one of the listed values must match.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    action: Action,
+    sources: Vec<IpNet>,
+    users: Vec<String>,
+    destinations: Vec<DestinationPattern>,
+    ports: Vec<RangeInclusive<u16>>,
+    commands: Vec<Command>,
+}
+
+impl Rule {
+    /// Creates a rule that takes `action` on any request, until conditions are added.
+    pub fn new(action: Action) -> Self {
+        Rule {
+            action,
+            sources: vec![],
+            users: vec![],
+            destinations: vec![],
+            ports: vec![],
+            commands: vec![],
+        }
+    }
+
+    /// Creates a rule that allows matching requests.
+    pub fn allow() -> Self {
+        Self::new(Action::Allow)
+    }
+
+    /// Creates a rule that denies matching requests.
+    pub fn deny() -> Self {
+        Self::new(Action::Deny)
+    }
+
+    /// Matches requests from clients in this network.
+    pub fn with_source(
+        mut self,
+        network: IpNet,
+    ) -> Self {
+        self.sources.push(network);
+        self
+    }
+
+    /// Matches requests of this authenticated user.
+    pub fn with_user<S: Into<String>>(
+        mut self,
+        user: S,
+    ) -> Self {
+        self.users.push(user.into());
+        self
+    }
+
+    /// Matches requests to destinations that match this pattern.
+    pub fn with_destination(
+        mut self,
+        pattern: DestinationPattern,
+    ) -> Self {
+        self.destinations.push(pattern);
+        self
+    }
+
+    /// Matches requests to destination ports in this range.
+    pub fn with_ports(
+        mut self,
+        ports: RangeInclusive<u16>,
+    ) -> Self {
+        self.ports.push(ports);
+        self
+    }
+
+    /// Matches requests with this command.
+    pub fn with_command(
+        mut self,
+        command: Command,
+    ) -> Self {
+        self.commands.push(command);
+        self
+    }
+
+    /// Whether the client and command conditions match, regardless of the destination.
+    fn matches_client(
+        &self,
+        request: &AccessRequest,
+    ) -> bool {
+        let user = |u: &String| Some(u.as_str()) == request.user;
+
+        (self.sources.is_empty() || self.sources.iter().any(|s| s.contains(&request.source)))
+            && (self.users.is_empty() || self.users.iter().any(user))
+            && (self.commands.is_empty() || self.commands.contains(&request.command))
+    }
+
+    fn matches_destination(
+        &self,
+        destination: &Address,
+        resolved: Option<IpAddr>,
+    ) -> bool {
+        (self.destinations.is_empty() || self.destinations.iter().any(|d| d.matches(destination, resolved)))
+            && (self.ports.is_empty() || self.ports.iter().any(|p| p.contains(&destination.port())))
+    }
+
+    fn has_destinations(&self) -> bool {
+        !self.destinations.is_empty() || !self.ports.is_empty()
+    }
+
+    fn has_networks(&self) -> bool {
+        self.destinations.iter().any(|d| matches!(d, DestinationPattern::Network(_)))
+    }
+}
+
+/// What a rule is matched against. The destination of a UDP association is unknown until its
+/// datagrams arrive, each of those is checked as a request of its own.
+#[derive(Clone, Copy, Debug)]
+pub struct AccessRequest<'a> {
+    pub source: IpAddr,
+    pub user: Option<&'a str>,
+    pub destination: Option<&'a Address>,
+    pub command: Command,
+}
+
+/// Rules the handlers consult before they act on a request. The first matching rule decides,
+/// requests no rule matches get the default action.
+#[derive(Clone, Debug)]
+pub struct AccessControl {
+    rules: Vec<Rule>,
+    default: Action,
+}
+
+impl Default for AccessControl {
+    fn default() -> Self {
+        Self::new(vec![])
+    }
+}
+
+impl AccessControl {
+    /// Creates an access control list that allows whatever the rules don't deny.
+    pub fn new(rules: Vec<Rule>) -> Self {
+        AccessControl {
+            rules,
+            default: Action::Allow,
+        }
+    }
+
+    /// Sets the action for requests no rule matches.
+    pub fn with_default(
+        mut self,
+        default: Action,
+    ) -> Self {
+        self.default = default;
+        self
+    }
+
+    /// Decides on a request. Domain names are resolved for rules with networks, so that a
+    /// network can't be reached by its domain names; the resolution may differ from the one
+    /// made when connecting, though.
+    ///
+    /// Without a destination, rules with destinations or ports don't decide: the request is
+    /// allowed if such a rule could allow some destination before a rule denies all of them.
+    pub async fn check(
+        &self,
+        request: &AccessRequest<'_>,
+    ) -> Action {
+        let destination = match request.destination {
+            Some(destination) => destination,
+            None => {
+                return self
+                    .rules
+                    .iter()
+                    .filter(|rule| rule.matches_client(request))
+                    .find(|rule| !rule.has_destinations() || rule.action == Action::Allow)
+                    .map(|rule| rule.action)
+                    .unwrap_or(self.default);
+            }
+        };
+
+        let resolved = match destination {
+            Address::Domainname { .. } if self.rules.iter().any(Rule::has_networks) => {
+                crate::resolve_addr(destination.to_string()).await.ok().map(|a| a.ip())
+            }
+            _ => None,
+        };
+
+        self.rules
+            .iter()
+            .find(|rule| rule.matches_client(request) && rule.matches_destination(destination, resolved))
+            .map(|rule| rule.action)
+            .unwrap_or(self.default)
+    }
+
+    /// Like `check`, but fails with `Error::NotAllowed` if the request is denied.
+    pub async fn authorize(
+        &self,
+        request: &AccessRequest<'_>,
+    ) -> Result<()> {
+        if self.check(request).await == Action::Deny {
+            let user = request.user.unwrap_or("anonymous");
+            let destination = request.destination.map(Address::to_string).unwrap_or_else(|| String::from("any"));
+            let reason = format!("{:?} to {} for {}", request.command, destination, user);
+            bail!(Error::NotAllowed(reason));
+        }
+
+        Ok(())
+    }
+}
+
+/// Matches a (lowercase) glob pattern, where `*` matches any sequence, against a name.
+fn glob_match(
+    pattern: &[u8],
+    name: &[u8],
+) -> bool {
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+
+    while n < name.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == name[n] {
+            p += 1;
+            n += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // Let the last `*` match one more character.
+            p = star + 1;
+            n = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[tokio::test]
+    pub async fn first_match() -> Result<()> {
+        let access_control = AccessControl::new(vec![
+            Rule::deny().with_destination("*.internal".parse()?),
+            Rule::deny().with_destination("10.0.0.0/8".parse()?).with_ports(parse_ports("1-1023")?),
+            Rule::allow().with_user("alice").with_command(Command::Bind),
+            Rule::allow().with_source(parse_network("192.168.0.0/16")?),
+        ])
+        .with_default(Action::Deny);
+        let access_control = &access_control;
+
+        let check = |source: &str, user, destination: &str, command| {
+            let destination = Address::try_from(destination.to_string()).unwrap();
+            let source = source.parse().unwrap();
+            async move {
+                let request = AccessRequest {
+                    source,
+                    user,
+                    destination: Some(&destination),
+                    command,
+                };
+                access_control.check(&request).await
+            }
+        };
+
+        assert_eq!(check("192.168.1.1", None, "db.INTERNAL:443", Command::Connect).await, Action::Deny);
+        assert_eq!(check("192.168.1.1", None, "10.1.2.3:443", Command::Connect).await, Action::Deny);
+        assert_eq!(check("192.168.1.1", None, "10.1.2.3:8443", Command::Connect).await, Action::Allow);
+        assert_eq!(check("192.168.1.1", None, "example.com:443", Command::Connect).await, Action::Allow);
+        assert_eq!(check("172.16.0.1", None, "example.com:443", Command::Connect).await, Action::Deny);
+        assert_eq!(check("172.16.0.1", Some("alice"), "example.com:443", Command::Bind).await, Action::Allow);
+        assert_eq!(check("172.16.0.1", Some("bob"), "example.com:443", Command::Bind).await, Action::Deny);
+
+        // UDP associations are allowed unless every destination is denied, datagrams are checked on their own.
+        let association = |source: &str| AccessRequest {
+            source: source.parse().unwrap(),
+            user: None,
+            destination: None,
+            command: Command::UdpAssociate,
+        };
+        assert_eq!(access_control.check(&association("192.168.1.1")).await, Action::Allow);
+        assert_eq!(access_control.check(&association("172.16.0.1")).await, Action::Deny);
+
+        assert!(glob_match(b"*.example.com", b"a.b.example.com"));
+        assert!(!glob_match(b"*.example.com", b"example.com"));
+        assert!("not a pattern!".parse::<DestinationPattern>().is_err());
+        assert!(parse_ports("9-1").is_err());
+
+        Ok(())
+    }
+}
@@ -1,7 +1,8 @@
-use crate::{constants::*, Credentials};
+use crate::{constants::*, Credentials, Error};
 use anyhow::Result;
+use percent_encoding::{percent_decode_str, percent_encode, NON_ALPHANUMERIC};
 use std::convert::{TryFrom, TryInto};
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use tokio::io::{AsyncRead, AsyncReadExt};
 use url::Url;
 
@@ -11,6 +12,7 @@ pub struct ProxyAddress {
     pub host: String,
     pub port: u16,
     pub credentials: Option<Credentials>,
+    pub tls: bool,
 }
 
 impl ProxyAddress {
@@ -25,17 +27,42 @@ impl ProxyAddress {
             host,
             port,
             credentials,
+            tls: false,
         }
     }
 
+    /// Formats the address as a URL that includes the credentials, if there are any. These are
+    /// percent-encoded, and decoded again when the URL is parsed.
+    pub fn to_string_with_credentials(&self) -> String {
+        let credentials = match &self.credentials {
+            Some(credentials) => credentials,
+            None => return self.to_string(),
+        };
+
+        let username = percent_encode(&credentials.username, NON_ALPHANUMERIC);
+        let password = percent_encode(&credentials.password, NON_ALPHANUMERIC);
+        let tls = if self.tls { "+tls" } else { "" };
+        format!(
+            "socks{}{}://{}:{}@{}:{}",
+            self.socks_version, tls, username, password, self.host, self.port
+        )
+    }
+
     pub fn root() -> Self {
         ProxyAddress::new(6, String::from("root"), 1080, None)
     }
+
+    /// Speaks TLS to the proxy, i.e., the `socks5+tls` and `socks6+tls` schemes.
+    pub fn with_tls(mut self) -> Self {
+        self.tls = true;
+        self
+    }
 }
 
 impl ToString for ProxyAddress {
     fn to_string(&self) -> String {
-        format!("socks{}://{}:{}", self.socks_version, self.host, self.port)
+        let tls = if self.tls { "+tls" } else { "" };
+        format!("socks{}{}://{}:{}", self.socks_version, tls, self.host, self.port)
     }
 }
 
@@ -51,30 +78,37 @@ impl TryFrom<String> for ProxyAddress {
         );
         ensure!(proxy_addr.port().is_some(), "Missing explicit port in proxy address.");
 
-        let socks_version = match proxy_addr.scheme() {
+        let (scheme, tls) = match proxy_addr.scheme().strip_suffix("+tls") {
+            Some(scheme) => (scheme, true),
+            None => (proxy_addr.scheme(), false),
+        };
+        let socks_version = match scheme {
             "socks5" => SOCKS_VER_5,
             "socks6" => SOCKS_VER_6,
             scheme => bail!("Unrecognized SOCKS scheme: {}", scheme),
         };
 
-        let username = proxy_addr.username();
+        let username: Vec<u8> = percent_decode_str(proxy_addr.username()).collect();
         let credentials = if username.is_empty() {
             None
         } else {
-            let password = proxy_addr.password().unwrap_or_default();
+            let password = percent_decode_str(proxy_addr.password().unwrap_or_default()).collect();
             Some(Credentials::new(username, password))
         };
 
-        Ok(Self::new(
-            socks_version,
-            proxy_addr.host().map(|h| h.to_string()).unwrap(),
-            proxy_addr.port().unwrap(),
-            credentials,
-        ))
+        Ok(Self {
+            tls,
+            ..Self::new(
+                socks_version,
+                proxy_addr.host().map(|h| h.to_string()).unwrap(),
+                proxy_addr.port().unwrap(),
+                credentials,
+            )
+        })
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Address {
     Domainname { host: String, port: u16 },
     Ip(SocketAddr),
@@ -97,6 +131,19 @@ impl Address {
         }
     }
 
+    /// The unspecified IPv4 address (`0.0.0.0:0`), for replies without a meaningful binding.
+    pub fn unspecified() -> Self {
+        Address::Ip(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0))
+    }
+
+    /// The port of the address.
+    pub fn port(&self) -> u16 {
+        match self {
+            Address::Ip(addr) => addr.port(),
+            Address::Domainname { port, .. } => *port,
+        }
+    }
+
     ///
     ///
     ///
@@ -203,7 +250,7 @@ where
 
             String::from_utf8_lossy(&dst_addr[..]).to_string()
         }
-        _ => unreachable!(),
+        address_type => bail!(Error::Protocol(format!("Unsupported address type: {}", address_type))),
     };
 
     // Read destination port.
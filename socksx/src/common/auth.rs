@@ -0,0 +1,210 @@
+use crate::Credentials;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Verifies the username/password credentials presented by a client. On success,
+/// the returned identity is what the rest of the connection handling refers to.
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    /// Returns the identity of the client, or `None` if the credentials are rejected.
+    async fn authenticate(
+        &self,
+        credentials: &Credentials,
+    ) -> Result<Option<String>>;
+}
+
+#[async_trait]
+impl<A: Authenticator + ?Sized> Authenticator for Arc<A> {
+    async fn authenticate(
+        &self,
+        credentials: &Credentials,
+    ) -> Result<Option<String>> {
+        (**self).authenticate(credentials).await
+    }
+}
+
+/// Checks credentials against a fixed set of usernames and plaintext passwords.
+#[derive(Clone, Debug, Default)]
+pub struct StaticAuthenticator {
+    users: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl StaticAuthenticator {
+    /// Creates an authenticator from a map of usernames to passwords.
+    pub fn new<S: Into<Vec<u8>>>(users: HashMap<S, S>) -> Self {
+        let users = users.into_iter().map(|(u, p)| (u.into(), p.into())).collect();
+
+        StaticAuthenticator { users }
+    }
+}
+
+#[async_trait]
+impl Authenticator for StaticAuthenticator {
+    async fn authenticate(
+        &self,
+        credentials: &Credentials,
+    ) -> Result<Option<String>> {
+        let valid = match self.users.get(&credentials.username) {
+            Some(password) => constant_time_eq(password, &credentials.password),
+            None => false,
+        };
+
+        Ok(valid.then(|| String::from_utf8_lossy(&credentials.username).to_string()))
+    }
+}
+
+/// Checks credentials against an htpasswd-style file, i.e., `username:hash` lines.
+/// Supported hashes are bcrypt (`$2y$`, `$2b$`, `$2a$`) and SHA-1 (`{SHA}`).
+#[derive(Clone, Debug, Default)]
+pub struct HtpasswdAuthenticator {
+    users: HashMap<String, String>,
+}
+
+impl HtpasswdAuthenticator {
+    /// Loads the users from an htpasswd-style file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let contents =
+            std::fs::read_to_string(path).with_context(|| format!("Failed to read htpasswd file: {:?}", path))?;
+
+        Self::parse(&contents).with_context(|| format!("Invalid htpasswd file: {:?}", path))
+    }
+
+    /// Parses the users from the contents of an htpasswd-style file.
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut users = HashMap::new();
+
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some((username, hash)) = line.split_once(':') {
+                ensure!(
+                    is_supported_hash(hash),
+                    "Unsupported hash for user '{}' on line {}, use bcrypt or SHA-1.",
+                    username,
+                    number + 1
+                );
+
+                users.insert(username.to_string(), hash.to_string());
+            } else {
+                bail!("Line {} doesn't seperate username and hash by ':'.", number + 1);
+            }
+        }
+
+        Ok(HtpasswdAuthenticator { users })
+    }
+}
+
+#[async_trait]
+impl Authenticator for HtpasswdAuthenticator {
+    async fn authenticate(
+        &self,
+        credentials: &Credentials,
+    ) -> Result<Option<String>> {
+        let username = String::from_utf8_lossy(&credentials.username).to_string();
+        let hash = match self.users.get(&username) {
+            Some(hash) => hash.clone(),
+            None => return Ok(None),
+        };
+
+        // Verifying bcrypt hashes is deliberately slow, keep it off the runtime's worker threads.
+        let password = credentials.password.clone();
+        let valid = tokio::task::spawn_blocking(move || verify_hash(&hash, &password)).await??;
+
+        Ok(valid.then_some(username))
+    }
+}
+
+/// Delegates verification of credentials to an (async) function.
+pub struct CallbackAuthenticator<F> {
+    callback: F,
+}
+
+impl<F, Fut> CallbackAuthenticator<F>
+where
+    F: Fn(Credentials) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<Option<String>>> + Send,
+{
+    /// Creates an authenticator that calls `callback` for every set of credentials.
+    pub fn new(callback: F) -> Self {
+        CallbackAuthenticator { callback }
+    }
+}
+
+#[async_trait]
+impl<F, Fut> Authenticator for CallbackAuthenticator<F>
+where
+    F: Fn(Credentials) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<Option<String>>> + Send,
+{
+    async fn authenticate(
+        &self,
+        credentials: &Credentials,
+    ) -> Result<Option<String>> {
+        (self.callback)(credentials.clone()).await
+    }
+}
+
+/// Tells whether the hash uses an algorithm this authenticator can verify.
+fn is_supported_hash(hash: &str) -> bool {
+    ["$2y$", "$2b$", "$2a$", "{SHA}"].iter().any(|p| hash.starts_with(p))
+}
+
+/// Verifies a password against a bcrypt or SHA-1 hash.
+fn verify_hash(
+    hash: &str,
+    password: &[u8],
+) -> Result<bool> {
+    if let Some(digest) = hash.strip_prefix("{SHA}") {
+        let expected = BASE64.decode(digest)?;
+        Ok(constant_time_eq(&expected, &Sha1::digest(password)))
+    } else {
+        Ok(bcrypt::verify(password, hash)?)
+    }
+}
+
+/// Compares two byte strings without leaking, through timing, where they differ.
+fn constant_time_eq(
+    a: &[u8],
+    b: &[u8],
+) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    pub async fn htpasswd() -> Result<()> {
+        // Equivalent to `htpasswd -nbs alice secret` and `htpasswd -nbB bob secret`.
+        let contents = format!(
+            "# users\nalice:{{SHA}}5en6G6MezRroT3XKqkdPOmY/BfQ=\nbob:{}\n",
+            bcrypt::hash("secret", 4)?
+        );
+        let authenticator = HtpasswdAuthenticator::parse(&contents)?;
+
+        let alice = Credentials::new("alice", "secret");
+        assert_eq!(authenticator.authenticate(&alice).await?, Some(String::from("alice")));
+
+        let bob = Credentials::new("bob", "secret");
+        assert_eq!(authenticator.authenticate(&bob).await?, Some(String::from("bob")));
+
+        let mallory = Credentials::new("alice", "guess");
+        assert_eq!(authenticator.authenticate(&mallory).await?, None);
+
+        assert!(HtpasswdAuthenticator::parse("carol:$apr1$abc$def").is_err());
+
+        Ok(())
+    }
+}
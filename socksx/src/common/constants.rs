@@ -1,3 +1,5 @@
+pub const SOCKS_VER_4: u8 = 0x04u8;
+pub const SOCKS_VER_4_REPLY: u8 = 0x00u8;
 pub const SOCKS_VER_5: u8 = 0x05u8;
 pub const SOCKS_VER_6: u8 = 0x06u8;
 
@@ -12,6 +14,24 @@ pub const SOCKS_OKIND_STACK: u16 = 0x01u16;
 pub const SOCKS_OKIND_AUTH_METH_ADV: u16 = 0x02u16;
 pub const SOCKS_OKIND_AUTH_METH_SEL: u16 = 0x03u16;
 pub const SOCKS_OKIND_AUTH_DATA: u16 = 0x04u16;
+pub const SOCKS_OKIND_SESS_REQ: u16 = 0x05u16;
+pub const SOCKS_OKIND_SESS_ID: u16 = 0x06u16;
+pub const SOCKS_OKIND_SESS_OK: u16 = 0x08u16;
+pub const SOCKS_OKIND_SESS_INV: u16 = 0x09u16;
+pub const SOCKS_OKIND_SESS_TEARDOWN: u16 = 0x0Au16;
+pub const SOCKS_OKIND_IDEMP_REQ: u16 = 0x0Bu16;
+pub const SOCKS_OKIND_IDEMP_WND: u16 = 0x0Cu16;
+pub const SOCKS_OKIND_IDEMP_EXPENDITURE: u16 = 0x0Du16;
+pub const SOCKS_OKIND_IDEMP_ACCEPT: u16 = 0x0Eu16;
+pub const SOCKS_OKIND_IDEMP_REJECT: u16 = 0x0Fu16;
+
+pub const SOCKS_UDP_MSG_ASSOC_INIT: u8 = 0x01u8;
+pub const SOCKS_UDP_MSG_ASSOC_ACK: u8 = 0x02u8;
+pub const SOCKS_UDP_MSG_DATAGRAM: u8 = 0x03u8;
+pub const SOCKS_UDP_MSG_ERROR: u8 = 0x04u8;
+
+pub const SOCKS_STACK_AVAILABLE: u8 = 0x01u8;
+pub const SOCKS_STACK_UNAVAILABLE: u8 = 0x02u8;
 
 pub const SOCKS_CMD_NOOP: u8 = 0x00u8;
 pub const SOCKS_CMD_CONNECT: u8 = 0x01u8;
@@ -19,6 +39,7 @@ pub const SOCKS_CMD_BIND: u8 = 0x02u8;
 pub const SOCKS_CMD_UDP_ASSOCIATE: u8 = 0x03u8;
 
 pub const SOCKS_PADDING: u8 = 0x00u8;
+pub const SOCKS_UDP_FRAG_NONE: u8 = 0x00u8;
 pub const SOCKS_RSV: u8 = 0x00u8;
 
 pub const SOCKS_ATYP_IPV4: u8 = 0x01u8;
@@ -26,3 +47,8 @@ pub const SOCKS_ATYP_DOMAINNAME: u8 = 0x03u8;
 pub const SOCKS_ATYP_IPV6: u8 = 0x04u8;
 
 pub const SOCKS_REP_SUCCEEDED: u8 = 0x00u8;
+
+pub const SOCKS_MAX_INITIAL_DATA: usize = 16384;
+
+/// Metadata key of the W3C trace context, below the keys of the chain (998, 999, and 1000+).
+pub const SOCKS_METADATA_TRACE_CONTEXT: u16 = 997;
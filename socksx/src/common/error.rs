@@ -0,0 +1,76 @@
+use std::io;
+use thiserror::Error;
+
+/// The errors of `socksx` that are worth telling apart. Functions return an `anyhow::Error`,
+/// these are carried inside it: use `error.downcast_ref::<socksx::Error>()` to match on them.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// The other side doesn't speak the protocol as expected, e.g., it uses another version.
+    #[error("Protocol violation: {0}")]
+    Protocol(String),
+    /// The request asks for something this side doesn't support.
+    #[error("Command not supported: {0}")]
+    CommandNotSupported(String),
+    /// The client couldn't be authenticated, or the proxy didn't accept our authentication.
+    #[error("Authentication failed: {0}")]
+    Authentication(String),
+    /// The destination's domain name didn't resolve to an address.
+    #[error("Failed to resolve {0}.")]
+    Resolve(String),
+    /// The connection to the destination (or to the next proxy) couldn't be set up.
+    #[error("Failed to connect to {destination}: {source}")]
+    Connect {
+        destination: String,
+        #[source]
+        source: io::Error,
+    },
+    /// The proxy replied that it couldn't execute the request, with this reply code.
+    #[error("Proxy failed to execute the request, reply code: {0}")]
+    Refused(u8),
+    /// The access control rules deny the request.
+    #[error("Not allowed: {0}")]
+    NotAllowed(String),
+    /// A limit on the number of concurrent connections is reached.
+    #[error("Limit exceeded: {0}")]
+    LimitExceeded(String),
+    /// An operation took longer than allowed.
+    #[error("Timed out: {0}")]
+    Timeout(String),
+    /// The TLS handshake failed, or the certificates or key to use for it couldn't be loaded.
+    #[error("TLS failure: {0}")]
+    Tls(String),
+}
+
+impl Error {
+    /// Wraps the error of a connection attempt to the destination.
+    pub fn connect<S: ToString>(
+        destination: S,
+        source: io::Error,
+    ) -> Self {
+        Error::Connect {
+            destination: destination.to_string(),
+            source,
+        }
+    }
+
+    /// Finds the most specific cause in the chain of an error: a `socksx::Error`, or else an I/O error.
+    pub(crate) fn cause(error: &anyhow::Error) -> Cause<'_> {
+        for cause in error.chain() {
+            if let Some(error) = cause.downcast_ref::<Error>() {
+                return Cause::Socksx(error);
+            }
+            if let Some(error) = cause.downcast_ref::<io::Error>() {
+                return Cause::Io(error);
+            }
+        }
+
+        Cause::Other
+    }
+}
+
+/// What an error boils down to, for the purpose of choosing a reply code.
+pub(crate) enum Cause<'a> {
+    Socksx(&'a Error),
+    Io(&'a io::Error),
+    Other,
+}
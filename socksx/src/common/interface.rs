@@ -1,21 +1,21 @@
+use crate::SocksStream;
 use anyhow::Result;
 use async_trait::async_trait;
-use tokio::net::TcpStream;
 
 #[async_trait]
 pub trait SocksHandler {
     async fn accept_request(
         &self,
-        source: &mut TcpStream,
+        source: &mut SocksStream,
     ) -> Result<()>;
 
     async fn refuse_request(
         &self,
-        source: &mut TcpStream,
+        source: &mut SocksStream,
     ) -> Result<()>;
 
     async fn setup(
         &self,
-        source: &mut TcpStream,
-    ) -> Result<TcpStream>;
+        source: &mut SocksStream,
+    ) -> Result<SocksStream>;
 }
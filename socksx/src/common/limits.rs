@@ -0,0 +1,211 @@
+use crate::Error;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+/// What happens to a connection that exceeds a limit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LimitMode {
+    /// Refuse it right away.
+    Refuse,
+    /// Wait for a slot, up to the given duration, before refusing it.
+    Queue(Duration),
+}
+
+/// Limits on the number of concurrent connections: in total, per client IP, and per user.
+/// Clones share their counts, so that handlers and the server can enforce the same limits.
+#[derive(Clone, Debug)]
+pub struct ConnectionLimits {
+    global: Option<Arc<Semaphore>>,
+    per_client: usize,
+    per_user: usize,
+    mode: LimitMode,
+    clients: Arc<Mutex<HashMap<IpAddr, Arc<Semaphore>>>>,
+    users: Arc<Mutex<HashMap<String, Arc<Semaphore>>>>,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+/// Holds slots of one or more limits, until it's dropped.
+#[derive(Debug, Default)]
+pub struct ConnectionPermit {
+    _permits: Vec<OwnedSemaphorePermit>,
+}
+
+impl ConnectionLimits {
+    /// Creates limits that allow `global` concurrent connections (0=unlimited), and refuse the rest.
+    pub fn new(global: usize) -> Self {
+        ConnectionLimits {
+            global: (global > 0).then(|| Arc::new(Semaphore::new(global))),
+            per_client: 0,
+            per_user: 0,
+            mode: LimitMode::Refuse,
+            clients: Default::default(),
+            users: Default::default(),
+        }
+    }
+
+    /// Allows each client IP `limit` concurrent connections (0=unlimited).
+    pub fn with_per_client(
+        mut self,
+        limit: usize,
+    ) -> Self {
+        self.per_client = limit;
+        self
+    }
+
+    /// Allows each authenticated user `limit` concurrent connections (0=unlimited).
+    pub fn with_per_user(
+        mut self,
+        limit: usize,
+    ) -> Self {
+        self.per_user = limit;
+        self
+    }
+
+    /// Sets what happens to connections that exceed a limit.
+    pub fn with_mode(
+        mut self,
+        mode: LimitMode,
+    ) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Takes a slot of the per-client and global limits, for a newly accepted client. The
+    /// per-client slot comes first, so that a client queued behind its own limit doesn't hold a
+    /// global slot meanwhile. A queued client waits for both slots up to a single deadline.
+    pub async fn acquire_client(
+        &self,
+        client: IpAddr,
+    ) -> Result<ConnectionPermit> {
+        let deadline = self.deadline();
+        let mut permits = vec![];
+        if self.per_client > 0 {
+            let semaphore = slot(&self.clients, client, self.per_client);
+            permits.push(self.acquire(semaphore, &format!("connections from {}", client), deadline).await?);
+        }
+        if let Some(global) = &self.global {
+            permits.push(self.acquire(global.clone(), "concurrent connections", deadline).await?);
+        }
+
+        Ok(ConnectionPermit { _permits: permits })
+    }
+
+    /// Takes a slot of the per-user limit, for a client that authenticated itself.
+    pub async fn acquire_user(
+        &self,
+        user: &str,
+    ) -> Result<ConnectionPermit> {
+        let mut permits = vec![];
+        if self.per_user > 0 {
+            let semaphore = slot(&self.users, user.to_string(), self.per_user);
+            permits.push(self.acquire(semaphore, &format!("connections for {}", user), self.deadline()).await?);
+        }
+
+        Ok(ConnectionPermit { _permits: permits })
+    }
+
+    /// Until when a connection over a limit waits for a slot, none means it doesn't.
+    fn deadline(&self) -> Option<Instant> {
+        match self.mode {
+            LimitMode::Refuse => None,
+            LimitMode::Queue(timeout) => Some(Instant::now() + timeout),
+        }
+    }
+
+    async fn acquire(
+        &self,
+        semaphore: Arc<Semaphore>,
+        what: &str,
+        deadline: Option<Instant>,
+    ) -> Result<OwnedSemaphorePermit> {
+        let permit = match deadline {
+            None => semaphore.try_acquire_owned().ok(),
+            Some(deadline) => tokio::time::timeout_at(deadline, semaphore.acquire_owned())
+                .await
+                .ok()
+                .and_then(|p| p.ok()),
+        };
+
+        permit.ok_or_else(|| Error::LimitExceeded(format!("Too many {}.", what)).into())
+    }
+}
+
+/// Returns the semaphore of the key. Semaphores nobody holds a permit of are forgotten.
+fn slot<K: Eq + Hash>(
+    semaphores: &Mutex<HashMap<K, Arc<Semaphore>>>,
+    key: K,
+    limit: usize,
+) -> Arc<Semaphore> {
+    let mut semaphores = semaphores.lock().unwrap();
+    semaphores.retain(|_, s| Arc::strong_count(s) > 1);
+
+    semaphores
+        .entry(key)
+        .or_insert_with(|| Arc::new(Semaphore::new(limit)))
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    pub async fn limits() -> Result<()> {
+        let limits = ConnectionLimits::new(3).with_per_client(2).with_per_user(1);
+        let (alice, bob) = ("10.0.0.1".parse()?, "10.0.0.2".parse()?);
+
+        let first = limits.acquire_client(alice).await?;
+        let _second = limits.acquire_client(alice).await?;
+        assert!(limits.acquire_client(alice).await.is_err());
+
+        let _third = limits.acquire_client(bob).await?;
+        assert!(limits.acquire_client(bob).await.is_err());
+
+        // Slots are released when the permit is dropped.
+        drop(first);
+        let _fourth = limits.acquire_client(bob).await?;
+
+        let user = limits.acquire_user("alice").await?;
+        assert!(limits.acquire_user("alice").await.is_err());
+
+        // Queued connections get the slot once it's released.
+        let limits = limits.with_mode(LimitMode::Queue(Duration::from_secs(5)));
+        let queued = tokio::spawn({
+            let limits = limits.clone();
+            async move { limits.acquire_user("alice").await.map(|_| ()) }
+        });
+        drop(user);
+        queued.await??;
+
+        let limits = limits.with_mode(LimitMode::Queue(Duration::from_millis(10)));
+        let _user = limits.acquire_user("alice").await?;
+        assert!(limits.acquire_user("alice").await.is_err());
+
+        // A client queued behind its own limit doesn't take a global slot from others.
+        let limits = ConnectionLimits::new(2)
+            .with_per_client(1)
+            .with_mode(LimitMode::Queue(Duration::from_secs(5)));
+        let first = limits.acquire_client(alice).await?;
+        let queued = tokio::spawn({
+            let limits = limits.clone();
+            async move { limits.acquire_client(alice).await.map(|_| ()) }
+        });
+        tokio::task::yield_now().await;
+        tokio::time::timeout(Duration::from_millis(100), limits.acquire_client(bob)).await??;
+        drop(first);
+        queued.await??;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,629 @@
+use crate::access_log::AccessRecord;
+use crate::addresses::{Address, ProxyAddress};
+use crate::error::{Cause, Error};
+use crate::SocksStream;
+use anyhow::Result;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::field::Empty;
+use tracing::{Instrument, Span};
+
+/// Upper bounds (in seconds) of the connect latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0];
+
+/// The protocol a handler speaks with its clients.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Protocol {
+    Socks4,
+    Socks5,
+    Socks6,
+    Http,
+}
+
+impl Protocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Socks4 => "socks4",
+            Protocol::Socks5 => "socks5",
+            Protocol::Socks6 => "socks6",
+            Protocol::Http => "http",
+        }
+    }
+}
+
+/// How a handshake ended.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Outcome {
+    Success,
+    ProtocolError,
+    AuthenticationFailure,
+    NotAllowed,
+    Timeout,
+    Error,
+}
+
+impl Outcome {
+    /// Classifies the result of a handshake.
+    pub fn of<T>(result: &Result<T>) -> Self {
+        let error = match result {
+            Ok(_) => return Outcome::Success,
+            Err(error) => error,
+        };
+
+        match Error::cause(error) {
+            Cause::Socksx(Error::Protocol(_)) | Cause::Socksx(Error::CommandNotSupported(_)) => Outcome::ProtocolError,
+            Cause::Socksx(Error::Authentication(_)) => Outcome::AuthenticationFailure,
+            Cause::Socksx(Error::NotAllowed(_)) => Outcome::NotAllowed,
+            Cause::Socksx(Error::Timeout(_)) => Outcome::Timeout,
+            _ => Outcome::Error,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::ProtocolError => "protocol_error",
+            Outcome::AuthenticationFailure => "authentication_failure",
+            Outcome::NotAllowed => "not_allowed",
+            Outcome::Timeout => "timeout",
+            Outcome::Error => "error",
+        }
+    }
+}
+
+/// Receives events from the handlers, e.g., to keep metrics. Every method does nothing by
+/// default, so that implementations only have to handle the events they're interested in.
+pub trait MetricsHook: Send + Sync {
+    /// A client connected.
+    fn connection_opened(
+        &self,
+        _protocol: Protocol,
+    ) {
+    }
+
+    /// The connection with a client is closed.
+    fn connection_closed(
+        &self,
+        _protocol: Protocol,
+    ) {
+    }
+
+    /// The handshake with a client ended.
+    fn handshake(
+        &self,
+        _protocol: Protocol,
+        _outcome: Outcome,
+    ) {
+    }
+
+    /// A client failed to authenticate.
+    fn authentication_failure(
+        &self,
+        _protocol: Protocol,
+    ) {
+    }
+
+    /// A reply was sent to a client, `code` is the reply field (or HTTP status code).
+    fn reply(
+        &self,
+        _protocol: Protocol,
+        _code: u16,
+    ) {
+    }
+
+    /// The destination was connected to, through `hops` proxies.
+    fn connected(
+        &self,
+        _protocol: Protocol,
+        _latency: Duration,
+        _hops: usize,
+    ) {
+    }
+
+    /// A relay carried `up` more bytes from the client, and `down` more bytes to it. Reported as
+    /// the relay goes, rather than once it ends.
+    fn relayed(
+        &self,
+        _protocol: Protocol,
+        _up: u64,
+        _down: u64,
+    ) {
+    }
+
+    /// The connection with a client is closed, `record` describes what happened on it.
+    fn access(
+        &self,
+        _record: &AccessRecord,
+    ) {
+    }
+}
+
+impl<H: MetricsHook + ?Sized> MetricsHook for Arc<H> {
+    fn connection_opened(
+        &self,
+        protocol: Protocol,
+    ) {
+        (**self).connection_opened(protocol)
+    }
+
+    fn connection_closed(
+        &self,
+        protocol: Protocol,
+    ) {
+        (**self).connection_closed(protocol)
+    }
+
+    fn handshake(
+        &self,
+        protocol: Protocol,
+        outcome: Outcome,
+    ) {
+        (**self).handshake(protocol, outcome)
+    }
+
+    fn authentication_failure(
+        &self,
+        protocol: Protocol,
+    ) {
+        (**self).authentication_failure(protocol)
+    }
+
+    fn reply(
+        &self,
+        protocol: Protocol,
+        code: u16,
+    ) {
+        (**self).reply(protocol, code)
+    }
+
+    fn connected(
+        &self,
+        protocol: Protocol,
+        latency: Duration,
+        hops: usize,
+    ) {
+        (**self).connected(protocol, latency, hops)
+    }
+
+    fn relayed(
+        &self,
+        protocol: Protocol,
+        up: u64,
+        down: u64,
+    ) {
+        (**self).relayed(protocol, up, down)
+    }
+
+    fn access(
+        &self,
+        record: &AccessRecord,
+    ) {
+        (**self).access(record)
+    }
+}
+
+/// Keeps the counters of the handlers it's given to, and renders them in the Prometheus text
+/// format. Share it between handlers with an `Arc`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    inner: Mutex<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    active: BTreeMap<Protocol, i64>,
+    handshakes: BTreeMap<(Protocol, Outcome), u64>,
+    authentication_failures: BTreeMap<Protocol, u64>,
+    replies: BTreeMap<(Protocol, u16), u64>,
+    bytes_up: BTreeMap<Protocol, u64>,
+    bytes_down: BTreeMap<Protocol, u64>,
+    connect_latency: BTreeMap<Protocol, Histogram>,
+    chain_hops: BTreeMap<(Protocol, usize), u64>,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Metrics {
+    /// Creates metrics without any counts.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of connections that are currently open.
+    pub fn active_connections(
+        &self,
+        protocol: Protocol,
+    ) -> i64 {
+        self.inner.lock().unwrap().active.get(&protocol).copied().unwrap_or(0)
+    }
+
+    /// The number of handshakes that ended with `outcome`.
+    pub fn handshakes(
+        &self,
+        protocol: Protocol,
+        outcome: Outcome,
+    ) -> u64 {
+        let counters = self.inner.lock().unwrap();
+        counters.handshakes.get(&(protocol, outcome)).copied().unwrap_or(0)
+    }
+
+    /// The number of bytes relayed from clients (up), and to clients (down).
+    pub fn bytes_relayed(
+        &self,
+        protocol: Protocol,
+    ) -> (u64, u64) {
+        let counters = self.inner.lock().unwrap();
+        let up = counters.bytes_up.get(&protocol).copied().unwrap_or(0);
+        let down = counters.bytes_down.get(&protocol).copied().unwrap_or(0);
+
+        (up, down)
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let counters = self.inner.lock().unwrap();
+        let mut text = String::new();
+
+        header(&mut text, "socksx_active_connections", "gauge", "Connections that are currently open.");
+        for (protocol, value) in &counters.active {
+            let _ = writeln!(text, "socksx_active_connections{{protocol=\"{}\"}} {}", protocol.as_str(), value);
+        }
+
+        header(&mut text, "socksx_handshakes_total", "counter", "Handshakes, by outcome.");
+        for ((protocol, outcome), value) in &counters.handshakes {
+            let labels = format!("protocol=\"{}\",outcome=\"{}\"", protocol.as_str(), outcome.as_str());
+            let _ = writeln!(text, "socksx_handshakes_total{{{}}} {}", labels, value);
+        }
+
+        let help = "Clients that failed to authenticate.";
+        header(&mut text, "socksx_authentication_failures_total", "counter", help);
+        for (protocol, value) in &counters.authentication_failures {
+            let name = "socksx_authentication_failures_total";
+            let _ = writeln!(text, "{}{{protocol=\"{}\"}} {}", name, protocol.as_str(), value);
+        }
+
+        header(&mut text, "socksx_replies_total", "counter", "Replies sent to clients, by reply code.");
+        for ((protocol, code), value) in &counters.replies {
+            let labels = format!("protocol=\"{}\",code=\"{}\"", protocol.as_str(), code);
+            let _ = writeln!(text, "socksx_replies_total{{{}}} {}", labels, value);
+        }
+
+        header(&mut text, "socksx_relayed_bytes_total", "counter", "Bytes relayed, by direction.");
+        for (direction, bytes) in [("up", &counters.bytes_up), ("down", &counters.bytes_down)] {
+            for (protocol, value) in bytes {
+                let labels = format!("protocol=\"{}\",direction=\"{}\"", protocol.as_str(), direction);
+                let _ = writeln!(text, "socksx_relayed_bytes_total{{{}}} {}", labels, value);
+            }
+        }
+
+        let help = "Time it took to connect to destinations.";
+        header(&mut text, "socksx_connect_duration_seconds", "histogram", help);
+        for (protocol, histogram) in &counters.connect_latency {
+            let name = "socksx_connect_duration_seconds";
+            let protocol = protocol.as_str();
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                let _ = writeln!(text, "{}_bucket{{protocol=\"{}\",le=\"{}\"}} {}", name, protocol, bound, count);
+            }
+            let _ = writeln!(text, "{}_bucket{{protocol=\"{}\",le=\"+Inf\"}} {}", name, protocol, histogram.count);
+            let _ = writeln!(text, "{}_sum{{protocol=\"{}\"}} {}", name, protocol, histogram.sum);
+            let _ = writeln!(text, "{}_count{{protocol=\"{}\"}} {}", name, protocol, histogram.count);
+        }
+
+        let help = "Connects to destinations, by the number of proxies in between.";
+        header(&mut text, "socksx_chain_hops_total", "counter", help);
+        for ((protocol, hops), value) in &counters.chain_hops {
+            let labels = format!("protocol=\"{}\",hops=\"{}\"", protocol.as_str(), hops);
+            let _ = writeln!(text, "socksx_chain_hops_total{{{}}} {}", labels, value);
+        }
+
+        text
+    }
+}
+
+fn header(
+    text: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+) {
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} {}", name, kind);
+}
+
+impl MetricsHook for Metrics {
+    fn connection_opened(
+        &self,
+        protocol: Protocol,
+    ) {
+        *self.inner.lock().unwrap().active.entry(protocol).or_default() += 1;
+    }
+
+    fn connection_closed(
+        &self,
+        protocol: Protocol,
+    ) {
+        *self.inner.lock().unwrap().active.entry(protocol).or_default() -= 1;
+    }
+
+    fn handshake(
+        &self,
+        protocol: Protocol,
+        outcome: Outcome,
+    ) {
+        *self.inner.lock().unwrap().handshakes.entry((protocol, outcome)).or_default() += 1;
+    }
+
+    fn authentication_failure(
+        &self,
+        protocol: Protocol,
+    ) {
+        *self.inner.lock().unwrap().authentication_failures.entry(protocol).or_default() += 1;
+    }
+
+    fn reply(
+        &self,
+        protocol: Protocol,
+        code: u16,
+    ) {
+        *self.inner.lock().unwrap().replies.entry((protocol, code)).or_default() += 1;
+    }
+
+    fn connected(
+        &self,
+        protocol: Protocol,
+        latency: Duration,
+        hops: usize,
+    ) {
+        let mut counters = self.inner.lock().unwrap();
+        *counters.chain_hops.entry((protocol, hops)).or_default() += 1;
+
+        let histogram = counters.connect_latency.entry(protocol).or_default();
+        let seconds = latency.as_secs_f64();
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter_mut()) {
+            if seconds <= *bound {
+                *count += 1;
+            }
+        }
+        histogram.count += 1;
+        histogram.sum += seconds;
+    }
+
+    fn relayed(
+        &self,
+        protocol: Protocol,
+        up: u64,
+        down: u64,
+    ) {
+        let mut counters = self.inner.lock().unwrap();
+        *counters.bytes_up.entry(protocol).or_default() += up;
+        *counters.bytes_down.entry(protocol).or_default() += down;
+    }
+}
+
+tokio::task_local! {
+    /// The access record of the connection that's being handled, see `Hook::scope`.
+    static RECORD: RefCell<AccessRecord>;
+}
+
+/// Updates the access record of the current connection, if there is one.
+fn update<F: FnOnce(&mut AccessRecord)>(update: F) {
+    let _ = RECORD.try_with(|record| update(&mut record.borrow_mut()));
+}
+
+/// The hooks of a handler, for a single protocol.
+#[derive(Clone)]
+pub(crate) struct Hook {
+    protocol: Protocol,
+    hooks: Vec<Arc<dyn MetricsHook>>,
+}
+
+impl Hook {
+    pub fn new(protocol: Protocol) -> Self {
+        Hook { protocol, hooks: vec![] }
+    }
+
+    pub fn with_hook(
+        mut self,
+        hook: Arc<dyn MetricsHook>,
+    ) -> Self {
+        self.hooks.push(hook);
+        self
+    }
+
+    /// Handles the connection with `client` in a span of its own, reporting it as open until
+    /// `connection` ends. Then, the events that were reported in the meantime are reported as a
+    /// single access record.
+    pub async fn scope<F, T>(
+        &self,
+        client: SocketAddr,
+        connection: F,
+    ) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        // The handler fills in the rest, once it knows.
+        let span = info_span!(
+            "connection",
+            protocol = self.protocol.as_str(),
+            %client,
+            user = Empty,
+            command = Empty,
+            destination = Empty,
+            chain_index = Empty,
+        );
+        if self.hooks.is_empty() {
+            return connection.instrument(span).await;
+        }
+
+        let _connection = self.connection();
+        let start = Instant::now();
+        let record = RefCell::new(AccessRecord::new(self.protocol, client));
+        RECORD
+            .scope(record, async {
+                let result = connection.await;
+
+                let mut record = RECORD.with(|record| record.borrow().clone());
+                record.duration = start.elapsed();
+                for hook in &self.hooks {
+                    hook.access(&record);
+                }
+
+                result
+            })
+            .instrument(span)
+            .await
+    }
+
+    /// Reports the connection as open, until the returned guard is dropped.
+    fn connection(&self) -> ConnectionGuard {
+        for hook in &self.hooks {
+            hook.connection_opened(self.protocol);
+        }
+
+        ConnectionGuard { hook: self.clone() }
+    }
+
+    /// Reports the outcome of a handshake, and authentication failures. Returns the result.
+    pub fn handshake<T>(
+        &self,
+        result: Result<T>,
+    ) -> Result<T> {
+        let outcome = Outcome::of(&result);
+        for hook in &self.hooks {
+            hook.handshake(self.protocol, outcome);
+            if outcome == Outcome::AuthenticationFailure {
+                hook.authentication_failure(self.protocol);
+            }
+        }
+
+        result
+    }
+
+    /// Records what the client asked for, and who it is if it authenticated itself, in the
+    /// connection span too.
+    pub fn request(
+        &self,
+        command: &str,
+        destination: &Address,
+        user: Option<&str>,
+    ) {
+        let span = Span::current();
+        span.record("user", user);
+        span.record("command", command);
+        span.record("destination", destination.to_string().as_str());
+
+        update(|record| {
+            record.command = Some(command.to_string());
+            record.destination = Some(destination.clone());
+            record.user = user.map(String::from);
+        });
+    }
+
+    pub fn reply(
+        &self,
+        code: u16,
+    ) {
+        update(|record| record.reply = Some(code));
+        for hook in &self.hooks {
+            hook.reply(self.protocol, code);
+        }
+    }
+
+    /// Reports a connect to `destination`, through the proxies of `chain`. Without a chain, the
+    /// address the destination was resolved to is known.
+    pub fn connected(
+        &self,
+        latency: Duration,
+        chain: &[ProxyAddress],
+        destination: &SocksStream,
+    ) {
+        let resolved = match chain {
+            [] => destination.peer_addr().ok().map(|address| address.ip()),
+            _ => None,
+        };
+        update(|record| {
+            record.chain = chain.iter().map(ProxyAddress::to_string).collect();
+            record.resolved = resolved;
+        });
+        for hook in &self.hooks {
+            hook.connected(self.protocol, latency, chain.len());
+        }
+    }
+
+    pub fn relayed(
+        &self,
+        up: u64,
+        down: u64,
+    ) {
+        update(|record| {
+            record.bytes_up += up;
+            record.bytes_down += down;
+        });
+        for hook in &self.hooks {
+            hook.relayed(self.protocol, up, down);
+        }
+    }
+}
+
+struct ConnectionGuard {
+    hook: Hook,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        for hook in &self.hook.hooks {
+            hook.connection_closed(self.hook.protocol);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    pub async fn render() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let destination = SocksStream::from(TcpStream::connect(listener.local_addr()?).await?);
+        let chain = vec![
+            ProxyAddress::new(6, String::from("localhost"), 1, None),
+            ProxyAddress::new(6, String::from("localhost"), 2, None),
+        ];
+
+        let metrics = Arc::new(Metrics::new());
+        let hook = Hook::new(Protocol::Socks5).with_hook(metrics.clone());
+
+        let connection = hook.connection();
+        hook.handshake(Ok(()))?;
+        let _ = hook.handshake::<()>(Err(Error::Authentication(String::from("Wrong password.")).into()));
+        hook.reply(0);
+        hook.connected(Duration::from_millis(20), &chain, &destination);
+        hook.relayed(10, 20);
+        assert_eq!(metrics.active_connections(Protocol::Socks5), 1);
+        drop(connection);
+
+        assert_eq!(metrics.active_connections(Protocol::Socks5), 0);
+        assert_eq!(metrics.handshakes(Protocol::Socks5, Outcome::Success), 1);
+        assert_eq!(metrics.bytes_relayed(Protocol::Socks5), (10, 20));
+
+        let text = metrics.render();
+        assert!(text.contains("socksx_handshakes_total{protocol=\"socks5\",outcome=\"authentication_failure\"} 1"));
+        assert!(text.contains("socksx_authentication_failures_total{protocol=\"socks5\"} 1"));
+        assert!(text.contains("socksx_connect_duration_seconds_bucket{protocol=\"socks5\",le=\"0.01\"} 0"));
+        assert!(text.contains("socksx_connect_duration_seconds_bucket{protocol=\"socks5\",le=\"0.025\"} 1"));
+        assert!(text.contains("socksx_chain_hops_total{protocol=\"socks5\",hops=\"2\"} 1"));
+
+        Ok(())
+    }
+}
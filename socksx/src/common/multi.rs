@@ -0,0 +1,171 @@
+use crate::addresses::ProxyAddress;
+use crate::constants::*;
+use crate::timeouts::Timeouts;
+use crate::{Error, HttpConnectHandler, Socks4Handler, Socks5Handler, Socks6Handler, SocksHandler, SocksStream};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::sync::Arc;
+
+type Handler = Arc<dyn SocksHandler + Send + Sync>;
+
+/// Serves several protocols on a single listener. The first byte a client sends tells them
+/// apart: the SOCKS version, or the first letter of an HTTP method.
+#[derive(Clone)]
+pub struct MultiProtocolHandler {
+    socks4: Option<Handler>,
+    socks5: Option<Handler>,
+    socks6: Option<Handler>,
+    http: Option<Handler>,
+    timeouts: Timeouts,
+}
+
+impl Default for MultiProtocolHandler {
+    fn default() -> Self {
+        Self::new(vec![])
+    }
+}
+
+impl MultiProtocolHandler {
+    /// Creates a handler for SOCKS4, SOCKS5, SOCKS6, and HTTP clients, which connect through `chain`.
+    pub fn new(chain: Vec<ProxyAddress>) -> Self {
+        MultiProtocolHandler {
+            socks4: Some(Arc::new(Socks4Handler::new(chain.clone()))),
+            socks5: Some(Arc::new(Socks5Handler::new(chain.clone()))),
+            socks6: Some(Arc::new(Socks6Handler::new(chain.clone()))),
+            http: Some(Arc::new(HttpConnectHandler::new(chain))),
+            timeouts: Timeouts::default(),
+        }
+    }
+
+    /// Replaces the handler for SOCKS4 clients, `None` refuses them.
+    pub fn with_socks4<H: SocksHandler + Send + Sync + 'static>(
+        mut self,
+        handler: Option<H>,
+    ) -> Self {
+        self.socks4 = handler.map(|h| Arc::new(h) as Handler);
+        self
+    }
+
+    /// Replaces the handler for SOCKS5 clients, `None` refuses them.
+    pub fn with_socks5<H: SocksHandler + Send + Sync + 'static>(
+        mut self,
+        handler: Option<H>,
+    ) -> Self {
+        self.socks5 = handler.map(|h| Arc::new(h) as Handler);
+        self
+    }
+
+    /// Replaces the handler for SOCKS6 clients, `None` refuses them.
+    pub fn with_socks6<H: SocksHandler + Send + Sync + 'static>(
+        mut self,
+        handler: Option<H>,
+    ) -> Self {
+        self.socks6 = handler.map(|h| Arc::new(h) as Handler);
+        self
+    }
+
+    /// Replaces the handler for HTTP clients, `None` refuses them.
+    pub fn with_http<H: SocksHandler + Send + Sync + 'static>(
+        mut self,
+        handler: Option<H>,
+    ) -> Self {
+        self.http = handler.map(|h| Arc::new(h) as Handler);
+        self
+    }
+
+    /// Limits the time a client has to send its first byte to the handshake timeout of `timeouts`.
+    /// The handlers apply their own timeouts after that.
+    pub fn with_timeouts(
+        mut self,
+        timeouts: Timeouts,
+    ) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Picks the handler for the protocol the client speaks, without consuming any of its bytes.
+    async fn detect(
+        &self,
+        source: &mut SocksStream,
+    ) -> Result<&Handler> {
+        let mut first = [0; 1];
+        ensure!(
+            source.peek(&mut first).await? == 1,
+            Error::Protocol(String::from("Client closed the connection before sending a request."))
+        );
+
+        let handler = match first[0] {
+            SOCKS_VER_4 => &self.socks4,
+            SOCKS_VER_5 => &self.socks5,
+            SOCKS_VER_6 => &self.socks6,
+            // HTTP requests start with a method, e.g., CONNECT or GET.
+            b'A'..=b'Z' => &self.http,
+            byte => bail!(Error::Protocol(format!("Client speaks an unknown protocol, first byte: {}.", byte))),
+        };
+
+        match handler {
+            Some(handler) => Ok(handler),
+            None => bail!(Error::Protocol(format!("Client speaks a disabled protocol, first byte: {}.", first[0]))),
+        }
+    }
+}
+
+#[async_trait]
+impl SocksHandler for MultiProtocolHandler {
+    /// Hands the client over to the handler for its protocol.
+    async fn accept_request(
+        &self,
+        source: &mut SocksStream,
+    ) -> Result<()> {
+        self.timeouts.handshake(self.detect(source)).await?.accept_request(source).await
+    }
+
+    /// Lets the handler for the protocol of the client refuse it.
+    async fn refuse_request(
+        &self,
+        source: &mut SocksStream,
+    ) -> Result<()> {
+        self.timeouts.handshake(self.detect(source)).await?.refuse_request(source).await
+    }
+
+    /// Lets the handler for the protocol of the client set up the connection it asked for.
+    async fn setup(
+        &self,
+        source: &mut SocksStream,
+    ) -> Result<SocksStream> {
+        self.timeouts.handshake(self.detect(source)).await?.setup(source).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::spawn_proxy;
+    use crate::{Socks4Client, Socks5Client, Socks6Client};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    pub async fn detect_protocol() -> Result<()> {
+        let proxy_addr = spawn_proxy(MultiProtocolHandler::default()).await?;
+        let target = TcpListener::bind("127.0.0.1:0").await?;
+        let destination = target.local_addr()?.to_string();
+
+        let client = Socks4Client::new(proxy_addr.to_string(), None).await?;
+        assert!(client.connect(destination.clone()).await.is_ok());
+
+        let client = Socks5Client::new(proxy_addr.to_string(), None).await?;
+        assert!(client.connect(destination.clone()).await.is_ok());
+
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        assert!(client.connect(destination.clone(), None, None).await.is_ok());
+
+        // SOCKS4 clients are refused once their handler is disabled.
+        let handler = MultiProtocolHandler::default().with_socks4(None::<Socks4Handler>);
+        let proxy_addr = spawn_proxy(handler).await?;
+
+        let client = Socks4Client::new(proxy_addr.to_string(), None).await?;
+        assert!(client.connect(destination).await.is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+use std::fmt;
+use std::io::{self, IoSlice};
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsStream;
+
+/// A connection to a client, a proxy, or a destination: plain TCP, or TLS on top of another
+/// stream. TLS to a link of a chain is tunneled through the streams to the links before it.
+pub struct SocksStream {
+    transport: Transport,
+    identity: Option<String>,
+    peeked: Vec<u8>,
+}
+
+enum Transport {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<SocksStream>>),
+}
+
+impl From<TcpStream> for SocksStream {
+    fn from(stream: TcpStream) -> Self {
+        SocksStream {
+            transport: Transport::Tcp(stream),
+            identity: None,
+            peeked: vec![],
+        }
+    }
+}
+
+impl SocksStream {
+    /// Wraps a TLS stream, along with the identity the peer authenticated with, if any.
+    pub(crate) fn tls(
+        stream: TlsStream<SocksStream>,
+        identity: Option<String>,
+    ) -> Self {
+        SocksStream {
+            transport: Transport::Tls(Box::new(stream)),
+            identity,
+            peeked: vec![],
+        }
+    }
+
+    /// The TCP connection underneath, i.e., the one to the peer or to the first link of a chain.
+    pub fn tcp(&self) -> &TcpStream {
+        match &self.transport {
+            Transport::Tcp(stream) => stream,
+            Transport::Tls(stream) => stream.get_ref().0.tcp(),
+        }
+    }
+
+    /// Tells whether the stream is encrypted with TLS.
+    pub fn is_tls(&self) -> bool {
+        matches!(self.transport, Transport::Tls(_))
+    }
+
+    /// The identity of the client, taken from the certificate it presented, if the listener
+    /// requires client certificates.
+    pub fn identity(&self) -> Option<&str> {
+        self.identity.as_deref()
+    }
+
+    /// The address of the peer, or of the first link of a chain.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp().peer_addr()
+    }
+
+    /// The local address of the TCP connection.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp().local_addr()
+    }
+
+    /// Puts data back on the stream, the next read returns it first.
+    pub(crate) fn unread(
+        &mut self,
+        mut data: Vec<u8>,
+    ) {
+        data.append(&mut self.peeked);
+        self.peeked = data;
+    }
+
+    /// Reads data into `buf`, without consuming it: the next read returns the same data.
+    pub async fn peek(
+        &mut self,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        if self.peeked.is_empty() {
+            let mut peeked = vec![0; buf.len()];
+            let read = self.read(&mut peeked).await?;
+            peeked.truncate(read);
+            self.peeked = peeked;
+        }
+
+        let peeked = self.peeked.len().min(buf.len());
+        buf[..peeked].copy_from_slice(&self.peeked[..peeked]);
+
+        Ok(peeked)
+    }
+}
+
+impl fmt::Debug for SocksStream {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.debug_struct("SocksStream")
+            .field("tcp", self.tcp())
+            .field("tls", &self.is_tls())
+            .field("identity", &self.identity)
+            .finish()
+    }
+}
+
+impl AsRawFd for SocksStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.tcp().as_raw_fd()
+    }
+}
+
+impl AsyncRead for SocksStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.peeked.is_empty() {
+            let peeked = this.peeked.len().min(buf.remaining());
+            buf.put_slice(&this.peeked[..peeked]);
+            this.peeked.drain(..peeked);
+
+            return Poll::Ready(Ok(()));
+        }
+
+        match &mut this.transport {
+            Transport::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Transport::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for SocksStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().transport {
+            Transport::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Transport::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().transport {
+            Transport::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Transport::Tls(stream) => Pin::new(stream.as_mut()).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match &self.transport {
+            Transport::Tcp(stream) => stream.is_write_vectored(),
+            Transport::Tls(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().transport {
+            Transport::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Transport::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().transport {
+            Transport::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Transport::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,322 @@
+use crate::Error;
+use anyhow::Result;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+use tracing::Span;
+
+/// Size of the buffer used for each direction of a relay.
+const RELAY_BUFFER_SIZE: usize = 8192;
+
+/// How long handlers wait for clients and destinations, none means indefinitely.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Timeouts {
+    handshake: Option<Duration>,
+    connect: Option<Duration>,
+    idle_upstream: Option<Duration>,
+    idle_downstream: Option<Duration>,
+}
+
+impl Timeouts {
+    /// Creates timeouts that wait indefinitely, until they're set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits the time a client has to send its request, including authentication.
+    pub fn with_handshake(
+        mut self,
+        timeout: Duration,
+    ) -> Self {
+        self.handshake = Some(timeout);
+        self
+    }
+
+    /// Limits the time it takes to connect to the destination, through the chain if there is one,
+    /// or for a peer to connect to a BIND listener.
+    pub fn with_connect(
+        mut self,
+        timeout: Duration,
+    ) -> Self {
+        self.connect = Some(timeout);
+        self
+    }
+
+    /// Limits the time the client may send nothing while relaying. The relay is closed once both
+    /// the client and the destination exceeded their idle timeouts, so a client that only receives
+    /// isn't cut off. Without a downstream timeout, this one applies to the destination too.
+    pub fn with_idle_upstream(
+        mut self,
+        timeout: Duration,
+    ) -> Self {
+        self.idle_upstream = Some(timeout);
+        self
+    }
+
+    /// Limits the time the destination may send nothing while relaying. The relay is closed once
+    /// both the destination and the client exceeded their idle timeouts, so a destination that
+    /// only receives isn't cut off. Without an upstream timeout, this one applies to the client too.
+    pub fn with_idle_downstream(
+        mut self,
+        timeout: Duration,
+    ) -> Self {
+        self.idle_downstream = Some(timeout);
+        self
+    }
+
+    /// Runs a handshake, failing with `Error::Timeout` if it takes too long.
+    pub async fn handshake<F, T>(
+        &self,
+        handshake: F,
+    ) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        limit(self.handshake, "handshake", handshake).await
+    }
+
+    /// Connects to a destination, failing with `Error::Timeout` if it takes too long.
+    pub async fn connect<F, T>(
+        &self,
+        connect: F,
+    ) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        limit(self.connect, "connect", connect).await
+    }
+
+    /// Relays between the client and the destination, until both directions are closed, or the
+    /// relay is idle. Bytes are reported to `relayed` as they're relayed, as the number from the
+    /// client and the number to the client, also if the relay fails. Returns the totals.
+    #[instrument(skip_all, fields(up, down))]
+    pub async fn relay<S, D, F>(
+        &self,
+        source: &mut S,
+        destination: &mut D,
+        relayed: F,
+    ) -> Result<(u64, u64)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+        D: AsyncRead + AsyncWrite + Unpin,
+        F: Fn(u64, u64) + Sync,
+    {
+        let (mut source_read, mut source_write) = tokio::io::split(source);
+        let (mut destination_read, mut destination_write) = tokio::io::split(destination);
+
+        // A direction without an idle timeout of its own takes the one of the other direction.
+        let idle = self.idle_upstream.or(self.idle_downstream).zip(self.idle_downstream.or(self.idle_upstream));
+        let start = Instant::now();
+        let activity = idle.map(|(up, down)| (Activity::new(start, up), Activity::new(start, down)));
+
+        let (up, down) = (AtomicU64::new(0), AtomicU64::new(0));
+        let upstream = copy(&mut source_read, &mut destination_write, activity.as_ref().map(|(u, d)| (u, d)), |n| {
+            up.fetch_add(n, Ordering::Relaxed);
+            relayed(n, 0);
+        });
+        let downstream = copy(&mut destination_read, &mut source_write, activity.as_ref().map(|(u, d)| (d, u)), |n| {
+            down.fetch_add(n, Ordering::Relaxed);
+            relayed(0, n);
+        });
+        let result = tokio::try_join!(upstream, downstream);
+
+        let (up, down) = (up.into_inner(), down.into_inner());
+        let span = Span::current();
+        span.record("up", up);
+        span.record("down", down);
+
+        result.map(|_| (up, down))
+    }
+}
+
+async fn limit<F, T>(
+    timeout: Option<Duration>,
+    what: &str,
+    future: F,
+) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| Error::Timeout(format!("The {} took longer than {:?}.", what, timeout)))?,
+        None => future.await,
+    }
+}
+
+/// When a direction of a relay last carried data, and how long it may carry nothing.
+struct Activity {
+    start: Instant,
+    idle: Duration,
+    last: AtomicU64,
+}
+
+impl Activity {
+    fn new(
+        start: Instant,
+        idle: Duration,
+    ) -> Self {
+        Activity {
+            start,
+            idle,
+            last: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        self.last.store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// When this direction exceeds its idle timeout, unless it carries data before then.
+    fn expiry(&self) -> Instant {
+        self.start + Duration::from_millis(self.last.load(Ordering::Relaxed)) + self.idle
+    }
+}
+
+/// Copies one direction of a relay, until the reader is closed, or both this direction and the
+/// other one exceeded their idle timeouts, given as `activity`. Then, the writer is shut down.
+/// A writer that doesn't take data within the idle timeout of this direction fails the copy.
+/// Each chunk that's written is reported to `copied`.
+async fn copy<R, W, F>(
+    reader: &mut R,
+    writer: &mut W,
+    activity: Option<(&Activity, &Activity)>,
+    copied: F,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    F: Fn(u64),
+{
+    let mut buffer = vec![0; RELAY_BUFFER_SIZE];
+    loop {
+        let read = match activity {
+            Some((own, other)) => loop {
+                match tokio::time::timeout_at(own.expiry().max(other.expiry()), reader.read(&mut buffer)).await {
+                    Ok(read) => break read?,
+                    // The other direction carried data meanwhile, so the relay isn't idle.
+                    Err(_) if own.expiry().max(other.expiry()) > Instant::now() => continue,
+                    Err(_) => break 0,
+                }
+            },
+            None => reader.read(&mut buffer).await?,
+        };
+        if read == 0 {
+            break;
+        }
+
+        let idle = activity.map(|(own, _)| {
+            own.touch();
+            own.idle
+        });
+        limit(idle, "relay", async { Ok(writer.write_all(&buffer[..read]).await?) }).await?;
+        copied(read as u64);
+    }
+
+    writer.shutdown().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::io::duplex;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Returns both ends of a TCP connection.
+    async fn pair() -> Result<(TcpStream, TcpStream)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let client = TcpStream::connect(listener.local_addr()?).await?;
+        let (server, _) = listener.accept().await?;
+
+        Ok((client, server))
+    }
+
+    #[tokio::test(start_paused = true)]
+    pub async fn timeouts() -> Result<()> {
+        let timeouts = Timeouts::new()
+            .with_handshake(Duration::from_millis(10))
+            .with_idle_upstream(Duration::from_millis(50))
+            .with_idle_downstream(Duration::from_millis(50));
+
+        let pending = std::future::pending::<Result<()>>();
+        let error = timeouts.handshake(pending).await.unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(Error::Timeout(_))));
+        assert!(timeouts.connect(async { Ok(()) }).await.is_ok());
+
+        // Data is relayed, and idle connections are closed eventually.
+        let (mut client, mut source) = duplex(64);
+        let (mut destination, mut server) = duplex(64);
+        let relay = tokio::spawn(async move { timeouts.relay(&mut source, &mut destination, |_, _| {}).await });
+
+        client.write_all(b"ping").await?;
+        let mut buffer = [0; 4];
+        server.read_exact(&mut buffer).await?;
+        assert_eq!(&buffer, b"ping");
+
+        assert_eq!(relay.await??, (4, 0));
+        assert_eq!(client.read(&mut buffer).await?, 0);
+        assert_eq!(server.read(&mut buffer).await?, 0);
+
+        // A client that only receives isn't idle, the destination isn't told it sends no more.
+        let (mut client, mut source) = duplex(64);
+        let (mut destination, mut server) = duplex(64);
+        let relay = tokio::spawn(async move { timeouts.relay(&mut source, &mut destination, |_, _| {}).await });
+
+        for _ in 0..10 {
+            server.write_all(b"data").await?;
+            client.read_exact(&mut buffer).await?;
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(tokio::time::timeout(Duration::from_millis(10), server.read(&mut buffer)).await.is_err());
+        assert_eq!(relay.await??, (0, 40));
+
+        // Each direction has its own timeout, the relay closes once both are exceeded.
+        let timeouts = Timeouts::new()
+            .with_idle_upstream(Duration::from_secs(3600))
+            .with_idle_downstream(Duration::from_millis(50));
+        let (mut client, mut source) = duplex(64);
+        let (mut destination, _server) = duplex(64);
+        let relay = tokio::spawn(async move { timeouts.relay(&mut source, &mut destination, |_, _| {}).await });
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert!(tokio::time::timeout(Duration::from_millis(10), client.read(&mut buffer)).await.is_err());
+        assert!(!relay.is_finished());
+        tokio::time::sleep(Duration::from_secs(3600)).await;
+        assert_eq!(relay.await??, (0, 0));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn relay_failure() -> Result<()> {
+        // Bytes are reported as they're relayed, also when the relay fails.
+        let (mut client, mut source) = pair().await?;
+        let (mut destination, mut server) = pair().await?;
+        let reported = Arc::new(AtomicU64::new(0));
+        let counter = reported.clone();
+        let relay = tokio::spawn(async move {
+            let relayed = |_, down| {
+                counter.fetch_add(down, Ordering::Relaxed);
+            };
+            Timeouts::new().relay(&mut source, &mut destination, relayed).await
+        });
+
+        server.write_all(b"data").await?;
+        let mut buffer = [0; 4];
+        client.read_exact(&mut buffer).await?;
+        assert_eq!(reported.load(Ordering::Relaxed), 4);
+
+        socket2::SockRef::from(&server).set_linger(Some(Duration::from_secs(0)))?;
+        drop(server);
+        assert!(relay.await?.is_err());
+        assert_eq!(reported.load(Ordering::Relaxed), 4);
+
+        Ok(())
+    }
+}
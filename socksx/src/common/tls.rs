@@ -0,0 +1,278 @@
+use crate::{Error, SocksStream};
+use anyhow::{Context, Result};
+use std::convert::TryFrom;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::crypto::{self, CryptoProvider};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::TlsStream;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// Terminates TLS for the clients of a listener. With a client CA, clients must present a
+/// certificate signed by it, the common name of the certificate is the identity of the client.
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    acceptor: tokio_rustls::TlsAcceptor,
+}
+
+impl TlsAcceptor {
+    /// Loads the certificate chain and private key of the listener, and the client CA if any,
+    /// from PEM files.
+    pub fn from_files<P: AsRef<Path>>(
+        certificates: P,
+        key: P,
+        client_ca: Option<P>,
+    ) -> Result<Self> {
+        let client_ca = match client_ca {
+            Some(client_ca) => Some(read(client_ca)?),
+            None => None,
+        };
+
+        Self::parse(&read(certificates)?, &read(key)?, client_ca.as_deref())
+    }
+
+    /// Parses the certificate chain and private key of the listener, and the client CA if any,
+    /// from the contents of PEM files.
+    pub fn parse(
+        certificates: &str,
+        key: &str,
+        client_ca: Option<&str>,
+    ) -> Result<Self> {
+        let builder = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?;
+
+        let builder = match client_ca {
+            Some(client_ca) => {
+                let verifier = WebPkiClientVerifier::builder_with_provider(roots(client_ca)?, provider())
+                    .build()
+                    .map_err(tls_error)?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let config = builder
+            .with_single_cert(parse_certificates(certificates)?, parse_key(key)?)
+            .map_err(tls_error)?;
+
+        Ok(TlsAcceptor {
+            acceptor: tokio_rustls::TlsAcceptor::from(Arc::new(config)),
+        })
+    }
+
+    /// Performs the TLS handshake with a client, and returns the encrypted stream.
+    pub async fn accept(
+        &self,
+        stream: TcpStream,
+    ) -> Result<SocksStream> {
+        let stream = self.acceptor.accept(SocksStream::from(stream)).await.map_err(tls_error)?;
+
+        let identity = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certificates| certificates.first())
+            .and_then(common_name);
+
+        Ok(SocksStream::tls(TlsStream::Server(stream), identity))
+    }
+}
+
+/// Starts TLS with proxies, and verifies them against a CA. Optionally, a client certificate
+/// is presented to them.
+#[derive(Clone)]
+pub struct TlsConnector {
+    connector: tokio_rustls::TlsConnector,
+}
+
+impl fmt::Debug for TlsConnector {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.debug_struct("TlsConnector").finish_non_exhaustive()
+    }
+}
+
+impl TlsConnector {
+    /// Loads the CA that proxies must be signed by, and the certificate chain and private key
+    /// to present to them if any, from PEM files.
+    pub fn from_files<P: AsRef<Path>>(
+        ca: P,
+        identity: Option<(P, P)>,
+    ) -> Result<Self> {
+        let identity = match identity {
+            Some((certificates, key)) => Some((read(certificates)?, read(key)?)),
+            None => None,
+        };
+        let identity = identity.as_ref().map(|(c, k)| (c.as_str(), k.as_str()));
+
+        Self::parse(&read(ca)?, identity)
+    }
+
+    /// Parses the CA that proxies must be signed by, and the certificate chain and private key
+    /// to present to them if any, from the contents of PEM files.
+    pub fn parse(
+        ca: &str,
+        identity: Option<(&str, &str)>,
+    ) -> Result<Self> {
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?
+            .with_root_certificates(roots(ca)?);
+
+        let config = match identity {
+            Some((certificates, key)) => builder
+                .with_client_auth_cert(parse_certificates(certificates)?, parse_key(key)?)
+                .map_err(tls_error)?,
+            None => builder.with_no_client_auth(),
+        };
+
+        Ok(TlsConnector {
+            connector: tokio_rustls::TlsConnector::from(Arc::new(config)),
+        })
+    }
+
+    /// Performs the TLS handshake with the proxy at the other end of `stream`, whose certificate
+    /// must be valid for `host`, and returns the encrypted stream.
+    pub async fn connect(
+        &self,
+        host: &str,
+        stream: SocksStream,
+    ) -> Result<SocksStream> {
+        let server_name = ServerName::try_from(host.to_string())
+            .map_err(|_| Error::Tls(format!("Invalid server name: {}.", host)))?;
+        let stream = self.connector.connect(server_name, stream).await.map_err(tls_error)?;
+
+        Ok(SocksStream::tls(TlsStream::Client(stream), None))
+    }
+}
+
+/// The cryptography of rustls, regardless of the default another crate may have installed.
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+fn read<P: AsRef<Path>>(path: P) -> Result<String> {
+    let path = path.as_ref();
+    std::fs::read_to_string(path).with_context(|| format!("Failed to read PEM file: {:?}", path))
+}
+
+fn parse_certificates(pem: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let certificates = CertificateDer::pem_slice_iter(pem.as_bytes())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(tls_error)?;
+    ensure!(!certificates.is_empty(), Error::Tls(String::from("No certificates found.")));
+
+    Ok(certificates)
+}
+
+fn parse_key(pem: &str) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_slice(pem.as_bytes()).map_err(tls_error)
+}
+
+fn roots(pem: &str) -> Result<Arc<RootCertStore>> {
+    let mut roots = RootCertStore::empty();
+    for certificate in parse_certificates(pem)? {
+        roots.add(certificate).map_err(tls_error)?;
+    }
+
+    Ok(Arc::new(roots))
+}
+
+/// The common name of the subject of a certificate.
+fn common_name(certificate: &CertificateDer) -> Option<String> {
+    let (_, certificate) = X509Certificate::from_der(certificate.as_ref()).ok()?;
+    let common_name = certificate.subject().iter_common_name().next()?;
+
+    common_name.as_str().ok().map(String::from)
+}
+
+fn tls_error<E: fmt::Display>(error: E) -> anyhow::Error {
+    Error::Tls(error.to_string()).into()
+}
+
+/// Generates a CA, a certificate for `localhost` and one for `alice`, both signed by the CA.
+/// Returns an acceptor that requires client certificates, and connectors with and without one.
+#[cfg(test)]
+pub(crate) fn test_pki() -> Result<(TlsAcceptor, TlsConnector, TlsConnector)> {
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+
+    let ca_key = KeyPair::generate()?;
+    let mut params = CertificateParams::new(vec![])?;
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.distinguished_name.push(DnType::CommonName, "socksx");
+    let ca = params.self_signed(&ca_key)?;
+
+    let server_key = KeyPair::generate()?;
+    let server = CertificateParams::new(vec![String::from("localhost")])?.signed_by(&server_key, &ca, &ca_key)?;
+
+    let client_key = KeyPair::generate()?;
+    let mut params = CertificateParams::new(vec![])?;
+    params.distinguished_name.push(DnType::CommonName, "alice");
+    let client = params.signed_by(&client_key, &ca, &ca_key)?;
+
+    let acceptor = TlsAcceptor::parse(&server.pem(), &server_key.serialize_pem(), Some(&ca.pem()))?;
+    let connector = TlsConnector::parse(&ca.pem(), Some((&client.pem(), &client_key.serialize_pem())))?;
+    let anonymous = TlsConnector::parse(&ca.pem(), None)?;
+
+    Ok((acceptor, connector, anonymous))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    pub async fn handshake() -> Result<()> {
+        let (acceptor, connector, anonymous) = test_pki()?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+
+        let server = tokio::spawn(async move {
+            let mut accepted = vec![];
+            for _ in 0..3 {
+                let (stream, _) = listener.accept().await?;
+                accepted.push(acceptor.accept(stream).await);
+            }
+
+            Ok::<_, anyhow::Error>(accepted)
+        });
+
+        // The client presents its certificate, its common name is its identity.
+        let stream = TcpStream::connect(address).await?;
+        let mut client = connector.connect("localhost", stream.into()).await?;
+        client.write_all(b"ping").await?;
+        client.flush().await?;
+
+        // Clients without a certificate are rejected.
+        let stream = TcpStream::connect(address).await?;
+        let _ = anonymous.connect("localhost", stream.into()).await;
+
+        // The certificate of the listener must be valid for the host.
+        let stream = TcpStream::connect(address).await?;
+        assert!(connector.connect("example.com", stream.into()).await.is_err());
+
+        let mut accepted = server.await??.into_iter();
+        let mut stream = accepted.next().unwrap()?;
+        assert!(stream.is_tls());
+        assert_eq!(stream.identity(), Some("alice"));
+
+        let mut buffer = [0; 4];
+        stream.read_exact(&mut buffer).await?;
+        assert_eq!(&buffer, b"ping");
+        assert!(accepted.all(|stream| stream.is_err()));
+
+        assert!(TlsAcceptor::parse("", "", None).is_err());
+
+        Ok(())
+    }
+}
@@ -1,6 +1,9 @@
+use crate::constants::SOCKS_MAX_INITIAL_DATA;
+use crate::Error;
 use anyhow::Result;
 use std::{net::SocketAddr, os};
-use tokio::net::{self, TcpStream};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{self, TcpSocket, TcpStream};
 
 ///
 ///
@@ -32,18 +35,29 @@ pub async fn resolve_addr<S: Into<String>>(addr: S) -> Result<SocketAddr> {
     }
 
     // Otherwise, address is probably a domain name.
-    let addresses: Vec<SocketAddr> = net::lookup_host(addr).await?.collect();
+    // The lookup error is kept as the cause, e.g., an invalid port or a failing resolver.
+    let lookup = net::lookup_host(addr.as_str()).await.map(|a| a.collect::<Vec<SocketAddr>>());
+    let addresses = match lookup {
+        Ok(addresses) => addresses,
+        Err(error) => return Err(anyhow::Error::new(error).context(Error::Resolve(addr))),
+    };
     match addresses[..] {
         [first, ..] => Ok(first),
-        [] => bail!("Domain name didn't resolve to an IP address."),
+        [] => bail!(Error::Resolve(addr)),
     }
 }
 
+/// The host of a `host:port` address, without the brackets of an IPv6 address.
+pub(crate) fn host(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map(|(host, _)| host).unwrap_or(addr);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
 ///
 ///
 ///
 pub async fn try_read_initial_data(stream: &mut TcpStream) -> Result<Option<Vec<u8>>> {
-    let mut initial_data = Vec::with_capacity(2usize.pow(14)); // 16KB is the max
+    let mut initial_data = Vec::with_capacity(SOCKS_MAX_INITIAL_DATA);
 
     stream.readable().await?;
     match stream.try_read_buf(&mut initial_data) {
@@ -54,3 +68,130 @@ pub async fn try_read_initial_data(stream: &mut TcpStream) -> Result<Option<Vec<
         }
     }
 }
+
+/// Connects to the destination, and sends the initial data. On Linux, TCP Fast Open is
+/// used (if enabled on the system) to send the initial data before the connect completes.
+pub async fn connect_with_initial_data(
+    destination: SocketAddr,
+    initial_data: &[u8],
+) -> Result<TcpStream> {
+    let socket = if destination.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+
+    if !initial_data.is_empty() {
+        enable_fast_open(&socket);
+    }
+
+    let mut stream = socket
+        .connect(destination)
+        .await
+        .map_err(|e| Error::connect(destination, e))?;
+    if !initial_data.is_empty() {
+        stream.write_all(initial_data).await?;
+    }
+
+    Ok(stream)
+}
+
+/// Defers the connect until the first write, so that the written data is carried in the SYN.
+/// This is best-effort: without TCP Fast Open a regular three-way handshake is performed.
+#[cfg(target_os = "linux")]
+pub(crate) fn enable_fast_open<S: os::unix::io::AsRawFd>(socket: &S) -> bool {
+    set_socket_option(socket, libc::IPPROTO_TCP, libc::TCP_FASTOPEN_CONNECT, 1)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn enable_fast_open<S>(_socket: &S) -> bool {
+    false
+}
+
+/// Sets an integer socket option, and tells whether the system accepted it.
+pub(crate) fn set_socket_option<S: os::unix::io::AsRawFd>(
+    socket: &S,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> bool {
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if result != 0 {
+        debug!("Socket option {} is unavailable: {}", name, std::io::Error::last_os_error());
+    }
+
+    result == 0
+}
+
+/// Serves incoming connections with the handler, on a random local port.
+#[cfg(test)]
+pub(crate) async fn spawn_proxy<H>(handler: H) -> Result<SocketAddr>
+where
+    H: crate::SocksHandler + Clone + Send + Sync + 'static,
+{
+    let listener = net::TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = listener.local_addr()?;
+
+    tokio::spawn(async move {
+        loop {
+            let (incoming, _) = listener.accept().await.unwrap();
+            let handler = handler.clone();
+
+            tokio::spawn(async move { handler.accept_request(&mut incoming.into()).await });
+        }
+    });
+
+    Ok(proxy_addr)
+}
+
+/// Serves incoming connections with the handler over TLS, on a random local port.
+#[cfg(test)]
+pub(crate) async fn spawn_tls_proxy<H>(
+    handler: H,
+    acceptor: crate::TlsAcceptor,
+) -> Result<SocketAddr>
+where
+    H: crate::SocksHandler + Clone + Send + Sync + 'static,
+{
+    let listener = net::TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = listener.local_addr()?;
+
+    tokio::spawn(async move {
+        loop {
+            let (incoming, _) = listener.accept().await.unwrap();
+            let handler = handler.clone();
+            let acceptor = acceptor.clone();
+
+            tokio::spawn(async move { handler.accept_request(&mut acceptor.accept(incoming).await?).await });
+        }
+    });
+
+    Ok(proxy_addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[tokio::test]
+    pub async fn resolve() -> Result<()> {
+        assert_eq!(resolve_addr("127.0.0.1:1080").await?, "127.0.0.1:1080".parse()?);
+
+        // The reason the lookup failed is kept.
+        let error = resolve_addr("localhost:port").await.unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(Error::Resolve(_))));
+        assert!(error.root_cause().downcast_ref::<io::Error>().is_some());
+
+        Ok(())
+    }
+}
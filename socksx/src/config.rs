@@ -0,0 +1,587 @@
+use anyhow::{Context, Result};
+use itertools::Itertools;
+use serde::Deserialize;
+use socksx::acl::{self, AccessControl, Action, Command, Rule};
+use socksx::auth::{HtpasswdAuthenticator, StaticAuthenticator};
+use socksx::limits::{ConnectionLimits, LimitMode};
+use socksx::{Authenticator, HttpConnectHandler, MetricsHook, MultiProtocolHandler, ProxyAddress, SocksHandler};
+use socksx::{Socks4Handler, Socks5Handler, Socks6Handler, Timeouts, TlsAcceptor, TlsConnector};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub type Handler = Arc<dyn SocksHandler + Sync + Send>;
+
+/// The contents of a configuration file: one or more listeners.
+///
+/// ```toml
+/// [[listener]]
+/// port = 1080
+/// protocol = "auto"
+/// limit_per_client = 16
+/// handshake_timeout = 10
+/// chain = ["socks6+tls://proxy.example.com:1080"]
+/// chain_ca = "/etc/socksx/ca.pem"
+///
+/// [listener.tls]
+/// certificate = "/etc/socksx/cert.pem"
+/// key = "/etc/socksx/key.pem"
+/// client_ca = "/etc/socksx/clients.pem"
+///
+/// [listener.auth]
+/// type = "htpasswd"
+/// path = "/etc/socksx/users"
+///
+/// [listener.acl]
+/// default = "allow"
+///
+/// [[listener.acl.rules]]
+/// action = "deny"
+/// destinations = ["10.0.0.0/8", "*.internal"]
+/// ports = [22, "6000-6100"]
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default, rename = "listener")]
+    pub listeners: Vec<ListenerConfig>,
+}
+
+impl Config {
+    /// Reads and validates a configuration file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let contents =
+            std::fs::read_to_string(path).with_context(|| format!("Failed to read config file: {:?}", path))?;
+
+        Self::parse(&contents).with_context(|| format!("Invalid config file: {:?}", path))
+    }
+
+    /// Parses and validates the contents of a configuration file.
+    pub fn parse(contents: &str) -> Result<Self> {
+        let config: Config = toml::from_str(contents)?;
+        ensure!(!config.listeners.is_empty(), "No listeners are declared, add a [[listener]] table.");
+
+        Ok(config)
+    }
+}
+
+/// Validates the listeners, e.g., after overrides are applied: their addresses must be unique,
+/// and SOCKS4 listeners can't require authentication, SOCKS4 clients have no way to provide it.
+pub fn validate(listeners: &[ListenerConfig]) -> Result<()> {
+    let mut addresses = HashSet::new();
+    for listener in listeners {
+        ensure!(
+            listener.auth.is_none() || listener.protocol != Protocol::Socks4,
+            "Listener {} requires authentication, which SOCKS4 clients can't provide.",
+            listener.address()
+        );
+        ensure!(
+            addresses.insert((listener.host.as_str(), listener.port)),
+            "Multiple listeners on {}:{}.",
+            listener.host,
+            listener.port
+        );
+    }
+
+    Ok(())
+}
+
+/// A listener, and how it handles the clients it accepts.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// Host (IP) to listen on.
+    #[serde(default = "default_host")]
+    pub host: String,
+    /// Port to listen on.
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// Protocol the clients speak.
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// Static proxy chain, the order is preserved.
+    #[serde(default)]
+    pub chain: Vec<String>,
+    /// CA (PEM) that the `+tls` links of the chain must be signed by.
+    #[serde(default)]
+    pub chain_ca: Option<PathBuf>,
+    /// Concurrent connections limit (0=unlimited).
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+    /// Concurrent connections limit per client IP (0=unlimited).
+    #[serde(default)]
+    pub limit_per_client: usize,
+    /// Concurrent connections limit per authenticated user (0=unlimited).
+    #[serde(default)]
+    pub limit_per_user: usize,
+    /// Seconds a connection that exceeds a limit waits for a slot, none means it's refused right away.
+    #[serde(default)]
+    pub limit_queue_timeout: Option<u64>,
+    /// Seconds a client has to send its request, none means indefinitely.
+    #[serde(default)]
+    pub handshake_timeout: Option<u64>,
+    /// Seconds connecting to a destination, or waiting for a BIND peer, may take, none means indefinitely.
+    #[serde(default)]
+    pub connect_timeout: Option<u64>,
+    /// Seconds a client may send nothing, the relay closes once the destination is idle too. None means the
+    /// downstream timeout applies, or that the relay never idles out if that's none too.
+    #[serde(default)]
+    pub upstream_idle_timeout: Option<u64>,
+    /// Seconds a destination may send nothing, the relay closes once the client is idle too. None means the
+    /// upstream timeout applies, or that the relay never idles out if that's none too.
+    #[serde(default)]
+    pub downstream_idle_timeout: Option<u64>,
+    /// Backend that checks the credentials of clients, none means clients don't authenticate.
+    /// SOCKS4 clients can't authenticate, so `auto` listeners refuse them.
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+    /// USERIDs accepted from SOCKS4 clients, then the user of their connections. None means any
+    /// USERID is accepted, and ignored.
+    #[serde(default)]
+    pub user_ids: Option<Vec<String>>,
+    /// Access control rules, none means every request is allowed.
+    #[serde(default)]
+    pub acl: Option<AclConfig>,
+    /// Certificate and key to terminate TLS with, none means clients connect over plain TCP.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        ListenerConfig {
+            host: default_host(),
+            port: default_port(),
+            protocol: Protocol::default(),
+            chain: vec![],
+            chain_ca: None,
+            limit: default_limit(),
+            limit_per_client: 0,
+            limit_per_user: 0,
+            limit_queue_timeout: None,
+            handshake_timeout: None,
+            connect_timeout: None,
+            upstream_idle_timeout: None,
+            downstream_idle_timeout: None,
+            auth: None,
+            user_ids: None,
+            acl: None,
+            tls: None,
+        }
+    }
+}
+
+fn default_host() -> String {
+    String::from("0.0.0.0")
+}
+
+fn default_port() -> u16 {
+    1080
+}
+
+fn default_limit() -> usize {
+    256
+}
+
+impl ListenerConfig {
+    /// The address to listen on.
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    /// Creates the connection limits of this listener.
+    pub fn limits(&self) -> ConnectionLimits {
+        let mode = match self.limit_queue_timeout {
+            Some(timeout) => LimitMode::Queue(Duration::from_secs(timeout)),
+            None => LimitMode::Refuse,
+        };
+
+        ConnectionLimits::new(self.limit)
+            .with_per_client(self.limit_per_client)
+            .with_per_user(self.limit_per_user)
+            .with_mode(mode)
+    }
+
+    /// Creates the timeouts of this listener.
+    pub fn timeouts(&self) -> Timeouts {
+        let mut timeouts = Timeouts::new();
+        if let Some(timeout) = self.handshake_timeout {
+            timeouts = timeouts.with_handshake(Duration::from_secs(timeout));
+        }
+        if let Some(timeout) = self.connect_timeout {
+            timeouts = timeouts.with_connect(Duration::from_secs(timeout));
+        }
+        if let Some(timeout) = self.upstream_idle_timeout {
+            timeouts = timeouts.with_idle_upstream(Duration::from_secs(timeout));
+        }
+        if let Some(timeout) = self.downstream_idle_timeout {
+            timeouts = timeouts.with_idle_downstream(Duration::from_secs(timeout));
+        }
+
+        timeouts
+    }
+
+    /// Creates the handler for the clients of this listener, the handler enforces the per-user
+    /// limit of `limits`, and reports to each of `hooks`.
+    pub fn handler(
+        &self,
+        limits: &ConnectionLimits,
+        hooks: &[Arc<dyn MetricsHook>],
+    ) -> Result<Handler> {
+        let chain: Vec<ProxyAddress> = self
+            .chain
+            .iter()
+            .cloned()
+            .map(|c| c.try_into())
+            .try_collect()
+            .context("Invalid chain")?;
+
+        let authenticator = match &self.auth {
+            Some(auth) => Some(auth.authenticator()?),
+            None => None,
+        };
+
+        let timeouts = self.timeouts();
+        let mut socks4 = Socks4Handler::new(chain.clone())
+            .with_limits(limits.clone())
+            .with_timeouts(timeouts);
+        if let Some(user_ids) = &self.user_ids {
+            socks4 = socks4.with_user_ids(user_ids.clone());
+        }
+
+        let mut socks5 = Socks5Handler::new(chain.clone())
+            .with_limits(limits.clone())
+            .with_timeouts(timeouts);
+        let mut socks6 = Socks6Handler::new(chain.clone())
+            .with_limits(limits.clone())
+            .with_timeouts(timeouts);
+        let mut http = HttpConnectHandler::new(chain)
+            .with_limits(limits.clone())
+            .with_timeouts(timeouts);
+        if let Some(acl) = &self.acl {
+            let access_control = acl.access_control().context("Invalid ACL")?;
+            socks4 = socks4.with_access_control(access_control.clone());
+            socks5 = socks5.with_access_control(access_control.clone());
+            socks6 = socks6.with_access_control(access_control.clone());
+            http = http.with_access_control(access_control);
+        }
+        for hook in hooks {
+            socks4 = socks4.with_metrics(hook.clone());
+            socks5 = socks5.with_metrics(hook.clone());
+            socks6 = socks6.with_metrics(hook.clone());
+            http = http.with_metrics(hook.clone());
+        }
+        if let Some(ca) = &self.chain_ca {
+            let connector = TlsConnector::from_files(ca, None).context("Invalid chain CA")?;
+            socks4 = socks4.with_chain_tls(connector.clone());
+            socks5 = socks5.with_chain_tls(connector.clone());
+            socks6 = socks6.with_chain_tls(connector.clone());
+            http = http.with_chain_tls(connector);
+        }
+        if let Some(authenticator) = authenticator {
+            socks5 = socks5.with_authenticator(authenticator.clone());
+            socks6 = socks6.with_authenticator(authenticator.clone());
+            http = http.with_authenticator(authenticator);
+        }
+
+        let handler: Handler = match self.protocol {
+            Protocol::Socks4 => Arc::new(socks4),
+            Protocol::Socks5 => Arc::new(socks5),
+            Protocol::Socks6 => Arc::new(socks6),
+            Protocol::Http => Arc::new(http),
+            // SOCKS4 clients can't authenticate, so they're refused if the others must.
+            Protocol::Auto => Arc::new(
+                MultiProtocolHandler::default()
+                    .with_socks4(Some(socks4).filter(|_| self.auth.is_none()))
+                    .with_socks5(Some(socks5))
+                    .with_socks6(Some(socks6))
+                    .with_http(Some(http))
+                    .with_timeouts(timeouts),
+            ),
+        };
+
+        Ok(handler)
+    }
+
+    /// Creates the TLS acceptor of this listener, if it terminates TLS.
+    pub fn tls_acceptor(&self) -> Result<Option<TlsAcceptor>> {
+        match &self.tls {
+            Some(tls) => Ok(Some(TlsAcceptor::from_files(&tls.certificate, &tls.key, tls.client_ca.as_ref())?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// The protocol of a listener: a SOCKS version, HTTP, or whatever each client speaks.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+pub enum Protocol {
+    #[serde(rename = "4")]
+    Socks4,
+    #[serde(rename = "5")]
+    Socks5,
+    #[serde(rename = "6")]
+    #[default]
+    Socks6,
+    #[serde(rename = "http")]
+    Http,
+    #[serde(rename = "auto")]
+    Auto,
+}
+
+impl FromStr for Protocol {
+    type Err = anyhow::Error;
+
+    fn from_str(protocol: &str) -> Result<Self> {
+        match protocol {
+            "4" => Ok(Protocol::Socks4),
+            "5" => Ok(Protocol::Socks5),
+            "6" => Ok(Protocol::Socks6),
+            "http" => Ok(Protocol::Http),
+            "auto" => Ok(Protocol::Auto),
+            protocol => bail!("Unrecognized protocol: {}", protocol),
+        }
+    }
+}
+
+/// Backend that checks the credentials of clients.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum AuthConfig {
+    /// Usernames and plaintext passwords, listed in the config file.
+    Static { users: HashMap<String, String> },
+    /// An htpasswd-style file, with bcrypt or SHA-1 hashes.
+    Htpasswd { path: PathBuf },
+}
+
+impl AuthConfig {
+    /// Creates the authenticator, e.g., by loading the htpasswd file.
+    pub fn authenticator(&self) -> Result<Arc<dyn Authenticator>> {
+        let authenticator: Arc<dyn Authenticator> = match self {
+            AuthConfig::Static { users } => Arc::new(StaticAuthenticator::new(users.clone())),
+            AuthConfig::Htpasswd { path } => Arc::new(HtpasswdAuthenticator::from_file(path)?),
+        };
+
+        Ok(authenticator)
+    }
+}
+
+/// Certificate and key of a listener, and the CA that client certificates must be signed by.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Certificate chain (PEM) presented to clients.
+    pub certificate: PathBuf,
+    /// Private key (PEM) of the certificate.
+    pub key: PathBuf,
+    /// CA (PEM) that client certificates must be signed by, none means clients don't present one.
+    /// The common name of a client certificate is the user of its connections.
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+}
+
+/// Access control rules, the first matching rule decides.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AclConfig {
+    /// Action for requests no rule matches.
+    #[serde(default = "default_action")]
+    pub default: ActionConfig,
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+}
+
+fn default_action() -> ActionConfig {
+    ActionConfig::Allow
+}
+
+impl AclConfig {
+    /// Creates the access control list, e.g., by parsing the networks of the rules.
+    pub fn access_control(&self) -> Result<AccessControl> {
+        let rules: Vec<Rule> = self
+            .rules
+            .iter()
+            .enumerate()
+            .map(|(i, rule)| rule.rule().with_context(|| format!("Invalid rule #{}", i + 1)))
+            .try_collect()?;
+
+        Ok(AccessControl::new(rules).with_default(self.default.into()))
+    }
+}
+
+/// A rule: conditions that are left out match any request.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    pub action: ActionConfig,
+    /// Client networks, e.g., `10.0.0.0/8`.
+    #[serde(default)]
+    pub sources: Vec<String>,
+    /// Authenticated users.
+    #[serde(default)]
+    pub users: Vec<String>,
+    /// Destination networks, or domain name patterns such as `*.example.com`.
+    #[serde(default)]
+    pub destinations: Vec<String>,
+    /// Destination ports, or port ranges such as `"8000-8080"`.
+    #[serde(default)]
+    pub ports: Vec<PortsConfig>,
+    #[serde(default)]
+    pub commands: Vec<CommandConfig>,
+}
+
+impl RuleConfig {
+    fn rule(&self) -> Result<Rule> {
+        let mut rule = Rule::new(self.action.into());
+        for source in &self.sources {
+            rule = rule.with_source(acl::parse_network(source)?);
+        }
+        for user in &self.users {
+            rule = rule.with_user(user.clone());
+        }
+        for destination in &self.destinations {
+            rule = rule.with_destination(destination.parse()?);
+        }
+        for ports in &self.ports {
+            rule = rule.with_ports(match ports {
+                PortsConfig::Port(port) => *port..=*port,
+                PortsConfig::Range(range) => acl::parse_ports(range)?,
+            });
+        }
+        for command in &self.commands {
+            rule = rule.with_command((*command).into());
+        }
+
+        Ok(rule)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ActionConfig {
+    Allow,
+    Deny,
+}
+
+impl From<ActionConfig> for Action {
+    fn from(action: ActionConfig) -> Self {
+        match action {
+            ActionConfig::Allow => Action::Allow,
+            ActionConfig::Deny => Action::Deny,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandConfig {
+    Connect,
+    Bind,
+    UdpAssociate,
+}
+
+impl From<CommandConfig> for Command {
+    fn from(command: CommandConfig) -> Self {
+        match command {
+            CommandConfig::Connect => Command::Connect,
+            CommandConfig::Bind => Command::Bind,
+            CommandConfig::UdpAssociate => Command::UdpAssociate,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum PortsConfig {
+    Port(u16),
+    Range(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use socksx::{Socks4Client, SocksStream};
+    use tokio::net::TcpListener;
+
+    #[test]
+    pub fn parse() -> Result<()> {
+        let config = Config::parse(
+            r#"
+            [[listener]]
+            port = 1080
+            protocol = "auto"
+            limit_per_user = 4
+            limit_queue_timeout = 10
+            handshake_timeout = 10
+            upstream_idle_timeout = 300
+
+            [listener.auth]
+            type = "static"
+            users = { alice = "secret" }
+
+            [[listener]]
+            port = 1081
+            protocol = "5"
+            chain = ["socks6://127.0.0.1:1082", "socks5+tls://proxy.example.com:1080"]
+
+            [listener.acl]
+            default = "deny"
+
+            [[listener.acl.rules]]
+            action = "allow"
+            sources = ["10.0.0.0/8", "192.168.1.1"]
+            destinations = ["*.example.com"]
+            ports = [443, "8000-8080"]
+            commands = ["connect", "udp_associate"]
+            "#,
+        )?;
+
+        assert_eq!(config.listeners.len(), 2);
+        assert_eq!(config.listeners[0].protocol, Protocol::Auto);
+        assert_eq!(config.listeners[1].host, "0.0.0.0");
+        validate(&config.listeners)?;
+        for listener in &config.listeners {
+            listener.handler(&listener.limits(), &[])?;
+        }
+
+        // Mistakes are reported, rather than ignored.
+        assert!(Config::parse("").is_err());
+        assert!(Config::parse("[[listener]]\nprotocol = \"7\"").is_err());
+        assert!(Config::parse("[[listener]]\nlimti = 5").is_err());
+        assert!(Config::parse("[[listener]]\nchain = [\"socks9://proxy:1080\"]")?.listeners[0]
+            .handler(&ConnectionLimits::default(), &[])
+            .is_err());
+        assert!(validate(&[ListenerConfig::default(), ListenerConfig::default()]).is_err());
+        let socks4 = "[[listener]]\nprotocol = \"4\"\n[listener.auth]\ntype = \"static\"\nusers = {}";
+        assert!(validate(&Config::parse(socks4)?.listeners).is_err());
+        let rules = "[[listener]]\n[listener.acl]\n[[listener.acl.rules]]\naction = \"deny\"\nsources = [\"10/8\"]";
+        assert!(Config::parse(rules)?.listeners[0].handler(&ConnectionLimits::default(), &[]).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn auto_with_auth() -> Result<()> {
+        let auto = "[[listener]]\nprotocol = \"auto\"\n[listener.auth]\ntype = \"static\"\nusers = {}";
+        let config = Config::parse(auto)?;
+        let listener = &config.listeners[0];
+        let handler = listener.handler(&listener.limits(), &[])?;
+
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?.to_string();
+        tokio::spawn(async move {
+            let (incoming, _) = proxy.accept().await?;
+            handler.accept_request(&mut SocksStream::from(incoming)).await
+        });
+
+        // SOCKS4 clients can't authenticate, so they're refused.
+        let target = TcpListener::bind("127.0.0.1:0").await?;
+        let client = Socks4Client::new(proxy_addr, None).await?;
+        assert!(client.connect(target.local_addr()?.to_string()).await.is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,384 @@
+use crate::acl::{AccessControl, AccessRequest, Command};
+use crate::addresses::ProxyAddress;
+use crate::limits::{ConnectionLimits, ConnectionPermit};
+use crate::metrics::{Hook, MetricsHook, Protocol};
+use crate::http::{self, HttpReply, HttpRequest};
+use crate::socks6::SocksChain;
+use crate::timeouts::Timeouts;
+use crate::{Authenticator, Error, SocksHandler, SocksStream, TlsConnector};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::AsyncWriteExt;
+use tracing::Instrument;
+
+/// An HTTP proxy front-end: tunnels `CONNECT` requests, and forwards plain HTTP requests with
+/// an absolute URI, through the same chain as the SOCKS handlers.
+#[derive(Clone)]
+pub struct HttpConnectHandler {
+    access_control: Option<Arc<AccessControl>>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    chain: Vec<ProxyAddress>,
+    chain_tls: Option<TlsConnector>,
+    limits: Option<ConnectionLimits>,
+    metrics: Hook,
+    timeouts: Timeouts,
+}
+
+impl Default for HttpConnectHandler {
+    fn default() -> Self {
+        Self::new(vec![])
+    }
+}
+
+impl HttpConnectHandler {
+    /// Creates a handler that connects through `chain`, if not empty.
+    pub fn new(chain: Vec<ProxyAddress>) -> Self {
+        HttpConnectHandler {
+            access_control: None,
+            authenticator: None,
+            chain,
+            chain_tls: None,
+            limits: None,
+            metrics: Hook::new(Protocol::Http),
+            timeouts: Timeouts::default(),
+        }
+    }
+
+    /// Requires clients to authenticate with Basic `Proxy-Authorization`, checked by `authenticator`.
+    pub fn with_authenticator<A: Authenticator + 'static>(
+        mut self,
+        authenticator: A,
+    ) -> Self {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /// Consults `access_control` before acting on a request, denied requests are refused.
+    pub fn with_access_control(
+        mut self,
+        access_control: AccessControl,
+    ) -> Self {
+        self.access_control = Some(Arc::new(access_control));
+        self
+    }
+
+    /// Holds a slot of the per-user limit of `limits` while relaying for an authenticated client.
+    pub fn with_limits(
+        mut self,
+        limits: ConnectionLimits,
+    ) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    /// Reports the events of this handler, e.g., handshakes and bytes relayed, to `hook`. Call
+    /// this again to report to several hooks, e.g., metrics and an access log.
+    pub fn with_metrics<H: MetricsHook + 'static>(
+        mut self,
+        hook: H,
+    ) -> Self {
+        self.metrics = self.metrics.with_hook(Arc::new(hook));
+        self
+    }
+
+    /// Limits the time spent on the handshake, on connecting to the destination, and on idle relays.
+    pub fn with_timeouts(
+        mut self,
+        timeouts: Timeouts,
+    ) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Starts TLS with the `+tls` links of the chain, and verifies them with `connector`.
+    pub fn with_chain_tls(
+        mut self,
+        connector: TlsConnector,
+    ) -> Self {
+        self.chain_tls = Some(connector);
+        self
+    }
+}
+
+#[async_trait]
+impl SocksHandler for HttpConnectHandler {
+    /// Sets up the connection the client asked for, and relays between both until either closes.
+    async fn accept_request(
+        &self,
+        source: &mut SocksStream,
+    ) -> Result<()> {
+        let client = source.peer_addr()?;
+        self.metrics.scope(client, self.serve(source)).await
+    }
+
+    /// Refuses the request of the client, e.g., because the proxy is at capacity.
+    async fn refuse_request(
+        &self,
+        source: &mut SocksStream,
+    ) -> Result<()> {
+        let client = source.peer_addr()?;
+        let reply = self.reply(source, HttpReply::ServiceUnavailable);
+        self.metrics.scope(client, reply).await
+    }
+
+    /// Reads the request of the client, and sets up the connection it asked for. For plain
+    /// HTTP requests, the request head is already sent to the returned stream.
+    async fn setup(
+        &self,
+        source: &mut SocksStream,
+    ) -> Result<SocksStream> {
+        let handshake = self.timeouts.handshake(self.handshake(source)).await;
+        let (request, _) = self.metrics.handshake(handshake)?;
+        self.execute(source, request).await
+    }
+}
+
+impl HttpConnectHandler {
+    /// Handles the client, from its request until the relay ends.
+    async fn serve(
+        &self,
+        source: &mut SocksStream,
+    ) -> Result<()> {
+        let handshake = self.timeouts.handshake(self.handshake(source)).await;
+        let (request, identity) = self.metrics.handshake(handshake)?;
+        if let Ok(destination) = request.destination() {
+            self.metrics.request(&request.method, &destination, identity.as_deref());
+        }
+
+        let _permit = self.acquire(source, identity.as_deref()).await?;
+        let mut destination = self.execute(source, request).await?;
+
+        // Start bidirectional copy, after this the connection closes.
+        self.timeouts.relay(source, &mut destination, |up, down| self.metrics.relayed(up, down)).await?;
+
+        Ok(())
+    }
+
+    /// Reads the request of the client, and authenticates it if required. Returns the request,
+    /// along with the identity of the client if it authenticated itself. On failure, the client
+    /// is told why.
+    async fn handshake(
+        &self,
+        source: &mut SocksStream,
+    ) -> Result<(HttpRequest, Option<String>)> {
+        match self.read_request(source).await {
+            Ok((request, identity)) => {
+                debug!(
+                    "{} to {} for {}",
+                    request.method,
+                    request.target,
+                    identity.as_deref().unwrap_or("anonymous")
+                );
+
+                Ok((request, identity))
+            }
+            Err(error) => {
+                // Tell the client why, before closing the connection.
+                self.reply(source, HttpReply::from(&error)).await?;
+                Err(error)
+            }
+        }
+    }
+
+    /// Reads the request of the client, authenticates it, and consults the access control rules.
+    async fn read_request(
+        &self,
+        source: &mut SocksStream,
+    ) -> Result<(HttpRequest, Option<String>)> {
+        let request = http::read_request(source).await?;
+
+        // Clients known by their TLS certificate don't have to send credentials.
+        let identity = match (source.identity(), &self.authenticator) {
+            (Some(identity), _) => Some(identity.to_string()),
+            (None, Some(authenticator)) => {
+                let credentials = request.credentials().ok_or_else(|| {
+                    Error::Authentication(String::from("Client didn't send Basic proxy credentials."))
+                })?;
+
+                let identity = authenticator.authenticate(&credentials).instrument(info_span!("auth")).await?;
+                let reason = "Username/password authentication failed.";
+                Some(identity.ok_or_else(|| Error::Authentication(String::from(reason)))?)
+            }
+            (None, None) => None,
+        };
+
+        // Consult the access control rules, before acting on the request.
+        if let Some(access_control) = &self.access_control {
+            let access_request = AccessRequest {
+                source: source.peer_addr()?.ip(),
+                user: identity.as_deref(),
+                destination: Some(&request.destination()?),
+                command: Command::Connect,
+            };
+            access_control.authorize(&access_request).await?;
+        }
+
+        Ok((request, identity))
+    }
+
+    /// Takes a slot of the per-user limit for the identity of the client, if it has one. The
+    /// slot is released when the returned permit is dropped.
+    async fn acquire(
+        &self,
+        source: &mut SocksStream,
+        identity: Option<&str>,
+    ) -> Result<ConnectionPermit> {
+        let (limits, user) = match (&self.limits, identity) {
+            (Some(limits), Some(user)) => (limits, user),
+            _ => return Ok(ConnectionPermit::default()),
+        };
+
+        match limits.acquire_user(user).await {
+            Ok(permit) => Ok(permit),
+            Err(error) => {
+                self.reply(source, HttpReply::from(&error)).await?;
+                Err(error)
+            }
+        }
+    }
+
+    /// Connects to the destination of the request, through the chain if one is configured.
+    async fn execute(
+        &self,
+        source: &mut SocksStream,
+        request: HttpRequest,
+    ) -> Result<SocksStream> {
+        let destination = match request.destination() {
+            Ok(destination) => destination,
+            Err(error) => {
+                self.reply(source, HttpReply::from(&error)).await?;
+                return Err(error);
+            }
+        };
+
+        let mut chain = SocksChain::default();
+        if !self.chain.is_empty() {
+            chain.detour(&self.chain);
+        }
+
+        let (links, start) = (chain.remaining().to_vec(), Instant::now());
+        let connect = chain.connect(destination, &[], self.chain_tls.as_ref());
+        let mut destination = match self.timeouts.connect(connect).await {
+            Ok(destination) => destination,
+            Err(error) => {
+                // Tell the client why, before closing the connection.
+                self.reply(source, HttpReply::from(&error)).await?;
+                return Err(error);
+            }
+        };
+        self.metrics.connected(start.elapsed(), &links, &destination);
+
+        if request.method == "CONNECT" {
+            // Notify source that the tunnel has been set up.
+            self.reply(source, HttpReply::Ok).await?;
+            source.flush().await?;
+        } else {
+            // The origin server responds to the client directly.
+            destination.write_all(&request.into_origin_bytes()?).await?;
+        }
+
+        Ok(destination)
+    }
+
+    /// Sends a reply to the client, and reports it.
+    async fn reply(
+        &self,
+        source: &mut SocksStream,
+        reply: HttpReply,
+    ) -> Result<()> {
+        self.metrics.reply(reply as u16);
+        http::write_reply(source, reply).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::StaticAuthenticator;
+    use crate::util::spawn_proxy;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Sends a request head to the proxy, and returns the stream and the status of the response.
+    async fn request(
+        proxy_addr: std::net::SocketAddr,
+        head: String,
+    ) -> Result<(TcpStream, String)> {
+        let mut stream = TcpStream::connect(proxy_addr).await?;
+        stream.write_all(head.as_bytes()).await?;
+
+        let mut response = vec![];
+        while !response.ends_with(b"\r\n\r\n") {
+            response.push(stream.read_u8().await?);
+        }
+
+        let response = String::from_utf8_lossy(&response);
+        let status = response.split(' ').nth(1).unwrap_or_default().to_string();
+
+        Ok((stream, status))
+    }
+
+    #[tokio::test]
+    pub async fn connect() -> Result<()> {
+        let users = vec![("alice", "secret")].into_iter().collect();
+        let handler = HttpConnectHandler::default().with_authenticator(StaticAuthenticator::new(users));
+        let proxy_addr = spawn_proxy(handler).await?;
+
+        let target = TcpListener::bind("127.0.0.1:0").await?;
+        let destination = target.local_addr()?.to_string();
+
+        let head = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", destination, destination);
+        let (_, status) = request(proxy_addr, head).await?;
+        assert_eq!(status, "407");
+
+        // "alice:secret"
+        let head = format!(
+            "CONNECT {} HTTP/1.1\r\nProxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\n\r\n",
+            destination
+        );
+        let (mut outgoing, status) = request(proxy_addr, head).await?;
+        assert_eq!(status, "200");
+
+        let (mut incoming, _) = target.accept().await?;
+        outgoing.write_all(b"ping").await?;
+        let mut buffer = [0; 4];
+        incoming.read_exact(&mut buffer).await?;
+        assert_eq!(&buffer, b"ping");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn forward() -> Result<()> {
+        let proxy_addr = spawn_proxy(HttpConnectHandler::default()).await?;
+        let target = TcpListener::bind("127.0.0.1:0").await?;
+
+        let head = format!(
+            "POST http://{}/index.html?q=1 HTTP/1.1\r\nProxy-Connection: keep-alive\r\nContent-Length: 4\r\n\r\nping",
+            target.local_addr()?
+        );
+        let mut stream = TcpStream::connect(proxy_addr).await?;
+        stream.write_all(head.as_bytes()).await?;
+
+        // The origin server receives the request in origin-form, without proxy headers, followed by the body.
+        let mut incoming = SocksStream::from(target.accept().await?.0);
+        let forwarded = http::read_request(&mut incoming).await?;
+        assert_eq!(forwarded.target, "/index.html?q=1");
+        assert_eq!(forwarded.header("Host"), Some(target.local_addr()?.to_string().as_str()));
+        assert_eq!(forwarded.header("Proxy-Connection"), None);
+        assert_eq!(forwarded.header("Connection"), Some("close"));
+
+        let mut body = [0; 4];
+        incoming.read_exact(&mut body).await?;
+        assert_eq!(&body, b"ping");
+
+        // Unreachable destinations are reported as a bad gateway.
+        let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let (_, status) = request(proxy_addr, format!("CONNECT {} HTTP/1.1\r\n\r\n", closed)).await?;
+        assert_eq!(status, "502");
+
+        Ok(())
+    }
+}
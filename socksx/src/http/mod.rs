@@ -0,0 +1,246 @@
+use crate::addresses::Address;
+use crate::error::{Cause, Error};
+use crate::{Credentials, SocksStream};
+use anyhow::Result;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use url::Url;
+
+mod http_handler;
+
+pub use http_handler::HttpConnectHandler;
+
+/// Upper bound on the size of the request line and headers.
+const MAX_HEAD_LENGTH: usize = 8192;
+
+/// The request line and headers of an HTTP request.
+#[derive(Clone, Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl HttpRequest {
+    /// The value of the first header with this (case-insensitive) name.
+    pub fn header(
+        &self,
+        name: &str,
+    ) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The credentials in the `Proxy-Authorization` header, if it uses the Basic scheme.
+    pub fn credentials(&self) -> Option<Credentials> {
+        let value = self.header("Proxy-Authorization")?;
+        let (scheme, encoded) = value.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("Basic") {
+            return None;
+        }
+
+        let decoded = BASE64.decode(encoded.trim()).ok()?;
+        let separator = decoded.iter().position(|b| *b == b':')?;
+
+        Some(Credentials::new(&decoded[..separator], &decoded[separator + 1..]))
+    }
+
+    /// The destination of the request: the authority of a CONNECT request, or the host of an
+    /// absolute URI (plain forward-proxy requests).
+    pub fn destination(&self) -> Result<Address> {
+        if self.method == "CONNECT" {
+            let (host, port) = self
+                .target
+                .rsplit_once(':')
+                .ok_or_else(|| Error::Protocol(format!("CONNECT target has no port: {}.", self.target)))?;
+            let port = port
+                .parse()
+                .map_err(|_| Error::Protocol(format!("CONNECT target has an invalid port: {}.", self.target)))?;
+
+            // IPv6 addresses are enclosed in brackets.
+            return Ok(Address::new(host.trim_start_matches('[').trim_end_matches(']'), port));
+        }
+
+        let url = self.absolute_uri()?;
+        let host = url.host_str().unwrap_or_default();
+        let port = url.port_or_known_default().unwrap_or(80);
+
+        Ok(Address::new(host.trim_start_matches('[').trim_end_matches(']'), port))
+    }
+
+    /// Rewrites a forward-proxy request for the origin server: the target becomes a path, the
+    /// proxy headers are dropped, and the connection is closed after the response.
+    pub fn into_origin_bytes(self) -> Result<Vec<u8>> {
+        let url = self.absolute_uri()?;
+        let mut target = String::from(url.path());
+        if let Some(query) = url.query() {
+            target.push('?');
+            target.push_str(query);
+        }
+
+        let mut head = format!("{} {} {}\r\n", self.method, target, self.version);
+        if self.header("Host").is_none() {
+            let host = url.host_str().unwrap_or_default();
+            match url.port() {
+                Some(port) => head.push_str(&format!("Host: {}:{}\r\n", host, port)),
+                None => head.push_str(&format!("Host: {}\r\n", host)),
+            }
+        }
+        for (name, value) in &self.headers {
+            let hop_by_hop = ["Proxy-Authorization", "Proxy-Connection", "Connection", "Keep-Alive"];
+            if !hop_by_hop.iter().any(|h| name.eq_ignore_ascii_case(h)) {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+        head.push_str("Connection: close\r\n\r\n");
+
+        Ok(head.into_bytes())
+    }
+
+    /// Parses the target of a forward-proxy request, only plain HTTP can be forwarded.
+    fn absolute_uri(&self) -> Result<Url> {
+        let url = Url::parse(&self.target)
+            .map_err(|_| Error::Protocol(format!("Request target isn't an absolute URI: {}.", self.target)))?;
+
+        ensure!(
+            url.scheme() == "http" && url.host_str().is_some(),
+            Error::CommandNotSupported(format!("Only http:// URIs can be forwarded: {}.", self.target))
+        );
+
+        Ok(url)
+    }
+}
+
+/// Reads the request line and headers of an HTTP request. The body, if any, is left on the stream.
+#[instrument(skip_all)]
+pub async fn read_request(stream: &mut SocksStream) -> Result<HttpRequest> {
+    let mut head = vec![];
+    let mut buffer = [0; 1024];
+    let end = loop {
+        ensure!(
+            head.len() < MAX_HEAD_LENGTH,
+            Error::Protocol(format!("Request head exceeds {} bytes.", MAX_HEAD_LENGTH))
+        );
+
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+
+        // The end of the head may straddle two reads.
+        let start = head.len().saturating_sub(3);
+        head.extend_from_slice(&buffer[..read]);
+        if let Some(position) = head[start..].windows(4).position(|w| w == b"\r\n\r\n") {
+            break start + position + 4;
+        }
+    };
+
+    // Whatever follows the head is left on the stream.
+    stream.unread(head.split_off(end));
+
+    let head = String::from_utf8_lossy(&head);
+    let mut lines = head.split("\r\n");
+
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) if version.starts_with("HTTP/1.") => {
+            (method, target, version)
+        }
+        _ => bail!(Error::Protocol(format!("Malformed request line: {}.", request_line))),
+    };
+
+    let mut headers = vec![];
+    for line in lines.filter(|l| !l.is_empty()) {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| Error::Protocol(format!("Malformed header: {}.", line)))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+
+    Ok(HttpRequest {
+        method: method.to_string(),
+        target: target.to_string(),
+        version: version.to_string(),
+        headers,
+    })
+}
+
+#[repr(u16)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HttpReply {
+    Ok = 200,
+    BadRequest = 400,
+    Forbidden = 403,
+    MethodNotAllowed = 405,
+    ProxyAuthenticationRequired = 407,
+    TooManyRequests = 429,
+    BadGateway = 502,
+    ServiceUnavailable = 503,
+    GatewayTimeout = 504,
+}
+
+impl HttpReply {
+    /// The reason phrase of the status code.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            HttpReply::Ok => "Connection established",
+            HttpReply::BadRequest => "Bad Request",
+            HttpReply::Forbidden => "Forbidden",
+            HttpReply::MethodNotAllowed => "Method Not Allowed",
+            HttpReply::ProxyAuthenticationRequired => "Proxy Authentication Required",
+            HttpReply::TooManyRequests => "Too Many Requests",
+            HttpReply::BadGateway => "Bad Gateway",
+            HttpReply::ServiceUnavailable => "Service Unavailable",
+            HttpReply::GatewayTimeout => "Gateway Timeout",
+        }
+    }
+}
+
+impl From<&anyhow::Error> for HttpReply {
+    /// Chooses the status code that tells the client why its request failed.
+    fn from(error: &anyhow::Error) -> Self {
+        match Error::cause(error) {
+            Cause::Socksx(Error::Protocol(_)) => HttpReply::BadRequest,
+            Cause::Socksx(Error::CommandNotSupported(_)) => HttpReply::MethodNotAllowed,
+            Cause::Socksx(Error::NotAllowed(_)) => HttpReply::Forbidden,
+            Cause::Socksx(Error::LimitExceeded(_)) => HttpReply::TooManyRequests,
+            Cause::Socksx(Error::Authentication(_)) => HttpReply::ProxyAuthenticationRequired,
+            Cause::Socksx(Error::Timeout(_)) => HttpReply::GatewayTimeout,
+            Cause::Socksx(Error::Connect { source, .. }) if source.kind() == io::ErrorKind::TimedOut => {
+                HttpReply::GatewayTimeout
+            }
+            Cause::Io(error) if error.kind() == io::ErrorKind::TimedOut => HttpReply::GatewayTimeout,
+            _ => HttpReply::BadGateway,
+        }
+    }
+}
+
+/// Writes a response without a body. Responses other than `200` close the connection.
+pub async fn write_reply<S>(
+    stream: &mut S,
+    reply: HttpReply,
+) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut head = format!("HTTP/1.1 {} {}\r\n", reply as u16, reply.reason());
+    match reply {
+        HttpReply::Ok => {}
+        HttpReply::ProxyAuthenticationRequired => {
+            head.push_str("Proxy-Authenticate: Basic realm=\"socksx\"\r\n");
+            head.push_str("Content-Length: 0\r\nConnection: close\r\n");
+        }
+        _ => head.push_str("Content-Length: 0\r\nConnection: close\r\n"),
+    }
+    head.push_str("\r\n");
+
+    stream.write_all(head.as_bytes()).await?;
+
+    Ok(())
+}
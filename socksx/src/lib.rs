@@ -1,27 +1,61 @@
 #[macro_use]
 extern crate anyhow;
 #[macro_use]
-extern crate log;
+extern crate tracing;
 #[macro_use]
 extern crate num_derive;
 
+#[path = "./common/access_log.rs"]
+pub mod access_log;
+#[path = "./common/acl.rs"]
+pub mod acl;
 #[path = "./common/addresses.rs"]
 pub mod addresses;
+#[path = "./common/auth.rs"]
+pub mod auth;
 #[path = "./common/constants.rs"]
 pub mod constants;
 #[path = "./common/credentials.rs"]
 pub mod credentials;
+#[path = "./common/error.rs"]
+pub mod error;
+pub mod http;
 #[path = "./common/interface.rs"]
 pub mod interface;
+#[path = "./common/limits.rs"]
+pub mod limits;
+#[path = "./common/metrics.rs"]
+pub mod metrics;
+#[path = "./common/multi.rs"]
+pub mod multi;
+pub mod socks4;
 pub mod socks5;
 pub mod socks6;
+#[path = "./common/stream.rs"]
+pub mod stream;
+#[path = "./common/timeouts.rs"]
+pub mod timeouts;
+#[path = "./common/tls.rs"]
+pub mod tls;
 #[path = "./common/util.rs"]
 pub mod util;
 
+pub use access_log::{AccessLog, LogFormat};
+pub use acl::AccessControl;
 pub use addresses::{Address, ProxyAddress};
+pub use auth::Authenticator;
 pub use credentials::Credentials;
+pub use error::Error;
+pub use http::HttpConnectHandler;
 pub use interface::SocksHandler;
+pub use limits::ConnectionLimits;
+pub use metrics::{Metrics, MetricsHook};
+pub use multi::MultiProtocolHandler;
+pub use socks4::{Socks4Client, Socks4Handler};
 pub use socks5::{Socks5Client, Socks5Handler};
 pub use socks6::{Socks6Client, Socks6Handler};
+pub use stream::SocksStream;
+pub use timeouts::Timeouts;
+pub use tls::{TlsAcceptor, TlsConnector};
 pub use tokio::io::copy_bidirectional;
-pub use util::{get_original_dst, resolve_addr, try_read_initial_data};
+pub use util::{connect_with_initial_data, get_original_dst, resolve_addr, try_read_initial_data};
@@ -1,45 +1,196 @@
 #[macro_use]
+extern crate anyhow;
+#[macro_use]
 extern crate human_panic;
 
-use anyhow::Result;
+mod config;
+mod server;
+
+use anyhow::{Context, Result};
 use clap::Parser;
+use config::{Config, ListenerConfig, Protocol, TlsConfig};
 use dotenv::dotenv;
-use itertools::Itertools;
-use log::LevelFilter;
-use socksx::{self, Socks5Handler, Socks6Handler, SocksHandler};
-use std::{convert::TryInto, sync::Arc};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Semaphore;
-use tokio::time::Instant;
-
-type Handler = Arc<dyn SocksHandler + Sync + Send>;
+use server::Server;
+use socksx::{AccessLog, LogFormat, Metrics, MetricsHook};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, Level};
 
 #[derive(Parser)]
 #[clap(version = env!("CARGO_PKG_VERSION"))]
 struct Args {
+    /// Writes a record to stdout for every connection that closes [default: disabled]
+    #[clap(long, env = "ACCESS_LOG", possible_values = &["json", "logfmt"])]
+    access_log: Option<LogFormat>,
+
     /// Entry in the proxy chain, the order is preserved
     #[clap(short, long, env = "CHAIN", multiple_occurrences = true)]
     chain: Vec<String>,
 
+    /// CA (PEM) that the `+tls` links of the chain must be signed by
+    #[clap(long, env = "CHAIN_CA")]
+    chain_ca: Option<PathBuf>,
+
+    /// Configuration file (TOML) declaring the listeners, other flags override its settings
+    #[clap(long, env = "CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Seconds connecting to a destination, or waiting for a BIND peer, may take [default: indefinitely]
+    #[clap(long, env = "CONNECT_TIMEOUT")]
+    connect_timeout: Option<u64>,
+
     /// Prints debug information
     #[clap(short, long, env = "DEBUG", takes_value = false)]
     debug: bool,
 
-    /// Host (IP) for the SOCKS server
-    #[clap(short, long, env = "HOST", default_value = "0.0.0.0")]
-    host: String,
+    /// Seconds established connections get to end after SIGTERM/SIGINT, before they're closed
+    #[clap(long, env = "DRAIN_TIMEOUT", default_value = "30")]
+    drain_timeout: u64,
+
+    /// Seconds a destination may send nothing, then the relay closes if the client is idle too [default: upstream]
+    #[clap(long, env = "DOWNSTREAM_IDLE_TIMEOUT")]
+    downstream_idle_timeout: Option<u64>,
+
+    /// Seconds a client has to send its request [default: indefinitely]
+    #[clap(long, env = "HANDSHAKE_TIMEOUT")]
+    handshake_timeout: Option<u64>,
+
+    /// Host (IP) for the SOCKS server [default: 0.0.0.0]
+    #[clap(short, long, env = "HOST")]
+    host: Option<String>,
+
+    /// Concurrent connections limit (0=unlimited) [default: 256]
+    #[clap(short, long, env = "LIMIT")]
+    limit: Option<usize>,
+
+    /// Concurrent connections limit per client IP (0=unlimited) [default: 0]
+    #[clap(long, env = "LIMIT_PER_CLIENT")]
+    limit_per_client: Option<usize>,
+
+    /// Concurrent connections limit per authenticated user (0=unlimited) [default: 0]
+    #[clap(long, env = "LIMIT_PER_USER")]
+    limit_per_user: Option<usize>,
+
+    /// Seconds a connection over a limit waits for a slot, before it's refused [default: refuse right away]
+    #[clap(long, env = "LIMIT_QUEUE_TIMEOUT")]
+    limit_queue_timeout: Option<u64>,
+
+    /// Address (host:port) to serve Prometheus metrics on, at /metrics [default: disabled]
+    #[clap(long, env = "METRICS")]
+    metrics: Option<String>,
+
+    /// Port for the SOCKS server [default: 1080]
+    #[clap(short, long, env = "PORT")]
+    port: Option<u16>,
+
+    /// SOCKS version, "http" for an HTTP proxy, or "auto" to detect the protocol per client [default: 6]
+    #[clap(short, long, env = "SOCKS", possible_values = &["4", "5", "6", "http", "auto"])]
+    socks: Option<String>,
+
+    /// Certificate chain (PEM) to terminate TLS with, along with --tls-key [default: plain TCP]
+    #[clap(long, env = "TLS_CERTIFICATE")]
+    tls_certificate: Option<PathBuf>,
+
+    /// CA (PEM) that client certificates must be signed by, their common name is the user
+    #[clap(long, env = "TLS_CLIENT_CA")]
+    tls_client_ca: Option<PathBuf>,
+
+    /// Private key (PEM) of the TLS certificate
+    #[clap(long, env = "TLS_KEY")]
+    tls_key: Option<PathBuf>,
+
+    /// Seconds a client may send nothing, then the relay closes if the destination is idle too [default: downstream]
+    #[clap(long, env = "UPSTREAM_IDLE_TIMEOUT")]
+    upstream_idle_timeout: Option<u64>,
+}
+
+impl Args {
+    /// Applies the flags that are set to the configuration of a listener.
+    fn apply(
+        &self,
+        listener: &mut ListenerConfig,
+    ) -> Result<()> {
+        if !self.chain.is_empty() {
+            listener.chain = self.chain.clone();
+        }
+        if let Some(ca) = &self.chain_ca {
+            listener.chain_ca = Some(ca.clone());
+        }
+        if let Some(host) = &self.host {
+            listener.host = host.clone();
+        }
+        if let Some(limit) = self.limit {
+            listener.limit = limit;
+        }
+        if let Some(limit) = self.limit_per_client {
+            listener.limit_per_client = limit;
+        }
+        if let Some(limit) = self.limit_per_user {
+            listener.limit_per_user = limit;
+        }
+        if let Some(timeout) = self.limit_queue_timeout {
+            listener.limit_queue_timeout = Some(timeout);
+        }
+        if let Some(port) = self.port {
+            listener.port = port;
+        }
+        if let Some(socks) = &self.socks {
+            listener.protocol = socks.parse::<Protocol>()?;
+        }
+        if let Some(timeout) = self.handshake_timeout {
+            listener.handshake_timeout = Some(timeout);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            listener.connect_timeout = Some(timeout);
+        }
+        if let Some(timeout) = self.upstream_idle_timeout {
+            listener.upstream_idle_timeout = Some(timeout);
+        }
+        if let Some(timeout) = self.downstream_idle_timeout {
+            listener.downstream_idle_timeout = Some(timeout);
+        }
+        match (&self.tls_certificate, &self.tls_key) {
+            (Some(certificate), Some(key)) => {
+                listener.tls = Some(TlsConfig {
+                    certificate: certificate.clone(),
+                    key: key.clone(),
+                    client_ca: None,
+                });
+            }
+            (None, None) => {}
+            _ => bail!("--tls-certificate and --tls-key must be set together."),
+        }
+        if let Some(client_ca) = &self.tls_client_ca {
+            let tls = listener.tls.as_mut().context("--tls-client-ca requires a TLS certificate and key.")?;
+            tls.client_ca = Some(client_ca.clone());
+        }
 
-    /// Concurrent connections limit (0=unlimted)
-    #[clap(short, long, env = "LIMIT", default_value = "256")]
-    limit: usize,
+        Ok(())
+    }
 
-    /// Port for the SOCKS server
-    #[clap(short, long, env = "PORT", default_value = "1080")]
-    port: u16,
+    /// Loads the listeners from the configuration file, if any, and applies the flags to them.
+    /// Without a configuration file, the flags describe a single listener. The address, protocol,
+    /// and chain of a listener can only be overridden if it's the only one.
+    fn listeners(&self) -> Result<Vec<ListenerConfig>> {
+        let mut listeners = match &self.config {
+            Some(path) => Config::load(path)?.listeners,
+            None => vec![ListenerConfig::default()],
+        };
+        ensure!(
+            listeners.len() == 1
+                || (self.host.is_none() && self.port.is_none() && self.socks.is_none() && self.chain.is_empty()),
+            "--host, --port, --socks, and --chain can't override a config file with multiple listeners."
+        );
+        for listener in listeners.iter_mut() {
+            self.apply(listener)?;
+        }
+        config::validate(&listeners)?;
 
-    /// SOCKS version
-    #[clap(short, long, env = "SOCKS", default_value = "6", possible_values = &["5", "6"])]
-    socks: u8,
+        Ok(listeners)
+    }
 }
 
 #[tokio::main]
@@ -47,14 +198,11 @@ async fn main() -> Result<()> {
     dotenv().ok();
     let args = Args::parse();
 
-    let mut logger = env_logger::builder();
-    logger.format_module_path(false);
-
-    if args.debug {
-        logger.filter_level(LevelFilter::Debug).init();
-    } else {
-        logger.filter_level(LevelFilter::Info).init();
+    // Events are printed along with the spans they occurred in, e.g., the connection.
+    let level = if args.debug { Level::DEBUG } else { Level::INFO };
+    tracing_subscriber::fmt().with_max_level(level).with_target(false).init();
 
+    if !args.debug {
         setup_panic!(Metadata {
             name: "SOCKSX".into(),
             version: env!("CARGO_PKG_VERSION").into(),
@@ -63,62 +211,46 @@ async fn main() -> Result<()> {
         });
     }
 
-    // TODO: validate host
-
-    //
-    //
-    let chain = args.chain.iter().cloned().map(|c| c.try_into()).try_collect()?;
-
-    //
-    //
-    let semaphore = if args.limit > 0 {
-        Some(Arc::new(Semaphore::new(args.limit)))
-    } else {
-        None
-    };
-
-    //
-    //
-    let listener = TcpListener::bind(format!("{}:{}", args.host, args.port)).await?;
-    let handler: Handler = match args.socks {
-        5 => Arc::new(Socks5Handler::new(chain)),
-        6 => Arc::new(Socks6Handler::new(chain)),
-        _ => unreachable!(),
-    };
-
-    loop {
-        let (incoming, _) = listener.accept().await?;
-
-        let handler = Arc::clone(&handler);
-        let semaphore = semaphore.clone();
+    let mut hooks: Vec<Arc<dyn MetricsHook>> = vec![];
+    if let Some(address) = &args.metrics {
+        let listener = TcpListener::bind(address)
+            .await
+            .with_context(|| format!("Failed to listen on {}", address))?;
+        let metrics = Arc::new(Metrics::new());
 
-        tokio::spawn(process(incoming, handler, semaphore));
+        info!("Serving metrics on {}", address);
+        tokio::spawn(server::serve_metrics(listener, metrics.clone()));
+        hooks.push(metrics);
+    }
+    if let Some(format) = args.access_log {
+        hooks.push(Arc::new(AccessLog::new(format)));
     }
-}
 
-///
-///
-///
-async fn process(
-    incoming: TcpStream,
-    handler: Handler,
-    semaphore: Option<Arc<Semaphore>>,
-) -> Result<()> {
-    let mut incoming = incoming;
-    let start_time = Instant::now();
-
-    if let Some(semaphore) = semaphore {
-        let permit = semaphore.try_acquire();
-        if permit.is_ok() {
-            handler.accept_request(&mut incoming).await?;
-        } else {
-            handler.refuse_request(&mut incoming).await?;
-        }
-    } else {
-        handler.accept_request(&mut incoming).await?;
+    let mut server = Server::new(hooks);
+    server.configure(args.listeners()?).await?;
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                // A configuration that can't be applied leaves the server as it was.
+                info!("Reloading the configuration");
+                let reloaded = match args.listeners() {
+                    Ok(listeners) => server.configure(listeners).await,
+                    Err(error) => Err(error),
+                };
+                if let Err(error) = reloaded {
+                    error!("Failed to reload the configuration: {:?}", error);
+                }
+            }
+            _ = terminate.recv() => break,
+            _ = tokio::signal::ctrl_c() => break,
+        }
     }
 
-    println!("{}ms", Instant::now().saturating_duration_since(start_time).as_millis());
+    info!("Shutting down");
+    server.shutdown(Duration::from_secs(args.drain_timeout)).await;
 
     Ok(())
 }
@@ -0,0 +1,310 @@
+use crate::config::{Handler, ListenerConfig};
+use anyhow::{Context, Result};
+use socksx::http::{self, HttpReply};
+use socksx::{ConnectionLimits, Metrics, MetricsHook, SocksStream, Timeouts, TlsAcceptor};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
+
+/// What a listener hands its clients over to, replaced when the configuration is reloaded.
+#[derive(Clone)]
+struct Service {
+    handler: Handler,
+    limits: ConnectionLimits,
+    timeouts: Timeouts,
+    tls: Option<TlsAcceptor>,
+}
+
+/// The listeners of the binary, and the connections they accepted.
+pub struct Server {
+    listeners: HashMap<String, Listener>,
+    connections: mpsc::Sender<()>,
+    drained: mpsc::Receiver<()>,
+    closing: watch::Sender<()>,
+    hooks: Vec<Arc<dyn MetricsHook>>,
+}
+
+struct Listener {
+    config: ListenerConfig,
+    service: watch::Sender<Service>,
+    accept: JoinHandle<()>,
+}
+
+impl Server {
+    /// Creates a server without listeners, its handlers report to each of `hooks`.
+    pub fn new(hooks: Vec<Arc<dyn MetricsHook>>) -> Self {
+        let (connections, drained) = mpsc::channel(1);
+        let (closing, _) = watch::channel(());
+
+        Server {
+            listeners: HashMap::new(),
+            connections,
+            drained,
+            closing,
+            hooks,
+        }
+    }
+
+    /// Starts, updates, and stops listeners, so that they match the configuration. Established
+    /// connections keep the handler they were accepted with. If the configuration is invalid,
+    /// or an address can't be listened on, nothing changes.
+    pub async fn configure(
+        &mut self,
+        configs: Vec<ListenerConfig>,
+    ) -> Result<()> {
+        let mut services = vec![];
+        let mut bound = HashMap::new();
+        for config in &configs {
+            let address = config.address();
+
+            // Connection counts carry over, unless the limits changed.
+            let limits = match self.listeners.get(&address) {
+                Some(listener) if same_limits(&listener.config, config) => listener.service.borrow().limits.clone(),
+                _ => config.limits(),
+            };
+            let handler = config
+                .handler(&limits, &self.hooks)
+                .with_context(|| format!("Invalid configuration for listener {}", address))?;
+            let tls = config
+                .tls_acceptor()
+                .with_context(|| format!("Invalid TLS configuration for listener {}", address))?;
+            services.push(Service {
+                handler,
+                limits,
+                timeouts: config.timeouts(),
+                tls,
+            });
+
+            if !self.listeners.contains_key(&address) {
+                let tcp_listener = TcpListener::bind(&address)
+                    .await
+                    .with_context(|| format!("Failed to listen on {}", address))?;
+                bound.insert(address, tcp_listener);
+            }
+        }
+
+        let addresses: Vec<String> = configs.iter().map(ListenerConfig::address).collect();
+        self.listeners.retain(|address, listener| {
+            let keep = addresses.contains(address);
+            if !keep {
+                info!("Stopped listening on {}", address);
+                listener.accept.abort();
+            }
+
+            keep
+        });
+
+        for (config, service) in configs.into_iter().zip(services) {
+            let address = config.address();
+            if let Some(listener) = self.listeners.get_mut(&address) {
+                info!("Reconfigured {} ({:?})", address, config.protocol);
+                listener.service.send_replace(service);
+                listener.config = config;
+                continue;
+            }
+
+            let tcp_listener = bound.remove(&address).expect("bound above");
+            let (service, receiver) = watch::channel(service);
+            let (connections, closing) = (self.connections.clone(), self.closing.subscribe());
+            let accept = tokio::spawn(serve(tcp_listener, receiver, connections, closing));
+
+            info!("Listening on {} ({:?})", address, config.protocol);
+            self.listeners.insert(address, Listener { config, service, accept });
+        }
+
+        Ok(())
+    }
+
+    /// Stops accepting clients, and waits for established connections to end. Connections that
+    /// are still open after `deadline` are closed. Dropping the server closes them too.
+    pub async fn shutdown(
+        mut self,
+        deadline: Duration,
+    ) {
+        for (address, listener) in self.listeners.drain() {
+            info!("Stopped listening on {}", address);
+            listener.accept.abort();
+        }
+
+        // Every connection holds a sender, the receiver yields nothing once all are dropped.
+        drop(self.connections);
+        info!("Draining connections, for at most {:?}", deadline);
+        match tokio::time::timeout(deadline, self.drained.recv()).await {
+            Ok(_) => info!("All connections ended"),
+            Err(_) => {
+                warn!("Closing the connections that are still open");
+                self.closing.send_replace(());
+                self.drained.recv().await;
+            }
+        }
+    }
+}
+
+/// Whether two listener configurations have the same connection limits.
+fn same_limits(
+    a: &ListenerConfig,
+    b: &ListenerConfig,
+) -> bool {
+    (a.limit, a.limit_per_client, a.limit_per_user, a.limit_queue_timeout)
+        == (b.limit, b.limit_per_client, b.limit_per_user, b.limit_queue_timeout)
+}
+
+/// Accepts clients, and hands each over to the current handler, until the task is aborted.
+/// Each connection holds a sender of `connections`, and ends when the server is closing.
+async fn serve(
+    listener: TcpListener,
+    service: watch::Receiver<Service>,
+    connections: mpsc::Sender<()>,
+    closing: watch::Receiver<()>,
+) {
+    loop {
+        let incoming = match listener.accept().await {
+            Ok((incoming, _)) => incoming,
+            Err(error) => {
+                // E.g., too many open files, this is temporary.
+                error!("Failed to accept a client: {}", error);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let service = service.borrow().clone();
+        let connection = connections.clone();
+        let mut closing = closing.clone();
+
+        tokio::spawn(async move {
+            let _connection = connection;
+            tokio::select! {
+                result = process(incoming, service) => result,
+                _ = closing.changed() => Ok(()),
+            }
+        });
+    }
+}
+
+/// Serves the metrics at `/metrics`, in the Prometheus text format, until the task is aborted.
+pub async fn serve_metrics(
+    listener: TcpListener,
+    metrics: Arc<Metrics>,
+) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(error) => {
+                error!("Failed to accept a metrics client: {}", error);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            let mut stream = SocksStream::from(stream);
+            let request = match http::read_request(&mut stream).await {
+                Ok(request) => request,
+                Err(error) => return http::write_reply(&mut stream, HttpReply::from(&error)).await,
+            };
+            if request.method != "GET" {
+                return http::write_reply(&mut stream, HttpReply::MethodNotAllowed).await;
+            }
+
+            let (status, body) = match request.target.as_str() {
+                "/metrics" => ("200 OK", metrics.render()),
+                _ => ("404 Not Found", String::new()),
+            };
+            let mut head = format!("HTTP/1.1 {}\r\n", status);
+            head.push_str("Content-Type: text/plain; version=0.0.4\r\n");
+            head.push_str(&format!("Content-Length: {}\r\nConnection: close\r\n\r\n", body.len()));
+            stream.write_all(head.as_bytes()).await?;
+            stream.write_all(body.as_bytes()).await?;
+
+            Ok(())
+        });
+    }
+}
+
+/// Takes the slots of the client under the connection limits, performs the TLS handshake, if
+/// the listener terminates TLS, and hands the client over to the handler, or lets the handler
+/// refuse it if a limit is reached. Clients over the limits can't make the proxy do handshakes:
+/// TLS clients are disconnected without a reply.
+async fn process(
+    incoming: TcpStream,
+    service: Service,
+) -> Result<()> {
+    let Service { handler, limits, timeouts, tls } = service;
+
+    // The permit holds the slots of the global and per-client limits, until the relay ends.
+    let permit = limits.acquire_client(incoming.peer_addr()?.ip()).await;
+    let mut incoming: SocksStream = match (tls, &permit) {
+        (Some(acceptor), Ok(_)) => timeouts.handshake(acceptor.accept(incoming)).await?,
+        (Some(_), Err(error)) => {
+            debug!("Disconnected a TLS client without a reply: {}", error);
+            return Ok(());
+        }
+        (None, _) => incoming.into(),
+    };
+
+    match permit {
+        Ok(_permit) => handler.accept_request(&mut incoming).await?,
+        Err(_) => handler.refuse_request(&mut incoming).await?,
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Protocol;
+    use socksx::{Socks4Client, Socks5Client};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    pub async fn reload() -> Result<()> {
+        let target = TcpListener::bind("127.0.0.1:0").await?;
+        let destination = target.local_addr()?.to_string();
+
+        let port = TcpListener::bind("127.0.0.1:0").await?.local_addr()?.port();
+        let mut config = ListenerConfig {
+            host: String::from("127.0.0.1"),
+            port,
+            protocol: Protocol::Socks5,
+            ..Default::default()
+        };
+
+        let metrics = Arc::new(Metrics::new());
+        let mut server = Server::new(vec![metrics.clone()]);
+        server.configure(vec![config.clone()]).await?;
+
+        let proxy_addr = config.address();
+        let client = Socks5Client::new(proxy_addr.clone(), None).await?;
+        let (mut established, _) = client.connect(destination.clone()).await?;
+        let (mut accepted, _) = target.accept().await?;
+
+        // New clients get the new handler, established connections are kept.
+        config.protocol = Protocol::Socks4;
+        server.configure(vec![config]).await?;
+
+        let client = Socks4Client::new(proxy_addr, None).await?;
+        client.connect(destination).await?;
+
+        established.write_all(b"ping").await?;
+        let mut buffer = [0; 4];
+        accepted.read_exact(&mut buffer).await?;
+        assert_eq!(&buffer, b"ping");
+
+        let text = metrics.render();
+        assert!(text.contains("socksx_handshakes_total{protocol=\"socks4\",outcome=\"success\"} 1"));
+
+        // Connections that don't end before the deadline are closed.
+        server.shutdown(Duration::from_millis(50)).await;
+        assert_eq!(established.read(&mut buffer).await?, 0);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,227 @@
+use crate::addresses::Address;
+use crate::constants::*;
+use crate::error::{Cause, Error};
+use anyhow::Result;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+mod s4_client;
+mod s4_handler;
+
+pub use s4_client::Socks4Client;
+pub use s4_handler::Socks4Handler;
+
+/// Upper bound on the length of the USERID and (SOCKS4a) domain name fields.
+const MAX_FIELD_LENGTH: usize = 255;
+
+#[repr(u8)]
+#[derive(Clone, Debug, PartialEq)]
+pub enum Socks4Command {
+    Connect = 0x01,
+    Bind = 0x02,
+}
+
+impl Socks4Command {
+    pub fn from_u8(command: u8) -> Option<Self> {
+        match command {
+            0x01 => Some(Socks4Command::Connect),
+            0x02 => Some(Socks4Command::Bind),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Socks4Request {
+    pub command: Socks4Command,
+    pub destination: Address,
+    pub user_id: String,
+}
+
+impl Socks4Request {
+    /// Creates a request. Domain names are sent as SOCKS4a, i.e., resolved by the proxy.
+    pub fn new(
+        command: u8,
+        destination: Address,
+        user_id: String,
+    ) -> Self {
+        Socks4Request {
+            command: Socks4Command::from_u8(command).unwrap(),
+            destination,
+            user_id,
+        }
+    }
+
+    /// Encodes the request. Fails for IPv6 destinations, SOCKS4 can't express those.
+    pub fn into_socks_bytes(self) -> Result<Vec<u8>> {
+        let mut data = vec![SOCKS_VER_4, self.command as u8];
+        data.extend(self.destination.port().to_be_bytes().iter());
+
+        // SOCKS4a marks a domain name with an invalid IP address: 0.0.0.x, with x non-zero.
+        let domain = match &self.destination {
+            Address::Ip(SocketAddr::V4(addr)) => {
+                data.extend(addr.ip().octets().iter());
+                None
+            }
+            Address::Ip(SocketAddr::V6(addr)) => bail!("SOCKS4 doesn't support IPv6 destinations: {}.", addr),
+            Address::Domainname { host, .. } => {
+                data.extend([0, 0, 0, 1].iter());
+                Some(host)
+            }
+        };
+
+        data.extend(self.user_id.as_bytes());
+        data.push(0);
+
+        if let Some(domain) = domain {
+            data.extend(domain.as_bytes());
+            data.push(0);
+        }
+
+        Ok(data)
+    }
+}
+
+/// Reads a SOCKS4 or SOCKS4a request from a client.
+///
+/// [socks4] https://www.openssh.com/txt/socks4.protocol
+/// [socks4a] https://www.openssh.com/txt/socks4a.protocol
+#[instrument(skip_all)]
+pub async fn read_request<S>(stream: &mut S) -> Result<Socks4Request>
+where
+    S: AsyncRead + Unpin,
+{
+    // Read SOCKS version, command type, destination port, and destination IP.
+    let mut request = [0; 8];
+    stream.read_exact(&mut request).await?;
+
+    let version = request[0];
+    let command = request[1];
+    let port = u16::from_be_bytes([request[2], request[3]]);
+    let ip = Ipv4Addr::new(request[4], request[5], request[6], request[7]);
+
+    // Validate the request.
+    ensure!(
+        version == SOCKS_VER_4,
+        Error::Protocol(format!("Client uses a different SOCKS version: {}.", version))
+    );
+    ensure!(
+        Socks4Command::from_u8(command).is_some(),
+        Error::CommandNotSupported(format!("Client requested an unsupported command: {}.", command))
+    );
+
+    let user_id = read_field(stream).await?;
+
+    // An IP address of 0.0.0.x, with x non-zero, means a domain name follows.
+    let octets = ip.octets();
+    let destination = if octets[..3] == [0, 0, 0] && octets[3] != 0 {
+        let host = read_field(stream).await?;
+        ensure!(!host.is_empty(), Error::Protocol(String::from("Client sent an empty domain name.")));
+
+        Address::Domainname { host, port }
+    } else {
+        Address::Ip(SocketAddr::new(IpAddr::V4(ip), port))
+    };
+
+    Ok(Socks4Request::new(command, destination, user_id))
+}
+
+/// Reads a NULL-terminated field.
+async fn read_field<S>(stream: &mut S) -> Result<String>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut field = vec![];
+    loop {
+        let byte = stream.read_u8().await?;
+        if byte == 0 {
+            break;
+        }
+
+        ensure!(
+            field.len() < MAX_FIELD_LENGTH,
+            Error::Protocol(format!("Field exceeds {} bytes.", MAX_FIELD_LENGTH))
+        );
+        field.push(byte);
+    }
+
+    Ok(String::from_utf8_lossy(&field).to_string())
+}
+
+#[repr(u8)]
+#[derive(Clone, Debug, PartialEq)]
+pub enum Socks4Reply {
+    Granted = 0x5A,
+    Rejected = 0x5B,
+    IdentdUnreachable = 0x5C,
+    IdentdMismatch = 0x5D,
+}
+
+impl Socks4Reply {
+    pub fn from_u8(reply: u8) -> Option<Self> {
+        match reply {
+            0x5A => Some(Socks4Reply::Granted),
+            0x5B => Some(Socks4Reply::Rejected),
+            0x5C => Some(Socks4Reply::IdentdUnreachable),
+            0x5D => Some(Socks4Reply::IdentdMismatch),
+            _ => None,
+        }
+    }
+}
+
+impl From<&anyhow::Error> for Socks4Reply {
+    /// Chooses the reply that tells the client its request failed. SOCKS4 doesn't say why.
+    fn from(error: &anyhow::Error) -> Self {
+        match Error::cause(error) {
+            Cause::Socksx(Error::Refused(code)) => Socks4Reply::from_u8(*code).unwrap_or(Socks4Reply::Rejected),
+            _ => Socks4Reply::Rejected,
+        }
+    }
+}
+
+/// Writes a reply. SOCKS4 replies carry an IPv4 address only, other bindings are sent as
+/// `0.0.0.0`, which tells the client to use the address of the proxy instead.
+pub async fn write_reply<S>(
+    stream: &mut S,
+    reply: Socks4Reply,
+    binding: &Address,
+) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let ip = match binding {
+        Address::Ip(SocketAddr::V4(addr)) => *addr.ip(),
+        _ => Ipv4Addr::UNSPECIFIED,
+    };
+
+    let mut data = vec![SOCKS_VER_4_REPLY, reply as u8];
+    data.extend(binding.port().to_be_bytes().iter());
+    data.extend(ip.octets().iter());
+
+    stream.write_all(&data).await?;
+
+    Ok(())
+}
+
+/// Reads a reply, and returns the address it carries.
+pub async fn read_reply<S>(stream: &mut S) -> Result<Address>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut reply = [0; 8];
+    stream.read_exact(&mut reply).await?;
+
+    let version = reply[0];
+    ensure!(
+        version == SOCKS_VER_4_REPLY,
+        Error::Protocol(format!("Proxy uses a different reply version: {}.", version))
+    );
+
+    let reply_code = reply[1];
+    ensure!(reply_code == Socks4Reply::Granted as u8, Error::Refused(reply_code));
+
+    let port = u16::from_be_bytes([reply[2], reply[3]]);
+    let ip = Ipv4Addr::new(reply[4], reply[5], reply[6], reply[7]);
+
+    Ok(Address::Ip(SocketAddr::new(IpAddr::V4(ip), port)))
+}
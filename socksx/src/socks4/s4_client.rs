@@ -0,0 +1,117 @@
+use crate::socks4::{self, Socks4Request};
+use crate::{constants::*, Address, Error, SocksStream};
+use anyhow::Result;
+use futures::future::{BoxFuture, FutureExt};
+use std::convert::TryInto;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+#[derive(Clone)]
+pub struct Socks4Client {
+    proxy_addr: SocketAddr,
+    user_id: Option<String>,
+}
+
+impl Socks4Client {
+    /// Creates a client for the proxy at `proxy_addr`, which sends `user_id` in the USERID field.
+    pub async fn new<A: Into<String>>(
+        proxy_addr: A,
+        user_id: Option<String>,
+    ) -> Result<Self> {
+        let proxy_addr = crate::resolve_addr(proxy_addr).await?;
+
+        Ok(Socks4Client { proxy_addr, user_id })
+    }
+
+    /// Opens a TCP connection to the proxy.
+    async fn connect_to_proxy(&self) -> Result<SocksStream> {
+        let stream = TcpStream::connect(&self.proxy_addr)
+            .await
+            .map_err(|e| Error::connect(self.proxy_addr, e))?;
+
+        Ok(stream.into())
+    }
+
+    /// Connects to the destination through the proxy. Domain names are resolved by the proxy
+    /// (SOCKS4a). Returns the stream, and the address the proxy connected from.
+    ///
+    /// [socks4a] https://www.openssh.com/txt/socks4a.protocol
+    pub async fn connect<A>(
+        &self,
+        destination: A,
+    ) -> Result<(SocksStream, Address)>
+    where
+        A: TryInto<Address, Error = anyhow::Error>,
+    {
+        let mut stream = self.connect_to_proxy().await?;
+        let binding = self.handshake(destination, &mut stream).await?;
+
+        Ok((stream, binding))
+    }
+
+    /// Performs a CONNECT handshake on a stream that is already connected to the proxy.
+    /// Returns the address the proxy connected from.
+    pub async fn handshake<A, S>(
+        &self,
+        destination: A,
+        stream: &mut S,
+    ) -> Result<Address>
+    where
+        A: TryInto<Address, Error = anyhow::Error>,
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.request(SOCKS_CMD_CONNECT, destination.try_into()?, stream).await
+    }
+
+    /// Asks the proxy to listen for a single inbound connection from `destination`. Returns
+    /// the address the proxy listens on, and a future that resolves once the peer connected.
+    ///
+    /// [socks4] https://www.openssh.com/txt/socks4.protocol
+    pub async fn bind<A>(
+        &self,
+        destination: A,
+    ) -> Result<(Address, BoxFuture<'static, Result<(SocksStream, Address)>>)>
+    where
+        A: TryInto<Address, Error = anyhow::Error>,
+    {
+        let mut stream = self.connect_to_proxy().await?;
+        let binding = self.request(SOCKS_CMD_BIND, destination.try_into()?, &mut stream).await?;
+
+        // An unspecified address means the proxy listens on its own address.
+        let binding = match binding {
+            Address::Ip(addr) if addr.ip().is_unspecified() => {
+                Address::Ip(SocketAddr::new(self.proxy_addr.ip(), addr.port()))
+            }
+            binding => binding,
+        };
+
+        // The second reply arrives when the peer connects to the proxy.
+        let accepted = async move {
+            let peer = socks4::read_reply(&mut stream).await?;
+            Ok((stream, peer))
+        };
+
+        Ok((binding, accepted.boxed()))
+    }
+
+    /// Sends a request, and reads the reply.
+    async fn request<S>(
+        &self,
+        command: u8,
+        destination: Address,
+        stream: &mut S,
+    ) -> Result<Address>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let user_id = self.user_id.clone().unwrap_or_default();
+        ensure!(!user_id.contains('\0'), "USERID MUST NOT contain NULL bytes.");
+        ensure!(user_id.len() <= 255, "USERID MUST NOT be larger than 255 bytes.");
+
+        let request = Socks4Request::new(command, destination, user_id);
+        stream.write_all(&request.into_socks_bytes()?).await?;
+
+        socks4::read_reply(stream).await
+    }
+}
@@ -0,0 +1,430 @@
+use crate::acl::{AccessControl, AccessRequest, Command};
+use crate::addresses::ProxyAddress;
+use crate::limits::{ConnectionLimits, ConnectionPermit};
+use crate::metrics::{Hook, MetricsHook, Protocol};
+use crate::socks4::{self, Socks4Command, Socks4Reply, Socks4Request};
+use crate::socks6::SocksChain;
+use crate::timeouts::Timeouts;
+use crate::{Address, Error, SocksHandler, SocksStream, TlsConnector};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+
+#[derive(Clone)]
+pub struct Socks4Handler {
+    access_control: Option<Arc<AccessControl>>,
+    user_ids: Option<Arc<HashSet<String>>>,
+    chain: Vec<ProxyAddress>,
+    chain_tls: Option<TlsConnector>,
+    limits: Option<ConnectionLimits>,
+    metrics: Hook,
+    timeouts: Timeouts,
+}
+
+impl Default for Socks4Handler {
+    fn default() -> Self {
+        Self::new(vec![])
+    }
+}
+
+impl Socks4Handler {
+    /// Creates a handler that connects through `chain`, if not empty.
+    pub fn new(chain: Vec<ProxyAddress>) -> Self {
+        Socks4Handler {
+            access_control: None,
+            user_ids: None,
+            chain,
+            chain_tls: None,
+            limits: None,
+            metrics: Hook::new(Protocol::Socks4),
+            timeouts: Timeouts::default(),
+        }
+    }
+
+    /// Only accepts requests with one of these values in the USERID field. SOCKS4 has no
+    /// passwords, so this identifies clients rather than authenticating them. Without this, the
+    /// USERID is ignored: the client chooses it, so it can't stand for the user of a connection.
+    pub fn with_user_ids<I, S>(
+        mut self,
+        user_ids: I,
+    ) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.user_ids = Some(Arc::new(user_ids.into_iter().map(Into::into).collect()));
+        self
+    }
+
+    /// Consults `access_control` before acting on a request, denied requests are refused.
+    pub fn with_access_control(
+        mut self,
+        access_control: AccessControl,
+    ) -> Self {
+        self.access_control = Some(Arc::new(access_control));
+        self
+    }
+
+    /// Holds a slot of the per-user limit of `limits` while relaying for an identified client, see
+    /// `with_user_ids`.
+    pub fn with_limits(
+        mut self,
+        limits: ConnectionLimits,
+    ) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    /// Reports the events of this handler, e.g., handshakes and bytes relayed, to `hook`. Call
+    /// this again to report to several hooks, e.g., metrics and an access log.
+    pub fn with_metrics<H: MetricsHook + 'static>(
+        mut self,
+        hook: H,
+    ) -> Self {
+        self.metrics = self.metrics.with_hook(Arc::new(hook));
+        self
+    }
+
+    /// Limits the time spent on the handshake, on connecting to the destination, and on idle relays.
+    pub fn with_timeouts(
+        mut self,
+        timeouts: Timeouts,
+    ) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Starts TLS with the `+tls` links of the chain, and verifies them with `connector`.
+    pub fn with_chain_tls(
+        mut self,
+        connector: TlsConnector,
+    ) -> Self {
+        self.chain_tls = Some(connector);
+        self
+    }
+}
+
+#[async_trait]
+impl SocksHandler for Socks4Handler {
+    /// Sets up the connection the client asked for, and relays between both until either closes.
+    async fn accept_request(
+        &self,
+        source: &mut SocksStream,
+    ) -> Result<()> {
+        let client = source.peer_addr()?;
+        self.metrics.scope(client, self.serve(source)).await
+    }
+
+    /// Rejects the request of the client, e.g., because the proxy is at capacity.
+    async fn refuse_request(
+        &self,
+        source: &mut SocksStream,
+    ) -> Result<()> {
+        // Notify source that the connection is refused.
+        let client = source.peer_addr()?;
+        let binding = Address::unspecified();
+        let reply = self.reply(source, Socks4Reply::Rejected, &binding);
+        self.metrics.scope(client, reply).await
+    }
+
+    /// Reads the request of the client, and sets up the connection it asked for.
+    async fn setup(
+        &self,
+        source: &mut SocksStream,
+    ) -> Result<SocksStream> {
+        let handshake = self.timeouts.handshake(self.handshake(source)).await;
+        let request = self.metrics.handshake(handshake)?;
+        self.execute(source, request).await
+    }
+}
+
+impl Socks4Handler {
+    /// Handles the client, from its request until the relay ends.
+    async fn serve(
+        &self,
+        source: &mut SocksStream,
+    ) -> Result<()> {
+        let handshake = self.timeouts.handshake(self.handshake(source)).await;
+        let request = self.metrics.handshake(handshake)?;
+        let command = format!("{:?}", request.command);
+        let identity = self.identity(source, &request).map(String::from);
+        self.metrics.request(&command, &request.destination, identity.as_deref());
+
+        let _permit = self.acquire(source, identity.as_deref()).await?;
+        let mut destination = self.execute(source, request).await?;
+
+        // Start bidirectional copy, after this the connection closes.
+        self.timeouts.relay(source, &mut destination, |up, down| self.metrics.relayed(up, down)).await?;
+
+        Ok(())
+    }
+
+    /// Reads the request of the client, and checks its USERID if required.
+    async fn handshake(
+        &self,
+        source: &mut SocksStream,
+    ) -> Result<Socks4Request> {
+        let request = match socks4::read_request(source).await {
+            Ok(request) => request,
+            Err(error) => {
+                if let Some(Error::CommandNotSupported(_)) = error.downcast_ref() {
+                    self.reply(source, Socks4Reply::Rejected, &Address::unspecified()).await?;
+                }
+                return Err(error);
+            }
+        };
+
+        if let Some(user_ids) = &self.user_ids {
+            if !user_ids.contains(&request.user_id) {
+                self.reply(source, Socks4Reply::Rejected, &Address::unspecified()).await?;
+                bail!(Error::Authentication(format!("Unknown USERID: {}.", request.user_id)));
+            }
+        }
+
+        // Consult the access control rules, before acting on the request.
+        if let Some(access_control) = &self.access_control {
+            let command = match request.command {
+                Socks4Command::Connect => Command::Connect,
+                Socks4Command::Bind => Command::Bind,
+            };
+            let access_request = AccessRequest {
+                source: source.peer_addr()?.ip(),
+                user: self.identity(source, &request),
+                destination: Some(&request.destination),
+                command,
+            };
+            if let Err(error) = access_control.authorize(&access_request).await {
+                self.reply(source, Socks4Reply::from(&error), &Address::unspecified()).await?;
+                return Err(error);
+            }
+        }
+
+        Ok(request)
+    }
+
+    /// Takes a slot of the per-user limit for the identity of the client, if it has one. The
+    /// slot is released when the returned permit is dropped.
+    async fn acquire(
+        &self,
+        source: &mut SocksStream,
+        identity: Option<&str>,
+    ) -> Result<ConnectionPermit> {
+        let (limits, user) = match (&self.limits, identity) {
+            (Some(limits), Some(user)) => (limits, user),
+            _ => return Ok(ConnectionPermit::default()),
+        };
+
+        match limits.acquire_user(user).await {
+            Ok(permit) => Ok(permit),
+            Err(error) => {
+                self.reply(source, Socks4Reply::from(&error), &Address::unspecified()).await?;
+                Err(error)
+            }
+        }
+    }
+
+    /// Sets up the connection the client asked for, and returns the stream to relay to.
+    async fn execute(
+        &self,
+        source: &mut SocksStream,
+        request: Socks4Request,
+    ) -> Result<SocksStream> {
+        debug!(
+            "{:?} to {} for {}",
+            request.command,
+            request.destination.to_string(),
+            self.identity(source, &request).unwrap_or("anonymous")
+        );
+
+        match request.command {
+            Socks4Command::Connect => self.connect(source, request.destination).await,
+            Socks4Command::Bind => self.bind(source, request.destination).await,
+        }
+    }
+
+    /// Connects to the destination on behalf of the client, through the chain if one is configured.
+    /// Domain names (SOCKS4a) are resolved here, or by the last link of the chain.
+    async fn connect(
+        &self,
+        source: &mut SocksStream,
+        destination: Address,
+    ) -> Result<SocksStream> {
+        let mut chain = SocksChain::default();
+        if !self.chain.is_empty() {
+            chain.detour(&self.chain);
+        }
+
+        let (links, start) = (chain.remaining().to_vec(), Instant::now());
+        let destination = match self.timeouts.connect(chain.connect(destination, &[], self.chain_tls.as_ref())).await {
+            Ok(destination) => destination,
+            Err(error) => {
+                // Tell the client, before closing the connection.
+                self.reply(source, Socks4Reply::from(&error), &Address::unspecified()).await?;
+                return Err(error);
+            }
+        };
+        self.metrics.connected(start.elapsed(), &links, &destination);
+
+        // Notify source that the connection has been set up, and from which address.
+        let binding = Address::Ip(destination.local_addr()?);
+        self.reply(source, Socks4Reply::Granted, &binding).await?;
+        source.flush().await?;
+
+        Ok(destination)
+    }
+
+    /// Accepts a single inbound connection on behalf of the client. The first reply
+    /// tells the client where the proxy listens, the second reply who connected.
+    ///
+    /// [socks4] https://www.openssh.com/txt/socks4.protocol
+    async fn bind(
+        &self,
+        source: &mut SocksStream,
+        destination: Address,
+    ) -> Result<SocksStream> {
+        // Listen on the interface the client reached us on, if SOCKS4 can express it.
+        let ip = match source.local_addr()? {
+            SocketAddr::V4(addr) => IpAddr::V4(*addr.ip()),
+            SocketAddr::V6(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        };
+        let listener = TcpListener::bind(SocketAddr::new(ip, 0)).await?;
+        let binding = Address::Ip(listener.local_addr()?);
+
+        self.reply(source, Socks4Reply::Granted, &binding).await?;
+        source.flush().await?;
+
+        let accepted = self.timeouts.connect(async { Ok(listener.accept().await?) }).await;
+        let (incoming, peer_addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                // Tell the client, before closing the connection.
+                self.reply(source, Socks4Reply::from(&error), &Address::unspecified()).await?;
+                return Err(error);
+            }
+        };
+
+        // The client tells us who it expects to connect back, reject anyone else.
+        if let Address::Ip(expected) = destination {
+            if !expected.ip().is_unspecified() && expected.ip() != peer_addr.ip() {
+                self.reply(source, Socks4Reply::Rejected, &Address::Ip(peer_addr)).await?;
+                bail!("Unexpected peer connected to BIND listener: {}.", peer_addr);
+            }
+        }
+
+        // Notify source that the inbound connection has been established.
+        self.reply(source, Socks4Reply::Granted, &Address::Ip(peer_addr)).await?;
+        source.flush().await?;
+
+        Ok(incoming.into())
+    }
+
+    /// The user of the connection: the identity of a TLS client certificate, or else the USERID
+    /// of the request if it's checked by `with_user_ids`.
+    fn identity<'a>(
+        &self,
+        source: &'a SocksStream,
+        request: &'a Socks4Request,
+    ) -> Option<&'a str> {
+        source.identity().or_else(|| user(request).filter(|_| self.user_ids.is_some()))
+    }
+
+    /// Sends a reply to the client, and reports it.
+    async fn reply(
+        &self,
+        source: &mut SocksStream,
+        reply: Socks4Reply,
+        binding: &Address,
+    ) -> Result<()> {
+        self.metrics.reply(reply.clone() as u16);
+        socks4::write_reply(source, reply, binding).await
+    }
+}
+
+/// The USERID of the request, if the client sent one.
+fn user(request: &Socks4Request) -> Option<&str> {
+    Some(request.user_id.as_str()).filter(|u| !u.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl::{Action, Rule};
+    use crate::util::spawn_proxy;
+    use crate::Socks4Client;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    pub async fn connect() -> Result<()> {
+        let proxy_addr = spawn_proxy(Socks4Handler::default().with_user_ids(vec!["alice"])).await?;
+        let target = TcpListener::bind(crate::resolve_addr("localhost:0").await?).await?;
+        let port = target.local_addr()?.port();
+
+        let client = Socks4Client::new(proxy_addr.to_string(), Some(String::from("mallory"))).await?;
+        assert!(client.connect(format!("localhost:{}", port)).await.is_err());
+
+        // SOCKS4a: the proxy resolves the domain name.
+        let client = Socks4Client::new(proxy_addr.to_string(), Some(String::from("alice"))).await?;
+        let (mut outgoing, binding) = client.connect(format!("localhost:{}", port)).await?;
+
+        let (mut incoming, peer_addr) = target.accept().await?;
+        assert_eq!(binding.port(), peer_addr.port());
+
+        outgoing.write_all(b"ping").await?;
+        let mut buffer = [0; 4];
+        incoming.read_exact(&mut buffer).await?;
+        assert_eq!(&buffer, b"ping");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn user_ids() -> Result<()> {
+        let target = TcpListener::bind("127.0.0.1:0").await?;
+        let destination = target.local_addr()?.to_string();
+        let access_control = AccessControl::new(vec![Rule::allow().with_user("admin")]).with_default(Action::Deny);
+
+        // Any client can send any USERID, so it's not the user unless it's checked.
+        let proxy_addr = spawn_proxy(Socks4Handler::default().with_access_control(access_control.clone())).await?;
+        let client = Socks4Client::new(proxy_addr.to_string(), Some(String::from("admin"))).await?;
+        assert!(client.connect(destination.clone()).await.is_err());
+
+        let handler = Socks4Handler::default().with_user_ids(vec!["admin"]).with_access_control(access_control);
+        let proxy_addr = spawn_proxy(handler).await?;
+        let client = Socks4Client::new(proxy_addr.to_string(), Some(String::from("admin"))).await?;
+        assert!(client.connect(destination.clone()).await.is_ok());
+
+        // Nor can it use up the slots of that user.
+        let limits = ConnectionLimits::new(0).with_per_user(1);
+        let proxy_addr = spawn_proxy(Socks4Handler::default().with_limits(limits)).await?;
+        let client = Socks4Client::new(proxy_addr.to_string(), Some(String::from("admin"))).await?;
+        let _first = client.connect(destination.clone()).await?;
+        assert!(client.connect(destination).await.is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn bind() -> Result<()> {
+        let proxy_addr = spawn_proxy(Socks4Handler::default()).await?;
+
+        let client = Socks4Client::new(proxy_addr.to_string(), None).await?;
+        let (binding, accepted) = client.bind("127.0.0.1:0".to_string()).await?;
+
+        let mut peer = TcpStream::connect(binding.to_string()).await?;
+        let (mut stream, peer_addr) = accepted.await?;
+        assert_eq!(peer_addr.to_string(), peer.local_addr()?.to_string());
+
+        peer.write_all(b"ping").await?;
+        let mut buffer = [0; 4];
+        stream.read_exact(&mut buffer).await?;
+        assert_eq!(&buffer, b"ping");
+
+        Ok(())
+    }
+}
@@ -1,14 +1,18 @@
 use crate::addresses::{self, Address};
 use crate::constants::*;
+use crate::error::{Cause, Error};
 use anyhow::Result;
+use std::io;
 use num_traits::FromPrimitive;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 mod s5_client;
 mod s5_handler;
+mod s5_udp;
 
 pub use s5_client::Socks5Client;
 pub use s5_handler::Socks5Handler;
+pub use s5_udp::Socks5Datagram;
 
 #[repr(u8)]
 #[derive(Clone, Debug, FromPrimitive, PartialEq)]
@@ -42,13 +46,40 @@ impl Socks5Request {
     ///
     ///
     pub fn into_socks_bytes(self) -> Vec<u8> {
-        let mut data = vec![SOCKS_VER_5, SOCKS_CMD_CONNECT, SOCKS_RSV];
+        let mut data = vec![SOCKS_VER_5, self.command as u8, SOCKS_RSV];
         data.extend(self.destination.as_socks_bytes());
 
         data
     }
 }
 
+/// Reads a SOCKS5 request, i.e., the command and destination, from a client.
+#[instrument(skip_all)]
+pub async fn read_request<S>(stream: &mut S) -> Result<Socks5Request>
+where
+    S: AsyncRead + Unpin,
+{
+    // Read SOCKS version, command type, and reserved byte.
+    let mut request = [0; 3];
+    stream.read_exact(&mut request).await?;
+
+    let [version, command, _] = request;
+
+    // Validate the request.
+    ensure!(
+        version == SOCKS_VER_5,
+        Error::Protocol(format!("Client uses a different SOCKS version: {}.", version))
+    );
+    ensure!(
+        Socks5Command::from_u8(command).is_some(),
+        Error::CommandNotSupported(format!("Client requested an unsupported command: {}.", command))
+    );
+
+    let destination = addresses::read_address(stream).await?;
+
+    Ok(Socks5Request::new(command, destination))
+}
+
 #[repr(u8)]
 #[derive(Clone, Debug, FromPrimitive, PartialEq)]
 pub enum Socks5Reply {
@@ -64,30 +95,48 @@ pub enum Socks5Reply {
     ConnectionAttemptTimeOut = 0x09,
 }
 
+impl From<&anyhow::Error> for Socks5Reply {
+    /// Chooses the reply that tells the client why its request failed.
+    fn from(error: &anyhow::Error) -> Self {
+        let kind = match Error::cause(error) {
+            Cause::Socksx(Error::Connect { source, .. }) => source.kind(),
+            Cause::Socksx(Error::Resolve(_)) => return Socks5Reply::HostUnreachable,
+            Cause::Socksx(Error::CommandNotSupported(_)) => return Socks5Reply::CommandNotSupported,
+            Cause::Socksx(Error::NotAllowed(_)) => return Socks5Reply::ConnectionNotAllowed,
+            Cause::Socksx(Error::LimitExceeded(_)) => return Socks5Reply::ConnectionNotAllowed,
+            Cause::Socksx(Error::Refused(code)) => {
+                return Socks5Reply::from_u8(*code).unwrap_or(Socks5Reply::GeneralFailure)
+            }
+            Cause::Socksx(Error::Timeout(_)) => return Socks5Reply::ConnectionAttemptTimeOut,
+            Cause::Io(error) => error.kind(),
+            _ => return Socks5Reply::GeneralFailure,
+        };
+
+        match kind {
+            io::ErrorKind::ConnectionRefused => Socks5Reply::ConnectionRefused,
+            io::ErrorKind::HostUnreachable => Socks5Reply::HostUnreachable,
+            io::ErrorKind::NetworkUnreachable => Socks5Reply::NetworkUnreachable,
+            io::ErrorKind::TimedOut => Socks5Reply::ConnectionAttemptTimeOut,
+            _ => Socks5Reply::GeneralFailure,
+        }
+    }
+}
+
 ///
 ///
 ///
 pub async fn write_reply<S>(
     stream: &mut S,
     reply: Socks5Reply,
+    binding: &Address,
 ) -> Result<()>
 where
     S: AsyncWrite + Unpin,
 {
-    let reply = [
-        SOCKS_VER_5,
-        reply as u8,
-        SOCKS_RSV,
-        SOCKS_ATYP_IPV4,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-    ];
-
-    stream.write(&reply).await?;
+    let mut data = vec![SOCKS_VER_5, reply as u8, SOCKS_RSV];
+    data.extend(binding.as_socks_bytes());
+
+    stream.write_all(&data).await?;
 
     Ok(())
 }
@@ -103,11 +152,7 @@ where
     stream.read_exact(&mut operation_reply).await?;
 
     let reply_code = operation_reply[1];
-    ensure!(
-        reply_code == SOCKS_REP_SUCCEEDED,
-        "CONNECT operation failed: {}",
-        reply_code
-    );
+    ensure!(reply_code == SOCKS_REP_SUCCEEDED, Error::Refused(reply_code));
 
     let binding = addresses::read_address(stream).await?;
 
@@ -1,15 +1,18 @@
-use crate::socks5::{self, Socks5Request};
-use crate::{constants::*, Address, Credentials};
+use crate::socks5::{self, Socks5Datagram, Socks5Request};
+use crate::{constants::*, Address, Credentials, Error, SocksStream, TlsConnector};
 use anyhow::Result;
+use futures::future::{BoxFuture, FutureExt};
 use std::convert::TryInto;
 use std::net::SocketAddr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
 
 #[derive(Clone)]
 pub struct Socks5Client {
     proxy_addr: SocketAddr,
+    proxy_host: String,
     credentials: Option<Credentials>,
+    tls: Option<TlsConnector>,
 }
 
 impl Socks5Client {
@@ -20,14 +23,40 @@ impl Socks5Client {
         proxy_addr: A,
         credentials: Option<Credentials>,
     ) -> Result<Self> {
+        let proxy_addr = proxy_addr.into();
+        let proxy_host = crate::util::host(&proxy_addr).to_string();
         let proxy_addr = crate::resolve_addr(proxy_addr).await?;
 
         Ok(Socks5Client {
             proxy_addr,
+            proxy_host,
             credentials,
+            tls: None,
         })
     }
 
+    /// Speaks TLS to the proxy, verified by `connector`. The certificate of the proxy must be
+    /// valid for the host this client was created with.
+    pub fn with_tls(
+        mut self,
+        connector: TlsConnector,
+    ) -> Self {
+        self.tls = Some(connector);
+        self
+    }
+
+    /// Opens a connection to the proxy, and starts TLS if required.
+    async fn connect_to_proxy(&self) -> Result<SocksStream> {
+        let stream = TcpStream::connect(&self.proxy_addr)
+            .await
+            .map_err(|e| Error::connect(self.proxy_addr, e))?;
+
+        match &self.tls {
+            Some(tls) => tls.connect(&self.proxy_host, stream.into()).await,
+            None => Ok(stream.into()),
+        }
+    }
+
     /// ...
     /// ...
     /// ...
@@ -36,25 +65,101 @@ impl Socks5Client {
     pub async fn connect<A>(
         &self,
         destination: A,
-    ) -> Result<(TcpStream, Address)>
+    ) -> Result<(SocksStream, Address)>
     where
         A: TryInto<Address, Error = anyhow::Error>,
     {
-        if let Some(Credentials { username, password }) = &self.credentials {
-            ensure!(username.len() > 255, "Username MUST NOT be larger than 255 bytes.");
-            ensure!(password.len() > 255, "Password MUST NOT be larger than 255 bytes.");
-        }
+        let mut stream = self.connect_to_proxy().await?;
+        let binding = self.handshake(destination, &mut stream).await?;
+
+        Ok((stream, binding))
+    }
 
-        // Create SOCKS5 CONNECT request.
+    /// Performs a CONNECT handshake on a stream that is already connected to the proxy, e.g.,
+    /// a stream tunneled through a previous proxy. Returns the address the proxy bound to.
+    ///
+    /// [rfc1928] https://tools.ietf.org/html/rfc1928
+    pub async fn handshake<A, S>(
+        &self,
+        destination: A,
+        stream: &mut S,
+    ) -> Result<Address>
+    where
+        A: TryInto<Address, Error = anyhow::Error>,
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
         let request = Socks5Request::new(SOCKS_CMD_CONNECT, destination.try_into()?);
 
-        let mut stream = TcpStream::connect(&self.proxy_addr).await?;
+        self.request(stream, request).await
+    }
+
+    /// Asks the proxy to listen for a single inbound connection from `destination`. Returns
+    /// the address the proxy listens on, and a future that resolves once the peer connected.
+    ///
+    /// [rfc1928] https://tools.ietf.org/html/rfc1928#section-4
+    pub async fn bind<A>(
+        &self,
+        destination: A,
+    ) -> Result<(Address, BoxFuture<'static, Result<(SocksStream, Address)>>)>
+    where
+        A: TryInto<Address, Error = anyhow::Error>,
+    {
+        let request = Socks5Request::new(SOCKS_CMD_BIND, destination.try_into()?);
+
+        let mut stream = self.connect_to_proxy().await?;
+        let binding = self.request(&mut stream, request).await?;
+
+        // The second reply arrives when the peer connects to the proxy.
+        let accepted = async move {
+            let peer = socks5::read_reply(&mut stream).await?;
+            Ok((stream, peer))
+        };
+
+        Ok((binding, accepted.boxed()))
+    }
+
+    /// Asks the proxy to relay UDP datagrams. The association lasts as long as the returned value.
+    ///
+    /// [rfc1928] https://tools.ietf.org/html/rfc1928#section-7
+    pub async fn udp_associate(&self) -> Result<Socks5Datagram> {
+        let mut stream = self.connect_to_proxy().await?;
+
+        // Tell the proxy from which address we'll be sending datagrams.
+        let socket = UdpSocket::bind(SocketAddr::new(stream.local_addr()?.ip(), 0)).await?;
+        let request = Socks5Request::new(SOCKS_CMD_UDP_ASSOCIATE, Address::Ip(socket.local_addr()?));
+
+        let binding = self.request(&mut stream, request).await?;
+
+        // An unspecified relay address means the relay is at the proxy's address.
+        let relay_addr = match binding {
+            Address::Ip(addr) if addr.ip().is_unspecified() => SocketAddr::new(self.proxy_addr.ip(), addr.port()),
+            binding => crate::resolve_addr(binding.to_string()).await?,
+        };
+
+        Ok(Socks5Datagram::new(socket, relay_addr, stream))
+    }
+
+    /// Negotiates authentication, sends the request, and returns the address from the reply.
+    ///
+    /// [rfc1928] https://tools.ietf.org/html/rfc1928
+    async fn request<S>(
+        &self,
+        stream: &mut S,
+        request: Socks5Request,
+    ) -> Result<Address>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        if let Some(Credentials { username, password }) = &self.credentials {
+            ensure!(username.len() <= 255, "Username MUST NOT be larger than 255 bytes.");
+            ensure!(password.len() <= 255, "Password MUST NOT be larger than 255 bytes.");
+        }
 
         // Enter authentication negotiation.
-        let auth_method = self.negotiate_auth_method(&mut stream).await?;
+        let auth_method = self.negotiate_auth_method(stream).await?;
         if auth_method == SOCKS_AUTH_USERNAME_PASSWORD {
             if let Some(credentials) = &self.credentials {
-                self.authenticate(&mut stream, credentials).await?;
+                self.authenticate(stream, credentials).await?;
             } else {
                 unreachable!();
             }
@@ -62,12 +167,12 @@ impl Socks5Client {
 
         // Send SOCKS request information.
         let request_bytes = request.into_socks_bytes();
-        stream.write(&request_bytes).await?;
+        stream.write_all(&request_bytes).await?;
 
         // Read operation reply.
-        let binding = socks5::read_reply(&mut stream).await?;
+        let binding = socks5::read_reply(stream).await?;
 
-        Ok((stream, binding))
+        Ok(binding)
     }
 
     /// ...
@@ -75,24 +180,27 @@ impl Socks5Client {
     /// ...
     ///
     /// [rfc1928] https://tools.ietf.org/html/rfc1928
-    async fn negotiate_auth_method(
+    async fn negotiate_auth_method<S>(
         &self,
-        stream: &mut TcpStream,
-    ) -> Result<u8> {
+        stream: &mut S,
+    ) -> Result<u8>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
         let mut request = vec![SOCKS_VER_5, 0x01, SOCKS_AUTH_NOT_REQUIRED];
         if self.credentials.is_some() {
             request[1] = 0x02;
             request.push(SOCKS_AUTH_USERNAME_PASSWORD);
         }
 
-        stream.write(&request).await?;
+        stream.write_all(&request).await?;
 
         let mut reply = [0; 2];
         stream.read_exact(&mut reply).await?;
 
         let socks_version = reply[0];
         if socks_version != SOCKS_VER_5 {
-            bail!("Proxy uses a different SOCKS version: {}.", socks_version);
+            bail!(Error::Protocol(format!("Proxy uses a different SOCKS version: {}.", socks_version)));
         }
 
         let auth_method = reply[1];
@@ -100,12 +208,13 @@ impl Socks5Client {
             0x00 => Ok(auth_method),
             0x02 => {
                 if self.credentials.is_none() {
-                    bail!("Proxy demands authentication, but no credentials are provided.");
+                    let reason = "Proxy demands authentication, but no credentials are provided.";
+                    bail!(Error::Authentication(String::from(reason)));
                 } else {
                     Ok(auth_method)
                 }
             }
-            0xFF => bail!("Proxy did not accept authentication method."),
+            0xFF => bail!(Error::Authentication(String::from("Proxy did not accept authentication method."))),
             _ => bail!("Proxy proposed unsupported authentication method: {}.", auth_method),
         }
     }
@@ -115,15 +224,18 @@ impl Socks5Client {
     /// ...
     ///
     /// [rfc1929] https://tools.ietf.org/html/rfc1929
-    async fn authenticate(
+    async fn authenticate<S>(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut S,
         credentials: &Credentials,
-    ) -> Result<()> {
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
         let mut request = vec![SOCKS_AUTH_VER];
         request.extend(credentials.as_socks_bytes());
 
-        stream.write(&request).await?;
+        stream.write_all(&request).await?;
 
         let mut reply = [0; 2];
         stream.read_exact(&mut reply).await?;
@@ -139,7 +251,8 @@ impl Socks5Client {
         // Check if status indicates success. If not, bail to close the connection.
         let status = reply[1];
         if status != SOCKS_AUTH_SUCCESS {
-            bail!("Authentication with the provided credentials failed.");
+            let reason = "Authentication with the provided credentials failed.";
+            bail!(Error::Authentication(String::from(reason)));
         }
 
         Ok(())
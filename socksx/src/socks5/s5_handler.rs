@@ -1,16 +1,29 @@
-use crate::addresses::{self, ProxyAddress};
-use crate::socks5::{self, Socks5Reply};
-use crate::SocksHandler;
-use crate::{constants::*, Credentials};
+use crate::acl::{AccessControl, AccessRequest, Command};
+use crate::addresses::ProxyAddress;
+use crate::limits::{ConnectionLimits, ConnectionPermit};
+use crate::metrics::{Hook, MetricsHook, Protocol};
+use crate::socks5::{self, s5_udp, Socks5Command, Socks5Reply, Socks5Request};
+use crate::socks6::SocksChain;
+use crate::timeouts::Timeouts;
+use crate::{constants::*, Address, Authenticator, Credentials, Error, SocksHandler, SocksStream, TlsConnector};
 use anyhow::Result;
 use async_trait::async_trait;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::net::{TcpListener, UdpSocket};
+use tracing::Span;
 
 #[derive(Clone)]
 pub struct Socks5Handler {
-    credentials: Option<Credentials>,
+    access_control: Option<Arc<AccessControl>>,
+    authenticator: Option<Arc<dyn Authenticator>>,
     chain: Vec<ProxyAddress>,
+    chain_tls: Option<TlsConnector>,
+    limits: Option<ConnectionLimits>,
+    metrics: Hook,
+    timeouts: Timeouts,
 }
 
 impl Default for Socks5Handler {
@@ -25,10 +38,70 @@ impl Socks5Handler {
     ///
     pub fn new(chain: Vec<ProxyAddress>) -> Self {
         Socks5Handler {
-            credentials: None,
+            access_control: None,
+            authenticator: None,
             chain,
+            chain_tls: None,
+            limits: None,
+            metrics: Hook::new(Protocol::Socks5),
+            timeouts: Timeouts::default(),
         }
     }
+
+    /// Requires clients to authenticate with a username and password, checked by `authenticator`.
+    pub fn with_authenticator<A: Authenticator + 'static>(
+        mut self,
+        authenticator: A,
+    ) -> Self {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /// Consults `access_control` before acting on a request, denied requests are refused.
+    pub fn with_access_control(
+        mut self,
+        access_control: AccessControl,
+    ) -> Self {
+        self.access_control = Some(Arc::new(access_control));
+        self
+    }
+
+    /// Holds a slot of the per-user limit of `limits` while relaying for an authenticated client.
+    pub fn with_limits(
+        mut self,
+        limits: ConnectionLimits,
+    ) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    /// Reports the events of this handler, e.g., handshakes and bytes relayed, to `hook`. Call
+    /// this again to report to several hooks, e.g., metrics and an access log.
+    pub fn with_metrics<H: MetricsHook + 'static>(
+        mut self,
+        hook: H,
+    ) -> Self {
+        self.metrics = self.metrics.with_hook(Arc::new(hook));
+        self
+    }
+
+    /// Limits the time spent on the handshake, on connecting to the destination, and on idle relays.
+    pub fn with_timeouts(
+        mut self,
+        timeouts: Timeouts,
+    ) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Starts TLS with the `+tls` links of the chain, and verifies them with `connector`.
+    pub fn with_chain_tls(
+        mut self,
+        connector: TlsConnector,
+    ) -> Self {
+        self.chain_tls = Some(connector);
+        self
+    }
 }
 
 #[async_trait]
@@ -38,14 +111,10 @@ impl SocksHandler for Socks5Handler {
     ///
     async fn accept_request(
         &self,
-        source: &mut TcpStream,
+        source: &mut SocksStream,
     ) -> Result<()> {
-        let mut destination = self.setup(source).await?;
-
-        // Start bidirectional copy, after this the connection closes.
-        tokio::io::copy_bidirectional(source, &mut destination).await?;
-
-        Ok(())
+        let client = source.peer_addr()?;
+        self.metrics.scope(client, self.serve(source)).await
     }
 
     ///
@@ -53,12 +122,13 @@ impl SocksHandler for Socks5Handler {
     ///
     async fn refuse_request(
         &self,
-        source: &mut TcpStream,
+        source: &mut SocksStream,
     ) -> Result<()> {
         // Notify source that the connection is refused.
-        socks5::write_reply(source, Socks5Reply::ConnectionRefused).await?;
-
-        Ok(())
+        let client = source.peer_addr()?;
+        let binding = Address::unspecified();
+        let reply = self.reply(source, Socks5Reply::ConnectionRefused, &binding);
+        self.metrics.scope(client, reply).await
     }
 
     ///
@@ -66,15 +136,117 @@ impl SocksHandler for Socks5Handler {
     ///
     async fn setup(
         &self,
-        source: &mut TcpStream,
-    ) -> Result<TcpStream> {
+        source: &mut SocksStream,
+    ) -> Result<SocksStream> {
+        let handshake = self.timeouts.handshake(self.handshake(source)).await;
+        let (request, identity) = self.metrics.handshake(handshake)?;
+        self.execute(source, request, identity).await
+    }
+}
+
+impl Socks5Handler {
+    /// Handles the client, from its request until the relay or UDP association ends.
+    async fn serve(
+        &self,
+        source: &mut SocksStream,
+    ) -> Result<()> {
+        let handshake = self.timeouts.handshake(self.handshake(source)).await;
+        let (request, identity) = self.metrics.handshake(handshake)?;
+        let command = format!("{:?}", request.command);
+        self.metrics.request(&command, &request.destination, identity.as_deref());
+
+        let _permit = self.acquire(source, identity.as_deref()).await?;
+
+        // A UDP association lives as long as the TCP connection, there's nothing to copy.
+        if request.command == Socks5Command::UdpAssociate {
+            return self.udp_associate(source, request.destination, identity).await;
+        }
+
+        let mut destination = self.execute(source, request, identity).await?;
+
+        // Start bidirectional copy, after this the connection closes.
+        self.timeouts.relay(source, &mut destination, |up, down| self.metrics.relayed(up, down)).await?;
+
+        Ok(())
+    }
+
+    /// Negotiates authentication with the client, and reads its request. Returns the request,
+    /// along with the identity of the client if it authenticated itself.
+    async fn handshake(
+        &self,
+        source: &mut SocksStream,
+    ) -> Result<(Socks5Request, Option<String>)> {
+        let identity = self.negotiate(source).await?;
+
+        let request = match socks5::read_request(source).await {
+            Ok(request) => request,
+            Err(error) => {
+                if let Some(Error::CommandNotSupported(_)) = error.downcast_ref() {
+                    self.reply(source, Socks5Reply::CommandNotSupported, &Address::unspecified()).await?;
+                }
+                return Err(error);
+            }
+        };
+
+        // Consult the access control rules, before acting on the request.
+        if let Some(access_control) = &self.access_control {
+            let command = match request.command {
+                Socks5Command::Connect => Command::Connect,
+                Socks5Command::Bind => Command::Bind,
+                Socks5Command::UdpAssociate => Command::UdpAssociate,
+            };
+            // The address of a UDP association is where the client sends from, not a destination.
+            let destination = Some(&request.destination).filter(|_| command != Command::UdpAssociate);
+            let access_request = AccessRequest {
+                source: source.peer_addr()?.ip(),
+                user: identity.as_deref(),
+                destination,
+                command,
+            };
+            if let Err(error) = access_control.authorize(&access_request).await {
+                self.reply(source, Socks5Reply::from(&error), &Address::unspecified()).await?;
+                return Err(error);
+            }
+        }
+
+        Ok((request, identity))
+    }
+
+    /// Takes a slot of the per-user limit for the identity of the client, if it has one. The
+    /// slot is released when the returned permit is dropped.
+    async fn acquire(
+        &self,
+        source: &mut SocksStream,
+        identity: Option<&str>,
+    ) -> Result<ConnectionPermit> {
+        let (limits, user) = match (&self.limits, identity) {
+            (Some(limits), Some(user)) => (limits, user),
+            _ => return Ok(ConnectionPermit::default()),
+        };
+
+        match limits.acquire_user(user).await {
+            Ok(permit) => Ok(permit),
+            Err(error) => {
+                self.reply(source, Socks5Reply::from(&error), &Address::unspecified()).await?;
+                Err(error)
+            }
+        }
+    }
+
+    /// Selects an authentication method from those the client proposes, and performs its
+    /// sub-negotiation. Returns the identity of the client, if it authenticated itself.
+    #[instrument(name = "auth", skip_all, fields(method))]
+    async fn negotiate(
+        &self,
+        source: &mut SocksStream,
+    ) -> Result<Option<String>> {
         let mut request = [0; 2];
         source.read_exact(&mut request).await?;
 
         let socks_version = request[0];
 
         if socks_version != SOCKS_VER_5 {
-            bail!("Client uses a different SOCKS version: {}.", socks_version);
+            bail!(Error::Protocol(format!("Client uses a different SOCKS version: {}.", socks_version)));
         }
 
         // Get all authentication methods the client proposes.
@@ -83,71 +255,624 @@ impl SocksHandler for Socks5Handler {
         let mut methods = vec![0; nmethods];
         source.read_exact(&mut methods).await?;
 
-        let method = if self.credentials.is_some() && methods.contains(&SOCKS_AUTH_USERNAME_PASSWORD) {
-            SOCKS_AUTH_USERNAME_PASSWORD
+        // Unauthenticated access is only an option if no authenticator is configured, or if the
+        // client is known by its TLS certificate.
+        let method = if source.identity().is_some() && methods.contains(&SOCKS_AUTH_NOT_REQUIRED) {
+            SOCKS_AUTH_NOT_REQUIRED
+        } else if self.authenticator.is_some() {
+            if methods.contains(&SOCKS_AUTH_USERNAME_PASSWORD) {
+                SOCKS_AUTH_USERNAME_PASSWORD
+            } else {
+                SOCKS_AUTH_NO_ACCEPTABLE_METHODS
+            }
         } else if methods.contains(&SOCKS_AUTH_NOT_REQUIRED) {
             SOCKS_AUTH_NOT_REQUIRED
         } else {
             SOCKS_AUTH_NO_ACCEPTABLE_METHODS
         };
 
-        info!("Use authentication method: {}", method);
+        Span::current().record("method", method);
 
         let response = [SOCKS_VER_5, method];
-        source.write(&response).await?;
+        source.write_all(&response).await?;
 
         // Enter method-specific sub-negotiation
-        if method == SOCKS_AUTH_USERNAME_PASSWORD {
-            let mut request = [0; 2];
-            source.read_exact(&mut request).await?;
-
-            let auth_version = request[0];
-            if auth_version != SOCKS_AUTH_VER {
-                bail!(
-                    "Client uses a different authentication method version: {}.",
-                    auth_version
-                );
+        match method {
+            SOCKS_AUTH_USERNAME_PASSWORD => Ok(Some(self.authenticate(source).await?)),
+            SOCKS_AUTH_NOT_REQUIRED => Ok(source.identity().map(String::from)),
+            _ => {
+                let reason = "Client didn't propose an acceptable authentication method.";
+                bail!(Error::Authentication(String::from(reason)))
             }
+        }
+    }
 
-            let ulen = request[1] as usize;
-            let mut uname = vec![0; ulen];
-            source.read_exact(&mut uname).await?;
+    /// Performs the username/password sub-negotiation, and returns the identity of the client.
+    ///
+    /// [rfc1929] https://tools.ietf.org/html/rfc1929
+    async fn authenticate(
+        &self,
+        source: &mut SocksStream,
+    ) -> Result<String> {
+        let mut request = [0; 2];
+        source.read_exact(&mut request).await?;
 
-            let plen = request[1] as usize;
-            let mut passwd = vec![0; plen];
-            source.read_exact(&mut passwd).await?;
+        let auth_version = request[0];
+        if auth_version != SOCKS_AUTH_VER {
+            bail!(
+                "Client uses a different authentication method version: {}.",
+                auth_version
+            );
+        }
 
-            let status = if let Some(Credentials { username, password }) = &self.credentials {
-                if &uname != username || &passwd != password {
-                    SOCKS_AUTH_SUCCESS
-                } else {
-                    0x01u8
-                }
-            } else {
-                unreachable!()
-            };
+        let ulen = request[1] as usize;
+        let mut uname = vec![0; ulen];
+        source.read_exact(&mut uname).await?;
 
-            let response = [SOCKS_VER_5, status];
-            source.write(&response).await?;
+        let mut plen = [0; 1];
+        source.read_exact(&mut plen).await?;
 
-            ensure!(status == SOCKS_AUTH_SUCCESS, "Username/password authentication failed.");
-        }
+        let mut passwd = vec![0; plen[0] as usize];
+        source.read_exact(&mut passwd).await?;
 
-        let mut request = [0; 3];
-        source.read_exact(&mut request).await?;
+        let identity = if let Some(authenticator) = &self.authenticator {
+            authenticator.authenticate(&Credentials::new(uname, passwd)).await?
+        } else {
+            unreachable!()
+        };
 
-        let command = request[1];
-        if command != SOCKS_CMD_CONNECT {
-            unimplemented!();
+        let status = if identity.is_some() {
+            SOCKS_AUTH_SUCCESS
+        } else {
+            SOCKS_AUTH_FAILED
+        };
+
+        let response = [SOCKS_AUTH_VER, status];
+        source.write_all(&response).await?;
+
+        identity.ok_or_else(|| Error::Authentication(String::from("Username/password authentication failed.")).into())
+    }
+
+    /// Executes a CONNECT or BIND request, and returns the stream to relay to.
+    async fn execute(
+        &self,
+        source: &mut SocksStream,
+        request: Socks5Request,
+        identity: Option<String>,
+    ) -> Result<SocksStream> {
+        debug!(
+            "{:?} to {} for {}",
+            request.command,
+            request.destination.to_string(),
+            identity.as_deref().unwrap_or("anonymous")
+        );
+
+        match request.command {
+            Socks5Command::Connect => self.connect(source, request.destination).await,
+            Socks5Command::Bind => self.bind(source, request.destination).await,
+            Socks5Command::UdpAssociate => {
+                self.reply(source, Socks5Reply::CommandNotSupported, &Address::unspecified()).await?;
+                bail!(Error::CommandNotSupported(String::from(
+                    "UDP ASSOCIATE can't be set up as a TCP stream."
+                )))
+            }
         }
+    }
 
-        let destination = addresses::read_address(source).await?;
-        let destination = TcpStream::connect(destination.to_string()).await?;
+    /// Connects to the destination on behalf of the client, through the chain if one is configured.
+    async fn connect(
+        &self,
+        source: &mut SocksStream,
+        destination: Address,
+    ) -> Result<SocksStream> {
+        let mut chain = SocksChain::default();
+        if !self.chain.is_empty() {
+            chain.detour(&self.chain);
+        }
 
-        // Notify source that the connection has been set up.
-        socks5::write_reply(source, Socks5Reply::Success).await?;
+        let (links, start) = (chain.remaining().to_vec(), Instant::now());
+        let destination = match self.timeouts.connect(chain.connect(destination, &[], self.chain_tls.as_ref())).await {
+            Ok(destination) => destination,
+            Err(error) => {
+                // Tell the client why, before closing the connection.
+                self.reply(source, Socks5Reply::from(&error), &Address::unspecified()).await?;
+                return Err(error);
+            }
+        };
+        self.metrics.connected(start.elapsed(), &links, &destination);
+
+        // Notify source that the connection has been set up, and from which address.
+        let binding = Address::Ip(destination.local_addr()?);
+        self.reply(source, Socks5Reply::Success, &binding).await?;
         source.flush().await?;
 
         Ok(destination)
     }
+
+    /// Accepts a single inbound connection on behalf of the client. The first reply
+    /// tells the client where the proxy listens, the second reply who connected.
+    ///
+    /// [rfc1928] https://tools.ietf.org/html/rfc1928#section-4
+    async fn bind(
+        &self,
+        source: &mut SocksStream,
+        destination: Address,
+    ) -> Result<SocksStream> {
+        // Listen on the interface the client reached us on, it's the one most likely reachable.
+        let listener = TcpListener::bind(SocketAddr::new(source.local_addr()?.ip(), 0)).await?;
+        let binding = Address::Ip(listener.local_addr()?);
+
+        self.reply(source, Socks5Reply::Success, &binding).await?;
+        source.flush().await?;
+
+        let accepted = self.timeouts.connect(async { Ok(listener.accept().await?) }).await;
+        let (incoming, peer_addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                // Tell the client, before closing the connection.
+                self.reply(source, Socks5Reply::from(&error), &Address::unspecified()).await?;
+                return Err(error);
+            }
+        };
+
+        // The client tells us who it expects to connect back, reject anyone else.
+        if let Address::Ip(expected) = destination {
+            if !expected.ip().is_unspecified() && expected.ip() != peer_addr.ip() {
+                self.reply(source, Socks5Reply::ConnectionNotAllowed, &Address::Ip(peer_addr)).await?;
+                bail!("Unexpected peer connected to BIND listener: {}.", peer_addr);
+            }
+        }
+
+        // Notify source that the inbound connection has been established.
+        self.reply(source, Socks5Reply::Success, &Address::Ip(peer_addr)).await?;
+        source.flush().await?;
+
+        Ok(incoming.into())
+    }
+
+    /// Relays UDP datagrams for the client, until it closes the TCP connection.
+    ///
+    /// [rfc1928] https://tools.ietf.org/html/rfc1928#section-7
+    async fn udp_associate(
+        &self,
+        source: &mut SocksStream,
+        destination: Address,
+        identity: Option<String>,
+    ) -> Result<()> {
+        debug!(
+            "UdpAssociate from {} for {}",
+            destination.to_string(),
+            identity.as_deref().unwrap_or("anonymous")
+        );
+
+        let socket = UdpSocket::bind(SocketAddr::new(source.local_addr()?.ip(), 0)).await?;
+        let binding = Address::Ip(socket.local_addr()?);
+
+        self.reply(source, Socks5Reply::Success, &binding).await?;
+        source.flush().await?;
+
+        // The client may announce the address it sends from, all zeros if it doesn't know yet.
+        let client_addr = match destination {
+            Address::Ip(addr) if !addr.ip().is_unspecified() && addr.port() != 0 => Some(addr),
+            _ => None,
+        };
+
+        let client_ip = source.peer_addr()?.ip();
+        let access_control = self.access_control.as_deref();
+        let relay = s5_udp::relay(&socket, client_ip, client_addr, access_control, identity.as_deref());
+        tokio::select! {
+            result = relay => result,
+            result = s5_udp::wait_for_close(source) => result,
+        }
+    }
+
+    /// Sends a reply to the client, and reports it.
+    async fn reply(
+        &self,
+        source: &mut SocksStream,
+        reply: Socks5Reply,
+        binding: &Address,
+    ) -> Result<()> {
+        self.metrics.reply(reply.clone() as u16);
+        socks5::write_reply(source, reply, binding).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access_log::AccessRecord;
+    use crate::acl::Rule;
+    use crate::auth::StaticAuthenticator;
+    use crate::tls::test_pki;
+    use crate::util::{spawn_proxy, spawn_tls_proxy};
+    use crate::Socks5Client;
+    use std::fmt;
+    use std::sync::Mutex;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpStream;
+    use tokio::sync::mpsc;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::Subscriber;
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::Layer;
+
+    #[tokio::test]
+    pub async fn binding() -> Result<()> {
+        let proxy_addr = spawn_proxy(Socks5Handler::default()).await?;
+        let target = TcpListener::bind("127.0.0.1:0").await?;
+
+        let client = Socks5Client::new(proxy_addr.to_string(), None).await?;
+        let (_, binding) = client.connect(target.local_addr()?.to_string()).await?;
+
+        // The proxy reports the address it connected from.
+        let (_, peer_addr) = target.accept().await?;
+        assert_eq!(binding, Address::Ip(peer_addr));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn bind() -> Result<()> {
+        let proxy_addr = spawn_proxy(Socks5Handler::default()).await?;
+
+        let client = Socks5Client::new(proxy_addr.to_string(), None).await?;
+        let (binding, accepted) = client.bind("127.0.0.1:0".to_string()).await?;
+
+        let mut peer = TcpStream::connect(binding.to_string()).await?;
+        let (mut stream, peer_addr) = accepted.await?;
+        assert_eq!(peer_addr.to_string(), peer.local_addr()?.to_string());
+
+        peer.write_all(b"ping").await?;
+        let mut buffer = [0; 4];
+        stream.read_exact(&mut buffer).await?;
+        assert_eq!(&buffer, b"ping");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn authenticate() -> Result<()> {
+        let users = vec![("alice", "secret")].into_iter().collect();
+        let handler = Socks5Handler::default().with_authenticator(StaticAuthenticator::new(users));
+        let proxy_addr = spawn_proxy(handler).await?;
+
+        let target = TcpListener::bind("127.0.0.1:0").await?;
+        let destination = target.local_addr()?.to_string();
+
+        let client = Socks5Client::new(proxy_addr.to_string(), Some(Credentials::new("alice", "guess"))).await?;
+        assert!(client.connect(destination.clone()).await.is_err());
+
+        let client = Socks5Client::new(proxy_addr.to_string(), None).await?;
+        assert!(client.connect(destination.clone()).await.is_err());
+
+        let client = Socks5Client::new(proxy_addr.to_string(), Some(Credentials::new("alice", "secret"))).await?;
+        assert!(client.connect(destination).await.is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn tls() -> Result<()> {
+        let (acceptor, connector, anonymous) = test_pki()?;
+        let users = vec![("alice", "secret")].into_iter().collect();
+        let handler = Socks5Handler::default().with_authenticator(StaticAuthenticator::new(users));
+        let proxy_addr = spawn_tls_proxy(handler, acceptor).await?;
+        let proxy_addr = format!("localhost:{}", proxy_addr.port());
+
+        let target = TcpListener::bind("127.0.0.1:0").await?;
+        let destination = target.local_addr()?.to_string();
+
+        // The client certificate stands in for credentials.
+        let client = Socks5Client::new(proxy_addr.clone(), None).await?.with_tls(connector);
+        let (mut outgoing, _) = client.connect(destination.clone()).await?;
+        let (mut incoming, _) = target.accept().await?;
+        outgoing.write_all(b"ping").await?;
+        let mut buffer = [0; 4];
+        incoming.read_exact(&mut buffer).await?;
+        assert_eq!(&buffer, b"ping");
+
+        let client = Socks5Client::new(proxy_addr.clone(), None).await?.with_tls(anonymous);
+        assert!(client.connect(destination.clone()).await.is_err());
+        let client = Socks5Client::new(proxy_addr, None).await?;
+        assert!(client.connect(destination).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn chain() -> Result<()> {
+        let users = vec![("alice", "secret")].into_iter().collect();
+        let last = Socks5Handler::default().with_authenticator(StaticAuthenticator::new(users));
+        let last_addr = spawn_proxy(last).await?;
+        let middle_addr = spawn_proxy(Socks5Handler::default()).await?;
+
+        let links = vec![
+            ProxyAddress::new(5, middle_addr.ip().to_string(), middle_addr.port(), None),
+            ProxyAddress::new(
+                5,
+                last_addr.ip().to_string(),
+                last_addr.port(),
+                Some(Credentials::new("alice", "secret")),
+            ),
+        ];
+        let proxy_addr = spawn_proxy(Socks5Handler::new(links)).await?;
+
+        let target = TcpListener::bind("127.0.0.1:0").await?;
+        let client = Socks5Client::new(proxy_addr.to_string(), None).await?;
+        let (mut outgoing, _) = client.connect(target.local_addr()?.to_string()).await?;
+
+        let (mut incoming, _) = target.accept().await?;
+        outgoing.write_all(b"ping").await?;
+        let mut buffer = [0; 4];
+        incoming.read_exact(&mut buffer).await?;
+        assert_eq!(&buffer, b"ping");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn udp_associate() -> Result<()> {
+        let proxy_addr = spawn_proxy(Socks5Handler::default()).await?;
+
+        let echo = UdpSocket::bind("127.0.0.1:0").await?;
+        let echo_addr = echo.local_addr()?;
+
+        tokio::spawn(async move {
+            let mut buffer = [0; 64];
+            let (length, from) = echo.recv_from(&mut buffer).await.unwrap();
+            echo.send_to(&buffer[..length], from).await.unwrap();
+        });
+
+        let client = Socks5Client::new(proxy_addr.to_string(), None).await?;
+        let datagram = client.udp_associate().await?;
+        datagram.send_to(b"ping", echo_addr.to_string()).await?;
+
+        let mut buffer = [0; 64];
+        let (length, source) = datagram.recv_from(&mut buffer).await?;
+        assert_eq!(&buffer[..length], b"ping");
+        assert_eq!(source.to_string(), echo_addr.to_string());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn connection_refused() -> Result<()> {
+        let proxy_addr = spawn_proxy(Socks5Handler::default()).await?;
+
+        // Nothing listens on the port anymore, so the proxy's connect is refused.
+        let destination = TcpListener::bind("127.0.0.1:0").await?.local_addr()?.to_string();
+
+        let client = Socks5Client::new(proxy_addr.to_string(), None).await?;
+        let error = client.connect(destination).await.unwrap_err();
+        match error.downcast_ref::<Error>() {
+            Some(Error::Refused(reply)) => assert_eq!(*reply, Socks5Reply::ConnectionRefused as u8),
+            _ => panic!("Unexpected error: {}", error),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn not_allowed() -> Result<()> {
+        let target = TcpListener::bind("127.0.0.1:0").await?;
+        let port = target.local_addr()?.port();
+
+        let rule = Rule::deny().with_destination("127.0.0.1".parse()?).with_ports(port..=port);
+        let handler = Socks5Handler::default().with_access_control(AccessControl::new(vec![rule]));
+        let proxy_addr = spawn_proxy(handler).await?;
+
+        let client = Socks5Client::new(proxy_addr.to_string(), None).await?;
+        let error = client.connect(target.local_addr()?.to_string()).await.unwrap_err();
+        match error.downcast_ref::<Error>() {
+            Some(Error::Refused(reply)) => assert_eq!(*reply, Socks5Reply::ConnectionNotAllowed as u8),
+            _ => panic!("Unexpected error: {}", error),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn udp_not_allowed() -> Result<()> {
+        let denied = UdpSocket::bind("127.0.0.1:0").await?;
+        let denied_addr = denied.local_addr()?;
+        let allowed = UdpSocket::bind("127.0.0.1:0").await?;
+        let allowed_addr = allowed.local_addr()?;
+
+        let port = denied_addr.port();
+        let rule = Rule::deny().with_destination("127.0.0.1".parse()?).with_ports(port..=port);
+        let handler = Socks5Handler::default().with_access_control(AccessControl::new(vec![rule]));
+        let proxy_addr = spawn_proxy(handler).await?;
+
+        // The association is allowed, but datagrams to the denied destination are dropped.
+        let client = Socks5Client::new(proxy_addr.to_string(), None).await?;
+        let datagram = client.udp_associate().await?;
+        datagram.send_to(b"denied", denied_addr.to_string()).await?;
+        datagram.send_to(b"allowed", allowed_addr.to_string()).await?;
+
+        let mut buffer = [0; 64];
+        let (length, _) = allowed.recv_from(&mut buffer).await?;
+        assert_eq!(&buffer[..length], b"allowed");
+        assert!(denied.try_recv_from(&mut buffer).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn limit_per_user() -> Result<()> {
+        let users = vec![("alice", "secret")].into_iter().collect();
+        let handler = Socks5Handler::default()
+            .with_authenticator(StaticAuthenticator::new(users))
+            .with_limits(ConnectionLimits::new(0).with_per_user(1));
+        let proxy_addr = spawn_proxy(handler).await?;
+
+        let target = TcpListener::bind("127.0.0.1:0").await?;
+        let destination = target.local_addr()?.to_string();
+        let credentials = Some(Credentials::new("alice", "secret"));
+
+        // The slot is held while the first connection is relayed.
+        let client = Socks5Client::new(proxy_addr.to_string(), credentials).await?;
+        let _first = client.connect(destination.clone()).await?;
+
+        let error = client.connect(destination).await.unwrap_err();
+        match error.downcast_ref::<Error>() {
+            Some(Error::Refused(reply)) => assert_eq!(*reply, Socks5Reply::ConnectionNotAllowed as u8),
+            _ => panic!("Unexpected error: {}", error),
+        }
+
+        Ok(())
+    }
+
+    /// Passes the access records of a handler on to the test.
+    struct Records(mpsc::UnboundedSender<AccessRecord>);
+
+    impl MetricsHook for Records {
+        fn access(
+            &self,
+            record: &AccessRecord,
+        ) {
+            let _ = self.0.send(record.clone());
+        }
+    }
+
+    #[tokio::test]
+    pub async fn access_record() -> Result<()> {
+        let (records, mut received) = mpsc::unbounded_channel();
+        let users = vec![("alice", "secret")].into_iter().collect();
+        let handler = Socks5Handler::default()
+            .with_authenticator(StaticAuthenticator::new(users))
+            .with_metrics(Records(records));
+        let proxy_addr = spawn_proxy(handler).await?;
+
+        let target = TcpListener::bind("127.0.0.1:0").await?;
+        let destination = target.local_addr()?;
+        let credentials = Some(Credentials::new("alice", "secret"));
+
+        let client = Socks5Client::new(proxy_addr.to_string(), credentials).await?;
+        let (mut outgoing, _) = client.connect(destination.to_string()).await?;
+        let (mut incoming, _) = target.accept().await?;
+
+        // The record is written once both sides closed the connection.
+        outgoing.write_all(b"ping").await?;
+        outgoing.shutdown().await?;
+        let mut buffer = [0; 4];
+        incoming.read_exact(&mut buffer).await?;
+        incoming.write_all(b"pong!").await?;
+        drop(incoming);
+        let mut buffer = vec![];
+        outgoing.read_to_end(&mut buffer).await?;
+
+        let record = received.recv().await.unwrap();
+        assert_eq!(record.client, outgoing.local_addr()?);
+        assert_eq!(record.user.as_deref(), Some("alice"));
+        assert_eq!(record.command.as_deref(), Some("Connect"));
+        assert_eq!(record.destination, Some(Address::Ip(destination)));
+        assert_eq!(record.resolved, Some(destination.ip()));
+        assert_eq!(record.reply, Some(Socks5Reply::Success as u16));
+        assert_eq!((record.bytes_up, record.bytes_down), (4, 5));
+
+        Ok(())
+    }
+
+    /// Collects the names of the spans that are created, and the fields recorded on them.
+    #[derive(Clone, Default)]
+    struct Spans(Arc<Mutex<Vec<String>>>);
+
+    impl<S: Subscriber> Layer<S> for Spans {
+        fn on_new_span(
+            &self,
+            attributes: &Attributes<'_>,
+            _id: &Id,
+            _context: Context<'_, S>,
+        ) {
+            let mut spans = self.0.lock().unwrap();
+            spans.push(attributes.metadata().name().to_string());
+            attributes.record(&mut Fields(&mut spans));
+        }
+
+        fn on_record(
+            &self,
+            _id: &Id,
+            values: &Record<'_>,
+            _context: Context<'_, S>,
+        ) {
+            values.record(&mut Fields(&mut self.0.lock().unwrap()));
+        }
+    }
+
+    struct Fields<'a>(&'a mut Vec<String>);
+
+    impl Visit for Fields<'_> {
+        fn record_debug(
+            &mut self,
+            field: &Field,
+            value: &dyn fmt::Debug,
+        ) {
+            self.0.push(format!("{}={:?}", field.name(), value));
+        }
+    }
+
+    #[tokio::test]
+    pub async fn spans() -> Result<()> {
+        let spans = Spans::default();
+        let _subscriber = tracing::subscriber::set_default(tracing_subscriber::registry().with(spans.clone()));
+
+        let middle_addr = spawn_proxy(Socks5Handler::default()).await?;
+        let links = vec![ProxyAddress::new(5, middle_addr.ip().to_string(), middle_addr.port(), None)];
+        let proxy_addr = spawn_proxy(Socks5Handler::new(links)).await?;
+
+        let target = TcpListener::bind("127.0.0.1:0").await?;
+        let destination = target.local_addr()?.to_string();
+        let client = Socks5Client::new(proxy_addr.to_string(), None).await?;
+        let (mut outgoing, _) = client.connect(destination.clone()).await?;
+
+        // Once data arrives, the relay has started.
+        let (mut incoming, _) = target.accept().await?;
+        outgoing.write_all(b"ping").await?;
+        let mut buffer = [0; 4];
+        incoming.read_exact(&mut buffer).await?;
+
+        let spans = spans.0.lock().unwrap();
+        for name in ["connection", "auth", "read_request", "connect", "hop", "relay"] {
+            assert!(spans.iter().any(|span| span == name), "No {} span in {:?}", name, spans);
+        }
+        assert!(spans.contains(&String::from("method=0")));
+        assert!(spans.contains(&String::from("index=1")));
+        assert!(spans.contains(&format!("destination={:?}", destination)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn timeouts() -> Result<()> {
+        use std::time::Duration;
+
+        // The link accepts connections, but never replies.
+        let link = TcpListener::bind("127.0.0.1:0").await?;
+        let link_addr = link.local_addr()?;
+        let links = vec![ProxyAddress::new(5, link_addr.ip().to_string(), link_addr.port(), None)];
+
+        let timeouts = Timeouts::new()
+            .with_handshake(Duration::from_millis(50))
+            .with_connect(Duration::from_millis(50));
+        let proxy_addr = spawn_proxy(Socks5Handler::new(links).with_timeouts(timeouts)).await?;
+
+        let client = Socks5Client::new(proxy_addr.to_string(), None).await?;
+        let error = client.connect("127.0.0.1:1".to_string()).await.unwrap_err();
+        match error.downcast_ref::<Error>() {
+            Some(Error::Refused(reply)) => assert_eq!(*reply, Socks5Reply::ConnectionAttemptTimeOut as u8),
+            _ => panic!("Unexpected error: {}", error),
+        }
+
+        // Nobody connects to the BIND listener in time.
+        let (_, accepted) = client.bind("127.0.0.1:0".to_string()).await?;
+        assert!(accepted.await.is_err());
+
+        // Clients that don't send a request are disconnected.
+        let mut stream = TcpStream::connect(proxy_addr).await?;
+        assert_eq!(stream.read(&mut [0; 1]).await?, 0);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,202 @@
+use crate::acl::{AccessControl, AccessRequest, Command};
+use crate::addresses::{self, Address};
+use crate::constants::*;
+use crate::SocksStream;
+use anyhow::Result;
+use std::convert::TryInto;
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::UdpSocket;
+
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// A UDP association with a SOCKS5 proxy. Datagrams are relayed by the proxy,
+/// for as long as this value (and thus the TCP control connection) is alive.
+///
+/// [rfc1928] https://tools.ietf.org/html/rfc1928#section-7
+pub struct Socks5Datagram {
+    socket: UdpSocket,
+    relay_addr: SocketAddr,
+    _control: SocksStream,
+}
+
+impl Socks5Datagram {
+    /// Wraps a local socket, the relay it sends to, and the connection that keeps the relay alive.
+    pub(crate) fn new(
+        socket: UdpSocket,
+        relay_addr: SocketAddr,
+        control: SocksStream,
+    ) -> Self {
+        Socks5Datagram {
+            socket,
+            relay_addr,
+            _control: control,
+        }
+    }
+
+    /// The address of the UDP relay at the proxy.
+    pub fn relay_addr(&self) -> SocketAddr {
+        self.relay_addr
+    }
+
+    /// The local address datagrams are sent from.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Sends a datagram to the destination, through the proxy. Returns the number of payload bytes sent.
+    pub async fn send_to<A>(
+        &self,
+        buf: &[u8],
+        destination: A,
+    ) -> Result<usize>
+    where
+        A: TryInto<Address, Error = anyhow::Error>,
+    {
+        let datagram = encode_datagram(&destination.try_into()?, buf);
+        self.socket.send_to(&datagram, self.relay_addr).await?;
+
+        Ok(buf.len())
+    }
+
+    /// Receives a datagram relayed by the proxy. Returns the number of payload bytes, and the sender.
+    pub async fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> Result<(usize, Address)> {
+        let mut datagram = vec![0; MAX_DATAGRAM_SIZE];
+
+        loop {
+            let (length, from) = self.socket.recv_from(&mut datagram).await?;
+            if from != self.relay_addr {
+                continue;
+            }
+
+            let (source, payload) = decode_datagram(&datagram[..length]).await?;
+
+            let length = payload.len().min(buf.len());
+            buf[..length].copy_from_slice(&payload[..length]);
+
+            return Ok((length, source));
+        }
+    }
+}
+
+/// Prepends the UDP request header (RSV, FRAG, ATYP, DST.ADDR, DST.PORT) to a payload.
+pub(crate) fn encode_datagram(
+    address: &Address,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut datagram = vec![SOCKS_RSV, SOCKS_RSV, SOCKS_UDP_FRAG_NONE];
+    datagram.extend(address.as_socks_bytes());
+    datagram.extend(payload);
+
+    datagram
+}
+
+/// Splits a datagram into the address from its UDP request header, and the payload.
+pub(crate) async fn decode_datagram(datagram: &[u8]) -> Result<(Address, &[u8])> {
+    ensure!(datagram.len() > 3, "Datagram is too short to hold a UDP request header.");
+
+    // Fragmentation is not supported, such datagrams MUST be dropped.
+    let fragment = datagram[2];
+    ensure!(fragment == SOCKS_UDP_FRAG_NONE, "Fragmented datagrams are not supported.");
+
+    let mut remaining = &datagram[3..];
+    let address = addresses::read_address(&mut remaining).await?;
+
+    Ok((address, remaining))
+}
+
+/// Relays datagrams between the client and the destinations it addresses. Datagrams
+/// from the client are unwrapped and forwarded, unless the access control rules deny
+/// their destination, all other datagrams are wrapped and sent back to the client.
+pub(crate) async fn relay(
+    socket: &UdpSocket,
+    client_ip: IpAddr,
+    client_addr: Option<SocketAddr>,
+    access_control: Option<&AccessControl>,
+    user: Option<&str>,
+) -> Result<()> {
+    let mut client_addr = client_addr;
+    let mut datagram = vec![0; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let (length, from) = socket.recv_from(&mut datagram).await?;
+
+        let from_client = match client_addr {
+            Some(client_addr) => from == client_addr,
+            None => from.ip() == client_ip,
+        };
+
+        if from_client {
+            client_addr = Some(from);
+
+            let (destination, payload) = match decode_datagram(&datagram[..length]).await {
+                Ok(decoded) => decoded,
+                Err(error) => {
+                    debug!("Dropping datagram from {}: {}", from, error);
+                    continue;
+                }
+            };
+
+            if let Some(access_control) = access_control {
+                let request = AccessRequest {
+                    source: client_ip,
+                    user,
+                    destination: Some(&destination),
+                    command: Command::UdpAssociate,
+                };
+                if let Err(error) = access_control.authorize(&request).await {
+                    debug!("Dropping datagram from {}: {}", from, error);
+                    continue;
+                }
+            }
+
+            // Delivery is best-effort, a single undeliverable datagram shouldn't end the association.
+            let sent = match crate::resolve_addr(destination.to_string()).await {
+                Ok(resolved) => socket.send_to(payload, resolved).await.map_err(anyhow::Error::from),
+                Err(error) => Err(error),
+            };
+
+            if let Err(error) = sent {
+                debug!("Dropping datagram for {}: {}", destination.to_string(), error);
+            }
+        } else if let Some(client_addr) = client_addr {
+            let reply = encode_datagram(&Address::Ip(from), &datagram[..length]);
+            socket.send_to(&reply, client_addr).await?;
+        }
+    }
+}
+
+/// Waits until the client closes the TCP connection that controls the association.
+pub(crate) async fn wait_for_close<S>(control: &mut S) -> Result<()>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buffer = [0; 512];
+    while control.read(&mut buffer).await? > 0 {}
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    pub async fn roundtrip_header() -> Result<()> {
+        let address = Address::new("example.com", 53);
+        let datagram = encode_datagram(&address, b"payload");
+
+        let (decoded, payload) = decode_datagram(&datagram).await?;
+        assert_eq!(decoded.to_string(), "example.com:53");
+        assert_eq!(payload, b"payload");
+
+        let mut fragmented = datagram.clone();
+        fragmented[2] = 0x01;
+        assert!(decode_datagram(&fragmented).await.is_err());
+
+        Ok(())
+    }
+}
@@ -1,5 +1,12 @@
-use crate::addresses::ProxyAddress;
+use crate::addresses::{Address, ProxyAddress};
+use crate::constants::*;
 use crate::socks6::options::{MetadataOption, SocksOption};
+use crate::{Error, Socks5Client, Socks6Client, SocksStream, TlsConnector};
+use anyhow::Result;
+use std::convert::TryFrom;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tracing::Instrument;
 
 #[derive(Clone, Debug)]
 pub struct SocksChain {
@@ -35,6 +42,11 @@ impl SocksChain {
         self.index + 1 < self.links.len()
     }
 
+    /// The links after the current one, i.e., the proxies a connect goes through.
+    pub fn remaining(&self) -> &[ProxyAddress] {
+        self.links.get(self.index + 1..).unwrap_or(&[])
+    }
+
     ///
     ///
     ///
@@ -67,15 +79,17 @@ impl SocksChain {
         }
     }
 
-    ///
-    ///
-    ///
+    /// The chain as metadata, for the current (SOCKS6) link to continue it. The links after the
+    /// current one carry their credentials, so that it can authenticate to them.
     pub fn as_options(&self) -> Vec<SocksOption> {
         let mut chain_options: Vec<SocksOption> = self
             .links
             .iter()
             .enumerate()
-            .map(|(i, c)| (i as u16, c.to_string()))
+            .map(|(i, c)| {
+                let link = if i > self.index { c.to_string_with_credentials() } else { c.to_string() };
+                (i as u16, link)
+            })
             .map(|(i, c)| MetadataOption::new(1000 + i, c).wrap())
             .collect();
 
@@ -84,6 +98,92 @@ impl SocksChain {
 
         chain_options
     }
+
+    /// Connects to the destination through the remaining links of the chain. SOCKS6 links
+    /// are chain-aware: they receive the chain as metadata, and continue the traversal. The
+    /// metadata can't cross a SOCKS5 link, so we tunnel through those ourselves, until the
+    /// destination or the next SOCKS6 link is reached. Connects to the destination directly,
+    /// if there are no remaining links. The initial data is sent as early as possible. The
+    /// handshake with each link is traced as a hop. TLS is started with the `+tls` links we
+    /// reach, verified by `tls`.
+    #[instrument(skip_all, fields(destination = %destination.to_string()))]
+    pub async fn connect(
+        &mut self,
+        destination: Address,
+        initial_data: &[u8],
+        tls: Option<&TlsConnector>,
+    ) -> Result<SocksStream> {
+        let mut link = match self.next_link() {
+            Some(link) => link.clone(),
+            None => {
+                let destination = crate::resolve_addr(destination.to_string()).await?;
+                let stream = crate::connect_with_initial_data(destination, initial_data).await?;
+                return Ok(stream.into());
+            }
+        };
+
+        let link_addr = format!("{}:{}", link.host, link.port);
+        let stream = TcpStream::connect(&link_addr)
+            .await
+            .map_err(|e| Error::connect(link_addr, e))?;
+        let mut stream = secure(&link, stream.into(), tls).await?;
+        loop {
+            let proxy_addr = format!("{}:{}", link.host, link.port);
+            let hop = info_span!("hop", index = self.index, link = %proxy_addr);
+
+            if link.socks_version == SOCKS_VER_6 {
+                // The link can pass the initial data on before its connect completes.
+                let initial_data = Some(initial_data.to_vec()).filter(|d| !d.is_empty());
+                let options = Some(self.as_options());
+
+                let client = Socks6Client::new(proxy_addr, link.credentials.clone()).await?;
+                client
+                    .handshake(destination.to_string(), initial_data, options, &mut stream)
+                    .instrument(hop)
+                    .await?;
+
+                return Ok(stream);
+            }
+
+            // Ask the SOCKS5 link to connect to the next link, or to the destination if it's the last.
+            let next = self.next_link().cloned();
+            let target = match &next {
+                Some(next) => Address::try_from(next)?,
+                None => destination.clone(),
+            };
+
+            let client = Socks5Client::new(proxy_addr, link.credentials.clone()).await?;
+            client.handshake(target.to_string(), &mut stream).instrument(hop).await?;
+
+            match next {
+                Some(next) => {
+                    // TLS with the next link is tunneled through the SOCKS5 link.
+                    stream = secure(&next, stream, tls).await?;
+                    link = next;
+                }
+                None => {
+                    stream.write_all(initial_data).await?;
+                    return Ok(stream);
+                }
+            }
+        }
+    }
+}
+
+/// Starts TLS with the link, if it asks for it.
+async fn secure(
+    link: &ProxyAddress,
+    stream: SocksStream,
+    tls: Option<&TlsConnector>,
+) -> Result<SocksStream> {
+    if !link.tls {
+        return Ok(stream);
+    }
+
+    match tls {
+        Some(tls) => tls.connect(&link.host, stream).await,
+        None => bail!(Error::Tls(format!("No TLS connector for link: {}.", link.to_string()))),
+    }
 }
 
 #[cfg(test)]
@@ -1,31 +1,31 @@
 use crate::addresses::{self, Address};
 use crate::socks6::options::{
-    AuthMethodAdvertisementOption, AuthMethodSelectionOption, MetadataOption, SocksOption, UnrecognizedOption,
+    AuthDataOption, AuthMethodAdvertisementOption, AuthMethodSelectionOption, IdempotenceOption, MetadataOption,
+    SessionOption, SocksOption, StackOption, UnrecognizedOption,
 };
+use crate::error::{Cause, Error};
 use crate::{constants::*, ProxyAddress};
 use anyhow::{ensure, Result};
 use num_traits::FromPrimitive;
 use std::collections::HashMap;
 use std::convert::TryInto;
+use std::io;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 pub mod chain;
 pub mod options;
 mod s6_client;
 mod s6_handler;
+mod s6_session;
+mod s6_stack;
+mod s6_trace;
+mod s6_udp;
 
 pub use chain::SocksChain;
+pub use options::AuthMethod;
 pub use s6_client::Socks6Client;
 pub use s6_handler::Socks6Handler;
-
-#[repr(u8)]
-#[derive(Clone, Debug, FromPrimitive)]
-pub enum AuthMethod {
-    NoAuthentication = 0x00,
-    Gssapi = 0x01,
-    UsernamePassword = 0x02,
-    NoAcceptableMethods = 0xFF,
-}
+pub use s6_udp::{Socks6Datagram, UdpError};
 
 #[repr(u8)]
 #[derive(Clone, Debug, FromPrimitive, PartialEq)]
@@ -104,15 +104,11 @@ impl Socks6Request {
     ///
     ///
     pub fn into_socks_bytes(self) -> Vec<u8> {
-        let mut data = vec![SOCKS_VER_6, SOCKS_CMD_CONNECT];
+        let mut data = vec![SOCKS_VER_6, self.command as u8];
         data.extend(self.destination.as_socks_bytes());
         data.push(SOCKS_PADDING);
 
-        let options_bytes: Vec<_> = self.options.into_iter().flat_map(|o| o.as_socks_bytes()).collect();
-        let options_bytes_length = (options_bytes.len() as u16).to_be_bytes();
-
-        data.extend(options_bytes_length.iter());
-        data.extend(options_bytes.iter());
+        data.extend(encode_options(self.options));
 
         data
     }
@@ -121,6 +117,7 @@ impl Socks6Request {
 ///
 ///
 ///
+#[instrument(skip_all)]
 pub async fn read_request<S>(stream: &mut S) -> Result<Socks6Request>
 where
     S: AsyncRead + Unpin,
@@ -132,8 +129,14 @@ where
     let [version, command] = request;
 
     // Validate the request.
-    ensure!(version == SOCKS_VER_6, "Version mismatch!");
-    ensure!(command == SOCKS_CMD_CONNECT, "Only COMMAND is supported!");
+    ensure!(
+        version == SOCKS_VER_6,
+        Error::Protocol(format!("Client uses a different SOCKS version: {}.", version))
+    );
+    ensure!(
+        Socks6Command::from_u8(command).is_some(),
+        Error::CommandNotSupported(format!("Client requested an unsupported command: {}.", command))
+    );
 
     let destination = addresses::read_address(stream).await?;
 
@@ -198,8 +201,20 @@ where
         stream.read_exact(&mut options_data).await?;
 
         let option = match kind {
-            0x0002 => AuthMethodAdvertisementOption::from_socks_bytes(options_data)?,
-            0x0003 => AuthMethodSelectionOption::from_socks_bytes(options_data)?,
+            SOCKS_OKIND_STACK => StackOption::from_socks_bytes(options_data)?,
+            SOCKS_OKIND_AUTH_METH_ADV => AuthMethodAdvertisementOption::from_socks_bytes(options_data)?,
+            SOCKS_OKIND_AUTH_METH_SEL => AuthMethodSelectionOption::from_socks_bytes(options_data)?,
+            SOCKS_OKIND_AUTH_DATA => AuthDataOption::from_socks_bytes(options_data)?,
+            SOCKS_OKIND_SESS_REQ
+            | SOCKS_OKIND_SESS_ID
+            | SOCKS_OKIND_SESS_OK
+            | SOCKS_OKIND_SESS_INV
+            | SOCKS_OKIND_SESS_TEARDOWN => SessionOption::from_socks_bytes(kind, options_data)?,
+            SOCKS_OKIND_IDEMP_REQ
+            | SOCKS_OKIND_IDEMP_WND
+            | SOCKS_OKIND_IDEMP_EXPENDITURE
+            | SOCKS_OKIND_IDEMP_ACCEPT
+            | SOCKS_OKIND_IDEMP_REJECT => IdempotenceOption::from_socks_bytes(kind, options_data)?,
             0xFDE8 => MetadataOption::from_socks_bytes(options_data)?,
             _ => UnrecognizedOption::new(kind, options_data.to_vec()).wrap(),
         };
@@ -211,58 +226,134 @@ where
     Ok(options)
 }
 
-pub async fn read_no_authentication<S>(stream: &mut S) -> Result<Vec<SocksOption>>
+/// Encodes the options, preceded by their total length.
+fn encode_options(options: Vec<SocksOption>) -> Vec<u8> {
+    let options_bytes: Vec<u8> = options.into_iter().flat_map(|o| o.as_socks_bytes()).collect();
+
+    let mut data = (options_bytes.len() as u16).to_be_bytes().to_vec();
+    data.extend(options_bytes);
+
+    data
+}
+
+/// Reads the authentication reply, and fails if the proxy didn't accept the authentication.
+/// Returns the options of the reply, e.g., the authentication method the proxy selected.
+///
+/// [socks6-draft11] https://tools.ietf.org/html/draft-olteanu-intarea-socks-6-11#section-7
+pub async fn read_authentication_reply<S>(stream: &mut S) -> Result<Vec<SocksOption>>
 where
     S: AsyncRead + Unpin,
 {
-    // Read auth reply
-    let mut reply = [0; 1];
+    let mut reply = [0; 2];
     stream.read_exact(&mut reply).await?;
 
-    let socks_version = reply[0];
+    let [socks_version, status] = reply;
     ensure!(
         socks_version == SOCKS_VER_6,
         "Proxy uses a different SOCKS version: {}",
         socks_version
     );
 
-    let mut reply = [0; 1];
-    stream.read_exact(&mut reply).await?;
+    let options = read_options(stream).await?;
 
-    let status = reply[0];
-    ensure!(
-        status == SOCKS_AUTH_SUCCESS,
-        "Authentication with proxy failed: {}",
-        status
-    );
+    if status != SOCKS_AUTH_SUCCESS {
+        for option in &options {
+            match option {
+                SocksOption::Session(SessionOption::Invalid) => {
+                    bail!(Error::Authentication(String::from("Session is invalid or has expired.")))
+                }
+                SocksOption::Idempotence(IdempotenceOption::Rejected) => {
+                    bail!(Error::Authentication(String::from("Idempotence token was rejected.")))
+                }
+                _ => {}
+            }
+        }
 
-    let options = read_options(stream).await?;
+        let selection = options.iter().find_map(|o| match o {
+            SocksOption::AuthMethodSelection(selection) => Some(selection.method.clone()),
+            _ => None,
+        });
+
+        let reason = match selection {
+            Some(AuthMethod::NoAcceptableMethods) => String::from("Proxy did not accept authentication method."),
+            Some(AuthMethod::UsernamePassword) => String::from("Authentication with the provided credentials failed."),
+            _ => format!("Authentication with proxy failed: {}", status),
+        };
+        bail!(Error::Authentication(reason))
+    }
 
     Ok(options)
 }
 
-pub async fn write_no_authentication<S>(stream: &mut S) -> Result<()>
+/// Writes the authentication reply, with the given options (e.g., the method selection).
+///
+/// [socks6-draft11] https://tools.ietf.org/html/draft-olteanu-intarea-socks-6-11#section-7
+pub async fn write_authentication_reply<S>(
+    stream: &mut S,
+    success: bool,
+    options: Vec<SocksOption>,
+) -> Result<()>
 where
     S: AsyncWrite + Unpin,
 {
-    // Write auth reply
-    let auth_reply = [SOCKS_VER_6, SOCKS_AUTH_SUCCESS, 0x00u8, 0x00u8];
-    stream.write(&auth_reply).await?;
+    let status = if success { SOCKS_AUTH_SUCCESS } else { SOCKS_AUTH_FAILED };
+
+    let mut auth_reply = vec![SOCKS_VER_6, status];
+    auth_reply.extend(encode_options(options));
+
+    stream.write_all(&auth_reply).await?;
 
     Ok(())
 }
 
+pub async fn write_no_authentication<S>(stream: &mut S) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    write_authentication_reply(stream, true, vec![]).await
+}
+
+/// Writes the initial data, which directly follows the request. The request's authentication
+/// method advertisement option MUST announce the length of the initial data.
 pub async fn write_initial_data<S>(
-    _stream: &mut S,
-    _request: &Socks6Request,
+    stream: &mut S,
+    initial_data: &[u8],
 ) -> Result<()>
 where
     S: AsyncWrite + Unpin,
 {
-    // Not yet implemented.
+    ensure!(
+        initial_data.len() <= SOCKS_MAX_INITIAL_DATA,
+        "Initial data MUST NOT be larger than {} bytes.",
+        SOCKS_MAX_INITIAL_DATA
+    );
+
+    stream.write_all(initial_data).await?;
+
     Ok(())
 }
 
+/// Reads the initial data that follows a request, as announced by the request.
+pub async fn read_initial_data<S>(
+    stream: &mut S,
+    request: &Socks6Request,
+) -> Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let length = request.initial_data_length as usize;
+    ensure!(
+        length <= SOCKS_MAX_INITIAL_DATA,
+        "Initial data MUST NOT be larger than {} bytes.",
+        SOCKS_MAX_INITIAL_DATA
+    );
+
+    let mut initial_data = vec![0; length];
+    stream.read_exact(&mut initial_data).await?;
+
+    Ok(initial_data)
+}
+
 #[repr(u8)]
 #[derive(Clone, Debug, FromPrimitive, PartialEq)]
 pub enum Socks6Reply {
@@ -278,32 +369,50 @@ pub enum Socks6Reply {
     ConnectionAttemptTimeOut = 0x09,
 }
 
+impl From<&anyhow::Error> for Socks6Reply {
+    /// Chooses the reply that tells the client why its request failed.
+    fn from(error: &anyhow::Error) -> Self {
+        let kind = match Error::cause(error) {
+            Cause::Socksx(Error::Connect { source, .. }) => source.kind(),
+            Cause::Socksx(Error::Resolve(_)) => return Socks6Reply::HostUnreachable,
+            Cause::Socksx(Error::CommandNotSupported(_)) => return Socks6Reply::CommandNotSupported,
+            Cause::Socksx(Error::NotAllowed(_)) => return Socks6Reply::ConnectionNotAllowed,
+            Cause::Socksx(Error::LimitExceeded(_)) => return Socks6Reply::ConnectionNotAllowed,
+            Cause::Socksx(Error::Refused(code)) => {
+                return Socks6Reply::from_u8(*code).unwrap_or(Socks6Reply::GeneralFailure)
+            }
+            Cause::Socksx(Error::Timeout(_)) => return Socks6Reply::ConnectionAttemptTimeOut,
+            Cause::Io(error) => error.kind(),
+            _ => return Socks6Reply::GeneralFailure,
+        };
+
+        match kind {
+            io::ErrorKind::ConnectionRefused => Socks6Reply::ConnectionRefused,
+            io::ErrorKind::HostUnreachable => Socks6Reply::HostUnreachable,
+            io::ErrorKind::NetworkUnreachable => Socks6Reply::NetworkUnreachable,
+            io::ErrorKind::TimedOut => Socks6Reply::ConnectionAttemptTimeOut,
+            _ => Socks6Reply::GeneralFailure,
+        }
+    }
+}
+
 ///
 ///
 ///
 pub async fn write_reply<S>(
     stream: &mut S,
     reply: Socks6Reply,
+    binding: &Address,
+    options: Vec<SocksOption>,
 ) -> Result<()>
 where
     S: AsyncWrite + Unpin,
 {
-    let reply = [
-        SOCKS_VER_6,
-        reply as u8,
-        SOCKS_PADDING,
-        SOCKS_ATYP_IPV4,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-    ];
-
-    stream.write(&reply).await?;
+    let mut data = vec![SOCKS_VER_6, reply as u8, SOCKS_PADDING];
+    data.extend(binding.as_socks_bytes());
+    data.extend(encode_options(options));
+
+    stream.write_all(&data).await?;
 
     Ok(())
 }
@@ -319,11 +428,7 @@ where
     stream.read_exact(&mut operation_reply).await?;
 
     let reply_code = operation_reply[1];
-    ensure!(
-        reply_code == SOCKS_REP_SUCCEEDED,
-        "CONNECT operation failed: {:?}",
-        Socks6Reply::from_u8(reply_code)
-    );
+    ensure!(reply_code == SOCKS_REP_SUCCEEDED, Error::Refused(reply_code));
 
     let binding = addresses::read_address(stream).await?;
     let options = read_options(stream).await?;
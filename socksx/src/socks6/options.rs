@@ -1,3 +1,4 @@
+use crate::{constants::*, Credentials};
 use anyhow::Result;
 use num_traits::FromPrimitive;
 
@@ -12,8 +13,12 @@ pub enum AuthMethod {
 
 #[derive(Clone, Debug)]
 pub enum SocksOption {
+    Stack(StackOption),
     AuthMethodAdvertisement(AuthMethodAdvertisementOption),
     AuthMethodSelection(AuthMethodSelectionOption),
+    AuthData(AuthDataOption),
+    Session(SessionOption),
+    Idempotence(IdempotenceOption),
     Metadata(MetadataOption),
     Unrecognized(UnrecognizedOption),
 }
@@ -23,14 +28,164 @@ impl SocksOption {
         use SocksOption::*;
 
         match self {
+            Stack(option) => option.clone().into_socks_bytes(),
             AuthMethodAdvertisement(option) => option.clone().into_socks_bytes(),
             AuthMethodSelection(option) => option.clone().into_socks_bytes(),
+            AuthData(option) => option.clone().into_socks_bytes(),
+            Session(option) => option.clone().into_socks_bytes(),
+            Idempotence(option) => option.clone().into_socks_bytes(),
             Metadata(option) => option.clone().into_socks_bytes(),
             Unrecognized(option) => option.clone().into_socks_bytes(),
         }
     }
 }
 
+/// The leg of the connection a stack option applies to.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StackLeg {
+    ClientProxy = 0x01,
+    ProxyRemote = 0x02,
+    Both = 0x03,
+}
+
+impl StackLeg {
+    pub fn from_u8(leg: u8) -> Option<Self> {
+        match leg {
+            0x01 => Some(StackLeg::ClientProxy),
+            0x02 => Some(StackLeg::ProxyRemote),
+            0x03 => Some(StackLeg::Both),
+            _ => None,
+        }
+    }
+}
+
+/// The protocol level a stack option applies to.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StackLevel {
+    Ip = 0x01,
+    Ipv4 = 0x02,
+    Ipv6 = 0x03,
+    Tcp = 0x04,
+    Udp = 0x05,
+}
+
+impl StackLevel {
+    pub fn from_u8(level: u8) -> Option<Self> {
+        match level {
+            0x01 => Some(StackLevel::Ip),
+            0x02 => Some(StackLevel::Ipv4),
+            0x03 => Some(StackLevel::Ipv6),
+            0x04 => Some(StackLevel::Tcp),
+            0x05 => Some(StackLevel::Udp),
+            _ => None,
+        }
+    }
+}
+
+/// The setting carried by a stack option, the level determines how the code is interpreted.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StackValue {
+    IpTos(u8),
+    HappyEyeballs(bool),
+    Ttl(u8),
+    NoFragmentation(bool),
+    TcpFastOpen(u16),
+    Mptcp(bool),
+    ListenBacklog(u16),
+}
+
+/// Asks the proxy to tune a protocol of the network stack, for one or both legs of the connection.
+///
+/// [socks6-draft11] https://tools.ietf.org/html/draft-olteanu-intarea-socks-6-11#section-8.1
+#[derive(Clone, Debug, PartialEq)]
+pub struct StackOption {
+    pub leg: StackLeg,
+    pub level: StackLevel,
+    pub value: StackValue,
+}
+
+impl StackOption {
+    pub fn new(
+        leg: StackLeg,
+        level: StackLevel,
+        value: StackValue,
+    ) -> Self {
+        Self { leg, level, value }
+    }
+
+    pub fn wrap(self) -> SocksOption {
+        SocksOption::Stack(self)
+    }
+
+    /// Tells whether the option applies to the proxy-remote leg.
+    pub fn applies_to_remote(&self) -> bool {
+        self.leg != StackLeg::ClientProxy
+    }
+
+    /// Parses the option, options with an unknown leg, level, or code are kept as unrecognized.
+    pub fn from_socks_bytes(bytes: Vec<u8>) -> Result<SocksOption> {
+        ensure!(bytes.len() >= 4, "Expected at least four bytes, got: {}", bytes.len());
+
+        let leg = StackLeg::from_u8(bytes[0] >> 6);
+        let level = StackLevel::from_u8(bytes[0] & 0x3F);
+        let (leg, level) = match (leg, level) {
+            (Some(leg), Some(level)) => (leg, level),
+            _ => return Ok(UnrecognizedOption::new(SOCKS_OKIND_STACK, bytes).wrap()),
+        };
+
+        let code = bytes[1];
+        let value_u8 = bytes[2];
+        let value_u16 = ((bytes[2] as u16) << 8) | bytes[3] as u16;
+        let available = value_u8 == SOCKS_STACK_AVAILABLE;
+
+        use StackLevel::*;
+        let value = match (level, code) {
+            (Ip | Ipv4 | Ipv6, 0x01) => StackValue::IpTos(value_u8),
+            (Ip | Ipv4 | Ipv6, 0x02) => StackValue::HappyEyeballs(available),
+            (Ip | Ipv4 | Ipv6, 0x03) => StackValue::Ttl(value_u8),
+            (Ip | Ipv4 | Ipv6, 0x04) => StackValue::NoFragmentation(available),
+            (Tcp, 0x01) => StackValue::TcpFastOpen(value_u16),
+            (Tcp, 0x02) => StackValue::Mptcp(available),
+            (Tcp, 0x03) => StackValue::ListenBacklog(value_u16),
+            _ => return Ok(UnrecognizedOption::new(SOCKS_OKIND_STACK, bytes).wrap()),
+        };
+
+        Ok(Self::new(leg, level, value).wrap())
+    }
+
+    /// Encodes the option, the leg and level share the first byte.
+    pub fn into_socks_bytes(self) -> Vec<u8> {
+        let availability = |available| {
+            if available {
+                SOCKS_STACK_AVAILABLE
+            } else {
+                SOCKS_STACK_UNAVAILABLE
+            }
+        };
+
+        let mut data = vec![((self.leg as u8) << 6) | self.level as u8];
+        match self.value {
+            StackValue::IpTos(tos) => data.extend([0x01, tos]),
+            StackValue::HappyEyeballs(available) => data.extend([0x02, availability(available)]),
+            StackValue::Ttl(ttl) => data.extend([0x03, ttl]),
+            StackValue::NoFragmentation(available) => data.extend([0x04, availability(available)]),
+            StackValue::TcpFastOpen(payload_size) => {
+                data.push(0x01);
+                data.extend(payload_size.to_be_bytes());
+            }
+            StackValue::Mptcp(available) => data.extend([0x02, availability(available)]),
+            StackValue::ListenBacklog(backlog) => {
+                data.push(0x03);
+                data.extend(backlog.to_be_bytes());
+            }
+        }
+
+        combine_and_pad(SOCKS_OKIND_STACK, data)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct AuthMethodAdvertisementOption {
     pub initial_data_length: u16,
@@ -80,7 +235,7 @@ impl AuthMethodAdvertisementOption {
         let mut data = self.initial_data_length.to_be_bytes().to_vec();
         data.extend(self.methods.iter().cloned().map(|m| m as u8));
 
-        combine_and_pad(0x02, data)
+        combine_and_pad(SOCKS_OKIND_AUTH_METH_ADV, data)
     }
 }
 
@@ -112,7 +267,197 @@ impl AuthMethodSelectionOption {
     pub fn into_socks_bytes(self) -> Vec<u8> {
         let data = vec![self.method as u8];
 
-        combine_and_pad(0x03, data)
+        combine_and_pad(SOCKS_OKIND_AUTH_METH_SEL, data)
+    }
+}
+
+/// Carries the data for an authentication method, e.g., the username and password.
+///
+/// [socks6-draft11] https://tools.ietf.org/html/draft-olteanu-intarea-socks-6-11#section-7.3
+#[derive(Clone, Debug)]
+pub struct AuthDataOption {
+    pub method: AuthMethod,
+    pub data: Vec<u8>,
+}
+
+impl AuthDataOption {
+    pub fn new(
+        method: AuthMethod,
+        data: Vec<u8>,
+    ) -> Self {
+        Self { method, data }
+    }
+
+    /// Creates the option for username/password authentication, formatted as in RFC 1929.
+    pub fn username_password(credentials: &Credentials) -> Self {
+        let mut data = vec![SOCKS_AUTH_VER];
+        data.extend(credentials.as_socks_bytes());
+
+        Self::new(AuthMethod::UsernamePassword, data)
+    }
+
+    pub fn wrap(self) -> SocksOption {
+        SocksOption::AuthData(self)
+    }
+
+    /// Extracts the username and password, ignoring any trailing padding.
+    pub fn credentials(&self) -> Result<Credentials> {
+        ensure!(
+            matches!(self.method, AuthMethod::UsernamePassword),
+            "Not username/password authentication data: {:?}",
+            self.method
+        );
+
+        let data = &self.data;
+        ensure!(
+            data.len() >= 2 && data[0] == SOCKS_AUTH_VER,
+            "Not valid username/password authentication data."
+        );
+
+        let ulen = data[1] as usize;
+        ensure!(data.len() >= 3 + ulen, "Username/password authentication data is truncated.");
+        let username = data[2..2 + ulen].to_vec();
+
+        let plen = data[2 + ulen] as usize;
+        ensure!(
+            data.len() >= 3 + ulen + plen,
+            "Username/password authentication data is truncated."
+        );
+        let password = data[3 + ulen..3 + ulen + plen].to_vec();
+
+        Ok(Credentials::new(username, password))
+    }
+
+    pub fn from_socks_bytes(bytes: Vec<u8>) -> Result<SocksOption> {
+        ensure!(!bytes.is_empty(), "Expected at least one byte, got: {}", bytes.len());
+
+        let method = bytes[0];
+        if let Some(method) = AuthMethod::from_u8(method) {
+            Ok(Self::new(method, bytes[1..].to_vec()).wrap())
+        } else {
+            bail!("Not a valid authentication method: {}", method)
+        }
+    }
+
+    pub fn into_socks_bytes(self) -> Vec<u8> {
+        let mut data = vec![self.method as u8];
+        data.extend(self.data);
+
+        combine_and_pad(SOCKS_OKIND_AUTH_DATA, data)
+    }
+}
+
+/// Lets clients reuse their authentication across requests, by referring to a session.
+///
+/// [socks6-draft11] https://tools.ietf.org/html/draft-olteanu-intarea-socks-6-11#section-8.4
+#[derive(Clone, Debug, PartialEq)]
+pub enum SessionOption {
+    Request,
+    Id(Vec<u8>),
+    Ok,
+    Invalid,
+    Teardown,
+}
+
+impl SessionOption {
+    pub fn wrap(self) -> SocksOption {
+        SocksOption::Session(self)
+    }
+
+    pub fn from_socks_bytes(
+        kind: u16,
+        bytes: Vec<u8>,
+    ) -> Result<SocksOption> {
+        let option = match kind {
+            SOCKS_OKIND_SESS_REQ => SessionOption::Request,
+            SOCKS_OKIND_SESS_ID => {
+                ensure!(!bytes.is_empty(), "Session ID MUST NOT be empty.");
+                SessionOption::Id(bytes)
+            }
+            SOCKS_OKIND_SESS_OK => SessionOption::Ok,
+            SOCKS_OKIND_SESS_INV => SessionOption::Invalid,
+            SOCKS_OKIND_SESS_TEARDOWN => SessionOption::Teardown,
+            _ => bail!("Not a session option: {}", kind),
+        };
+
+        Ok(option.wrap())
+    }
+
+    pub fn into_socks_bytes(self) -> Vec<u8> {
+        match self {
+            SessionOption::Request => combine_and_pad(SOCKS_OKIND_SESS_REQ, vec![]),
+            SessionOption::Id(id) => combine_and_pad(SOCKS_OKIND_SESS_ID, id),
+            SessionOption::Ok => combine_and_pad(SOCKS_OKIND_SESS_OK, vec![]),
+            SessionOption::Invalid => combine_and_pad(SOCKS_OKIND_SESS_INV, vec![]),
+            SessionOption::Teardown => combine_and_pad(SOCKS_OKIND_SESS_TEARDOWN, vec![]),
+        }
+    }
+}
+
+/// Protects requests (e.g., those with initial data) against replay, with single-use tokens
+/// from a window that the proxy assigns to a session.
+///
+/// [socks6-draft11] https://tools.ietf.org/html/draft-olteanu-intarea-socks-6-11#section-8.5
+#[derive(Clone, Debug, PartialEq)]
+pub enum IdempotenceOption {
+    Request { window_size: u32 },
+    Window { base: u32, size: u32 },
+    Expenditure { token: u32 },
+    Accepted,
+    Rejected,
+}
+
+impl IdempotenceOption {
+    pub fn wrap(self) -> SocksOption {
+        SocksOption::Idempotence(self)
+    }
+
+    pub fn from_socks_bytes(
+        kind: u16,
+        bytes: Vec<u8>,
+    ) -> Result<SocksOption> {
+        let u32_at = |index: usize| -> Result<u32> {
+            ensure!(
+                bytes.len() >= index + 4,
+                "Expected at least {} bytes, got: {}",
+                index + 4,
+                bytes.len()
+            );
+            Ok(u32::from_be_bytes([bytes[index], bytes[index + 1], bytes[index + 2], bytes[index + 3]]))
+        };
+
+        let option = match kind {
+            SOCKS_OKIND_IDEMP_REQ => IdempotenceOption::Request { window_size: u32_at(0)? },
+            SOCKS_OKIND_IDEMP_WND => IdempotenceOption::Window {
+                base: u32_at(0)?,
+                size: u32_at(4)?,
+            },
+            SOCKS_OKIND_IDEMP_EXPENDITURE => IdempotenceOption::Expenditure { token: u32_at(0)? },
+            SOCKS_OKIND_IDEMP_ACCEPT => IdempotenceOption::Accepted,
+            SOCKS_OKIND_IDEMP_REJECT => IdempotenceOption::Rejected,
+            _ => bail!("Not an idempotence option: {}", kind),
+        };
+
+        Ok(option.wrap())
+    }
+
+    pub fn into_socks_bytes(self) -> Vec<u8> {
+        match self {
+            IdempotenceOption::Request { window_size } => {
+                combine_and_pad(SOCKS_OKIND_IDEMP_REQ, window_size.to_be_bytes().to_vec())
+            }
+            IdempotenceOption::Window { base, size } => {
+                let mut data = base.to_be_bytes().to_vec();
+                data.extend(size.to_be_bytes());
+
+                combine_and_pad(SOCKS_OKIND_IDEMP_WND, data)
+            }
+            IdempotenceOption::Expenditure { token } => {
+                combine_and_pad(SOCKS_OKIND_IDEMP_EXPENDITURE, token.to_be_bytes().to_vec())
+            }
+            IdempotenceOption::Accepted => combine_and_pad(SOCKS_OKIND_IDEMP_ACCEPT, vec![]),
+            IdempotenceOption::Rejected => combine_and_pad(SOCKS_OKIND_IDEMP_REJECT, vec![]),
+        }
     }
 }
 
@@ -190,7 +535,7 @@ fn combine_and_pad(
     // The total length of the option is the combined number of bytes of
     // the kind, length, and data fields, plus the number of padding bytes.
     let option_length = data.len() + 2 + 2;
-    let padding_bytes = vec![0; 4 - (option_length % 4)];
+    let padding_bytes = vec![0; (4 - (option_length % 4)) % 4];
     let total_length: u16 = (option_length + padding_bytes.len()) as u16;
 
     let mut bytes = vec![];
@@ -1,18 +1,52 @@
-use crate::socks6::{self, Socks6Request};
+use crate::socks6::s6_trace;
+use crate::socks6::s6_udp::{self, UdpMessage};
+use crate::socks6::{self, Socks6Datagram, Socks6Request};
 use crate::socks6::{
-    options::{AuthMethodAdvertisementOption, SocksOption},
+    options::{AuthDataOption, AuthMethodAdvertisementOption, IdempotenceOption, SessionOption, SocksOption},
     AuthMethod,
 };
-use crate::{constants::*, Address, Credentials};
+use crate::{constants::*, Address, Credentials, Error, SocksStream, TlsConnector};
 use anyhow::{ensure, Result};
+use futures::future::{BoxFuture, FutureExt};
+use std::sync::{Arc, Mutex};
 use std::{convert::TryInto, net::SocketAddr};
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
 
 #[derive(Clone)]
 pub struct Socks6Client {
     proxy_addr: SocketAddr,
+    proxy_host: String,
     credentials: Option<Credentials>,
+    session: Option<Arc<Mutex<ClientSession>>>,
+    tls: Option<TlsConnector>,
+}
+
+/// What the client remembers of its session with the proxy.
+#[derive(Debug, Default)]
+struct ClientSession {
+    id: Option<Vec<u8>>,
+    window_size: u32,
+    window: Option<(u32, u32)>,
+    spent: u32,
+}
+
+impl ClientSession {
+    /// Takes the next unspent token from the window, if any.
+    fn next_token(&mut self) -> Option<u32> {
+        let (base, size) = self.window?;
+        if self.spent < size {
+            self.spent += 1;
+            Some(base.wrapping_add(self.spent - 1))
+        } else {
+            None
+        }
+    }
+
+    /// Tells whether a (new) window should be requested.
+    fn needs_window(&self) -> bool {
+        self.window_size > 0 && self.window.map(|(_, size)| self.spent >= size).unwrap_or(true)
+    }
 }
 
 impl Socks6Client {
@@ -23,14 +57,68 @@ impl Socks6Client {
         proxy_addr: A,
         credentials: Option<Credentials>,
     ) -> Result<Self> {
+        let proxy_addr = proxy_addr.into();
+        let proxy_host = crate::util::host(&proxy_addr).to_string();
         let proxy_addr = crate::resolve_addr(proxy_addr).await?;
 
         Ok(Socks6Client {
             proxy_addr,
+            proxy_host,
             credentials,
+            session: None,
+            tls: None,
         })
     }
 
+    /// Opens a connection to the proxy, and starts TLS if required.
+    async fn connect_to_proxy(&self) -> Result<SocksStream> {
+        let stream = TcpStream::connect(&self.proxy_addr)
+            .await
+            .map_err(|e| Error::connect(self.proxy_addr, e))?;
+
+        match &self.tls {
+            Some(tls) => tls.connect(&self.proxy_host, stream.into()).await,
+            None => Ok(stream.into()),
+        }
+    }
+
+    /// Speaks TLS to the proxy, verified by `connector`. The certificate of the proxy must be
+    /// valid for the host this client was created with.
+    pub fn with_tls(
+        mut self,
+        connector: TlsConnector,
+    ) -> Self {
+        self.tls = Some(connector);
+        self
+    }
+
+    /// Asks the proxy for a session, and refers to it in subsequent requests instead of
+    /// authenticating again. Clones of this client share the session.
+    ///
+    /// [socks6-draft11] https://tools.ietf.org/html/draft-olteanu-intarea-socks-6-11#section-9.1
+    pub fn with_session(mut self) -> Self {
+        self.session.get_or_insert_with(Default::default);
+        self
+    }
+
+    /// Like `with_session`, but also asks for a window of `window_size` idempotence tokens. A
+    /// token is spent on every request with initial data, so that the proxy can reject replays.
+    ///
+    /// [socks6-draft11] https://tools.ietf.org/html/draft-olteanu-intarea-socks-6-11#section-9.2
+    pub fn with_idempotence(
+        mut self,
+        window_size: u32,
+    ) -> Self {
+        let session = self.session.get_or_insert_with(Default::default);
+        session.lock().unwrap().window_size = window_size;
+        self
+    }
+
+    /// The ID of the current session with the proxy, if any.
+    pub fn session_id(&self) -> Option<Vec<u8>> {
+        self.session.as_ref().and_then(|s| s.lock().unwrap().id.clone())
+    }
+
     ///
     ///
     ///
@@ -39,11 +127,11 @@ impl Socks6Client {
         destination: A,
         initial_data: Option<Vec<u8>>,
         options: Option<Vec<SocksOption>>,
-    ) -> Result<(TcpStream, Address)>
+    ) -> Result<(SocksStream, Address)>
     where
         A: TryInto<Address, Error = anyhow::Error>,
     {
-        let mut stream = TcpStream::connect(&self.proxy_addr).await?;
+        let mut stream = self.connect_to_proxy().await?;
         let binding = self.handshake(destination, initial_data, options, &mut stream).await?;
 
         Ok((stream, binding))
@@ -53,56 +141,236 @@ impl Socks6Client {
     /// ...
     /// ...
     /// [socks6-draft11] https://tools.ietf.org/html/draft-olteanu-intarea-socks-6-11
-    pub async fn handshake<A>(
+    pub async fn handshake<A, S>(
         &self,
         destination: A,
         initial_data: Option<Vec<u8>>,
         options: Option<Vec<SocksOption>>,
-        stream: &mut TcpStream,
+        stream: &mut S,
     ) -> Result<Address>
     where
         A: TryInto<Address, Error = anyhow::Error>,
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let destination = destination.try_into()?;
+        let (binding, _) = self
+            .request(SOCKS_CMD_CONNECT, destination, initial_data, options, stream)
+            .await?;
+
+        Ok(binding)
+    }
+
+    /// Asks the proxy to accept a single inbound connection, on the given address (unspecified
+    /// means any address, port zero means any port). Returns the address the proxy listens on,
+    /// and a future that resolves once a peer connected, to the stream and the peer's address.
+    /// The initial data is sent to the peer.
+    ///
+    /// [socks6-draft11] https://tools.ietf.org/html/draft-olteanu-intarea-socks-6-11#section-8.2
+    pub async fn bind<A>(
+        &self,
+        address: A,
+        initial_data: Option<Vec<u8>>,
+        options: Option<Vec<SocksOption>>,
+    ) -> Result<(Address, BoxFuture<'static, Result<(SocksStream, Address)>>)>
+    where
+        A: TryInto<Address, Error = anyhow::Error>,
+    {
+        let mut stream = self.connect_to_proxy().await?;
+        let (binding, _) = self
+            .request(SOCKS_CMD_BIND, address.try_into()?, initial_data, options, &mut stream)
+            .await?;
+
+        // The second reply arrives when the peer connects to the proxy.
+        let accepted = async move {
+            let (peer, _) = socks6::read_reply(&mut stream).await?;
+            Ok((stream, peer))
+        };
+
+        Ok((binding, accepted.boxed()))
+    }
+
+    /// Asks the proxy to relay UDP datagrams. The association lasts as long as the returned value.
+    ///
+    /// [socks6-draft11] https://tools.ietf.org/html/draft-olteanu-intarea-socks-6-11#section-8.3
+    pub async fn udp_associate(&self) -> Result<Socks6Datagram> {
+        let mut stream = self.connect_to_proxy().await?;
+        let (binding, _) = self
+            .request(SOCKS_CMD_UDP_ASSOCIATE, Address::unspecified(), None, None, &mut stream)
+            .await?;
+
+        // An unspecified relay address means the relay is at the proxy's address.
+        let relay_addr = match binding {
+            Address::Ip(addr) if addr.ip().is_unspecified() => SocketAddr::new(self.proxy_addr.ip(), addr.port()),
+            binding => crate::resolve_addr(binding.to_string()).await?,
+        };
+
+        // The proxy announces the ID of the association, it's part of every datagram.
+        let association = match s6_udp::read_message(&mut stream).await? {
+            UdpMessage::AssociationInit { association } => association,
+            message => bail!("Expected the association to be initialized, got: {:?}", message),
+        };
+
+        let socket = UdpSocket::bind(SocketAddr::new(stream.local_addr()?.ip(), 0)).await?;
+
+        Ok(Socks6Datagram::new(socket, relay_addr, association, stream))
+    }
+
+    /// Sends a NOOP request. This keeps the session alive, and requests new idempotence tokens if needed.
+    pub async fn noop(&self) -> Result<()> {
+        let mut stream = self.connect_to_proxy().await?;
+        self.request(SOCKS_CMD_NOOP, Address::unspecified(), None, None, &mut stream)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Tears down the session with the proxy, if there's one. Later requests start a new session.
+    pub async fn end_session(&self) -> Result<()> {
+        if self.session_id().is_none() {
+            return Ok(());
+        }
+
+        let options = vec![SessionOption::Teardown.wrap()];
+        let mut stream = self.connect_to_proxy().await?;
+        self.request(SOCKS_CMD_NOOP, Address::unspecified(), None, Some(options), &mut stream)
+            .await?;
+
+        if let Some(session) = &self.session {
+            let mut session = session.lock().unwrap();
+            *session = ClientSession {
+                window_size: session.window_size,
+                ..Default::default()
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Sends a request, and reads the authentication and operation replies. Returns the
+    /// address and options of the operation reply.
+    async fn request<S>(
+        &self,
+        command: u8,
+        destination: Address,
+        initial_data: Option<Vec<u8>>,
+        options: Option<Vec<SocksOption>>,
+        stream: &mut S,
+    ) -> Result<(Address, Vec<SocksOption>)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
     {
         if let Some(Credentials { username, password }) = &self.credentials {
-            ensure!(username.len() > 255, "Username MUST NOT be larger than 255 bytes.");
-            ensure!(password.len() > 255, "Password MUST NOT be larger than 255 bytes.");
+            ensure!(username.len() <= 255, "Username MUST NOT be larger than 255 bytes.");
+            ensure!(password.len() <= 255, "Password MUST NOT be larger than 255 bytes.");
         }
 
         // Prepare initial data.
         let initial_data = initial_data.unwrap_or_default();
         ensure!(
-            initial_data.len() <= 2 ^ 14,
-            "Initial data MUST NOT be larger than 16384 bytes."
+            initial_data.len() <= SOCKS_MAX_INITIAL_DATA,
+            "Initial data MUST NOT be larger than {} bytes.",
+            SOCKS_MAX_INITIAL_DATA
         );
         let initial_data_length = initial_data.len() as u16;
 
-        // Prepare SOCKS options.
+        // Prepare SOCKS options, credentials are sent along with the request unless a session is used.
+        let mut options = options.unwrap_or_default();
+        let session_id = self.prepare_session(&mut options, !initial_data.is_empty());
+
         let mut auth_methods = vec![];
-        if self.credentials.is_some() {
+        if let (Some(credentials), None) = (&self.credentials, &session_id) {
             auth_methods.push(AuthMethod::UsernamePassword);
+            options.push(AuthDataOption::username_password(credentials).wrap());
         }
 
-        let auth_methods_adv = AuthMethodAdvertisementOption::new(initial_data_length, vec![]);
-        let mut options = options.unwrap_or_default();
+        let auth_methods_adv = AuthMethodAdvertisementOption::new(initial_data_length, auth_methods);
         options.push(auth_methods_adv.wrap());
 
-        // Create SOCKS6 CONNECT request.
-        let request = Socks6Request::new(
-            SOCKS_CMD_CONNECT,
-            destination.try_into()?,
-            initial_data_length,
-            options,
-            None,
-        );
+        // The proxy continues the trace of the current span, if there is one.
+        options.extend(s6_trace::inject());
+
+        let request = Socks6Request::new(command, destination, initial_data_length, options, None);
 
-        // Send SOCKS request information.
+        // Send SOCKS request information, directly followed by the initial data.
         let request_bytes = request.into_socks_bytes();
-        stream.write(&request_bytes).await?;
+        stream.write_all(&request_bytes).await?;
+        socks6::write_initial_data(stream, &initial_data).await?;
 
         // Wait for authentication and operation reply.
-        let _ = socks6::read_no_authentication(stream).await?;
-        let (binding, _) = socks6::read_reply(stream).await?;
+        let auth_reply = socks6::read_authentication_reply(stream).await;
+        self.update_session(session_id.is_some(), auth_reply.as_deref().ok());
 
-        Ok(binding)
+        let _ = auth_reply?;
+
+        socks6::read_reply(stream).await
+    }
+
+    /// Adds the session and idempotence options to the request. Returns the ID of the session
+    /// the request refers to, if there's one already.
+    fn prepare_session(
+        &self,
+        options: &mut Vec<SocksOption>,
+        has_initial_data: bool,
+    ) -> Option<Vec<u8>> {
+        let mut session = self.session.as_ref()?.lock().unwrap();
+
+        match &session.id {
+            Some(id) => options.push(SessionOption::Id(id.clone()).wrap()),
+            None => options.push(SessionOption::Request.wrap()),
+        }
+
+        if has_initial_data {
+            if let Some(token) = session.next_token() {
+                options.push(IdempotenceOption::Expenditure { token }.wrap());
+            }
+        }
+
+        // There's no point in new tokens for a session that's about to end.
+        let teardown = options
+            .iter()
+            .any(|o| matches!(o, SocksOption::Session(SessionOption::Teardown)));
+        if session.needs_window() && !teardown {
+            let window_size = session.window_size;
+            options.push(IdempotenceOption::Request { window_size }.wrap());
+        }
+
+        session.id.clone()
+    }
+
+    /// Remembers the session and token window the proxy assigned. If the proxy refused a request
+    /// that referred to the session, the session is forgotten, and the next request authenticates.
+    fn update_session(
+        &self,
+        used_session: bool,
+        auth_reply: Option<&[SocksOption]>,
+    ) {
+        let mut session = match &self.session {
+            Some(session) => session.lock().unwrap(),
+            None => return,
+        };
+
+        let options = match auth_reply {
+            Some(options) => options,
+            None => {
+                if used_session {
+                    *session = ClientSession {
+                        window_size: session.window_size,
+                        ..Default::default()
+                    };
+                }
+                return;
+            }
+        };
+
+        for option in options {
+            match option {
+                SocksOption::Session(SessionOption::Id(id)) => session.id = Some(id.clone()),
+                SocksOption::Idempotence(IdempotenceOption::Window { base, size }) => {
+                    session.window = Some((*base, *size));
+                    session.spent = 0;
+                }
+                _ => {}
+            }
+        }
     }
 }
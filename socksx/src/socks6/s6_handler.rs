@@ -1,14 +1,34 @@
+use crate::acl::{AccessControl, AccessRequest, Command};
 use crate::addresses::ProxyAddress;
-use crate::socks6::{self, Socks6Reply};
-use crate::{Socks6Client, SocksHandler};
+use crate::limits::{ConnectionLimits, ConnectionPermit};
+use crate::metrics::{Hook, MetricsHook, Protocol};
+use crate::socks6::options::{
+    AuthMethodSelectionOption, IdempotenceOption, SessionOption, SocksOption, StackOption,
+};
+use crate::socks6::s6_session::SessionManager;
+use crate::socks6::{self, s6_stack, s6_trace, s6_udp, AuthMethod, Socks6Command, Socks6Reply, Socks6Request};
+use crate::timeouts::Timeouts;
+use crate::{Address, Authenticator, Error, SocksHandler, SocksStream, TlsConnector};
 use anyhow::Result;
 use async_trait::async_trait;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UdpSocket;
+use tracing::field::debug;
+use tracing::Span;
 
 #[derive(Clone)]
 pub struct Socks6Handler {
+    access_control: Option<Arc<AccessControl>>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    chain_tls: Option<TlsConnector>,
+    limits: Option<ConnectionLimits>,
+    metrics: Hook,
+    sessions: Arc<SessionManager>,
     static_links: Vec<ProxyAddress>,
+    timeouts: Timeouts,
 }
 
 impl Default for Socks6Handler {
@@ -22,8 +42,473 @@ impl Socks6Handler {
     ///
     ///
     pub fn new(static_links: Vec<ProxyAddress>) -> Self {
-        Socks6Handler { static_links }
+        Socks6Handler {
+            access_control: None,
+            authenticator: None,
+            chain_tls: None,
+            limits: None,
+            metrics: Hook::new(Protocol::Socks6),
+            sessions: Arc::new(SessionManager::default()),
+            static_links,
+            timeouts: Timeouts::default(),
+        }
+    }
+
+    /// Requires clients to authenticate with a username and password, checked by `authenticator`.
+    pub fn with_authenticator<A: Authenticator + 'static>(
+        mut self,
+        authenticator: A,
+    ) -> Self {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /// Consults `access_control` before acting on a request, denied requests are refused.
+    pub fn with_access_control(
+        mut self,
+        access_control: AccessControl,
+    ) -> Self {
+        self.access_control = Some(Arc::new(access_control));
+        self
+    }
+
+    /// Holds a slot of the per-user limit of `limits` while relaying for an authenticated client.
+    pub fn with_limits(
+        mut self,
+        limits: ConnectionLimits,
+    ) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    /// Reports the events of this handler, e.g., handshakes and bytes relayed, to `hook`. Call
+    /// this again to report to several hooks, e.g., metrics and an access log.
+    pub fn with_metrics<H: MetricsHook + 'static>(
+        mut self,
+        hook: H,
+    ) -> Self {
+        self.metrics = self.metrics.with_hook(Arc::new(hook));
+        self
+    }
+
+    /// Limits the time spent on the handshake, on connecting to the destination, and on idle relays.
+    pub fn with_timeouts(
+        mut self,
+        timeouts: Timeouts,
+    ) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Starts TLS with the `+tls` links of the chain, and verifies them with `connector`.
+    pub fn with_chain_tls(
+        mut self,
+        connector: TlsConnector,
+    ) -> Self {
+        self.chain_tls = Some(connector);
+        self
+    }
+
+    /// Authenticates the client with the data it included in the request, or by the session it
+    /// refers to, and sends the authentication reply. Session and idempotence options are handled
+    /// here too, as their replies are part of the authentication reply. Returns the identity of
+    /// the client, if it authenticated itself.
+    ///
+    /// [socks6-draft11] https://tools.ietf.org/html/draft-olteanu-intarea-socks-6-11#section-7
+    #[instrument(name = "auth", skip_all, fields(method))]
+    async fn authenticate(
+        &self,
+        source: &mut SocksStream,
+        request: &Socks6Request,
+    ) -> Result<Option<String>> {
+        let mut session_id = None;
+        let mut session_requested = false;
+        let mut teardown = false;
+        let mut window_size = None;
+        let mut token = None;
+        for option in &request.options {
+            match option {
+                SocksOption::Session(SessionOption::Request) => session_requested = true,
+                SocksOption::Session(SessionOption::Id(id)) => session_id = Some(id.clone()),
+                SocksOption::Session(SessionOption::Teardown) => teardown = true,
+                SocksOption::Idempotence(IdempotenceOption::Request { window_size: size }) => window_size = Some(*size),
+                SocksOption::Idempotence(IdempotenceOption::Expenditure { token: t }) => token = Some(*t),
+                _ => {}
+            }
+        }
+
+        let mut reply = vec![];
+        let identity = if let Some(id) = &session_id {
+            // Clients that refer to a session don't have to authenticate again.
+            match self.sessions.resume(id) {
+                Some(identity) => identity,
+                None => {
+                    let invalid = SessionOption::Invalid.wrap();
+                    socks6::write_authentication_reply(source, false, vec![invalid]).await?;
+                    bail!(Error::Authentication(String::from("Session is invalid or has expired.")));
+                }
+            }
+        } else {
+            let identity = self.verify_credentials(source, request, &mut reply).await?;
+            if session_requested {
+                session_id = Some(self.sessions.create(identity.clone())?);
+            }
+
+            identity
+        };
+
+        if let Some(id) = &session_id {
+            reply.push(SessionOption::Id(id.clone()).wrap());
+            reply.push(SessionOption::Ok.wrap());
+        }
+
+        // Tokens can only be spent within a session, and only once.
+        if let Some(token) = token {
+            let accepted = session_id.as_ref().map(|id| self.sessions.spend(id, token)).unwrap_or(false);
+            if !accepted {
+                reply.push(IdempotenceOption::Rejected.wrap());
+                socks6::write_authentication_reply(source, false, reply).await?;
+                bail!(Error::Authentication(format!("Idempotence token {} was rejected.", token)));
+            }
+
+            reply.push(IdempotenceOption::Accepted.wrap());
+        }
+
+        if let (Some(id), Some(window_size)) = (&session_id, window_size) {
+            if let Some((base, size)) = self.sessions.issue_window(id, window_size)? {
+                reply.push(IdempotenceOption::Window { base, size }.wrap());
+            }
+        }
+
+        if let (Some(id), true) = (&session_id, teardown) {
+            self.sessions.teardown(id);
+        }
+
+        socks6::write_authentication_reply(source, true, reply).await?;
+
+        Ok(identity)
+    }
+
+    /// Verifies the credentials included in the request, if authentication is required. The
+    /// method selection is added to the reply options, on failure the reply is sent right away.
+    /// Clients known by their TLS certificate don't have to include credentials.
+    async fn verify_credentials(
+        &self,
+        source: &mut SocksStream,
+        request: &Socks6Request,
+        reply: &mut Vec<SocksOption>,
+    ) -> Result<Option<String>> {
+        if let Some(identity) = source.identity() {
+            return Ok(Some(identity.to_string()));
+        }
+
+        let authenticator = match &self.authenticator {
+            Some(authenticator) => authenticator,
+            None => return Ok(None),
+        };
+
+        let mut advertised = false;
+        let mut auth_data = None;
+        for option in &request.options {
+            match option {
+                SocksOption::AuthMethodAdvertisement(advertisement) => {
+                    advertised = advertisement
+                        .methods
+                        .iter()
+                        .any(|m| matches!(m, AuthMethod::UsernamePassword));
+                }
+                SocksOption::AuthData(data) if matches!(data.method, AuthMethod::UsernamePassword) => {
+                    auth_data = Some(data);
+                }
+                _ => {}
+            }
+        }
+
+        let (method, identity) = match auth_data {
+            Some(auth_data) => {
+                let credentials = auth_data.credentials()?;
+                (AuthMethod::UsernamePassword, authenticator.authenticate(&credentials).await?)
+            }
+            None if advertised => (AuthMethod::UsernamePassword, None),
+            None => (AuthMethod::NoAcceptableMethods, None),
+        };
+
+        Span::current().record("method", debug(&method));
+        reply.push(AuthMethodSelectionOption::new(method).wrap());
+
+        match identity {
+            Some(identity) => Ok(Some(identity)),
+            None => {
+                socks6::write_authentication_reply(source, false, reply.clone()).await?;
+                bail!(Error::Authentication(String::from("Username/password authentication failed.")))
+            }
+        }
+    }
+
+    /// Handles the client, from its request until the relay, or the association, ends.
+    async fn serve(
+        &self,
+        source: &mut SocksStream,
+    ) -> Result<()> {
+        let handshake = self.timeouts.handshake(self.handshake(source)).await;
+        let (request, initial_data, identity) = self.metrics.handshake(handshake)?;
+        let command = format!("{:?}", request.command);
+        self.metrics.request(&command, &request.destination, identity.as_deref());
+
+        let _permit = self.acquire(source, identity.as_deref()).await?;
+
+        let metadata = request.metadata.clone();
+        let serve = async {
+            match request.command {
+                Socks6Command::UdpAssociate => self.udp_associate(source, identity.as_deref()).await,
+                Socks6Command::NoOp => self.noop(source).await,
+                _ => {
+                    let mut destination = self.execute(source, request, initial_data).await?;
+
+                    // Start bidirectional copy, after this the connection closes.
+                    self.timeouts.relay(source, &mut destination, |up, down| self.metrics.relayed(up, down)).await?;
+
+                    Ok(())
+                }
+            }
+        };
+
+        // Next hops, if any, are traced as part of the trace of the client.
+        s6_trace::continue_trace(&metadata, serve).await
     }
+
+    /// Reads the request and its initial data, and authenticates the client. Returns the
+    /// request, along with the initial data and the identity of the client.
+    async fn handshake(
+        &self,
+        source: &mut SocksStream,
+    ) -> Result<(Socks6Request, Vec<u8>, Option<String>)> {
+        let request = socks6::read_request(source).await?;
+        let initial_data = socks6::read_initial_data(source, &request).await?;
+        let identity = self.authenticate(source, &request).await?;
+
+        debug!(
+            "{:?} to {} for {}",
+            request.command,
+            request.destination.to_string(),
+            identity.as_deref().unwrap_or("anonymous")
+        );
+
+        // Consult the access control rules, before acting on the request. NOOP doesn't act.
+        if let Some(access_control) = &self.access_control {
+            let command = match request.command {
+                Socks6Command::NoOp => None,
+                Socks6Command::Connect => Some(Command::Connect),
+                Socks6Command::Bind => Some(Command::Bind),
+                Socks6Command::UdpAssociate => Some(Command::UdpAssociate),
+            };
+            if let Some(command) = command {
+                // The destinations of a UDP association are those of its datagrams.
+                let destination = Some(&request.destination).filter(|_| command != Command::UdpAssociate);
+                let access_request = AccessRequest {
+                    source: source.peer_addr()?.ip(),
+                    user: identity.as_deref(),
+                    destination,
+                    command,
+                };
+                if let Err(error) = access_control.authorize(&access_request).await {
+                    self.reply(source, Socks6Reply::from(&error), &Address::unspecified(), vec![]).await?;
+                    return Err(error);
+                }
+            }
+        }
+
+        Ok((request, initial_data, identity))
+    }
+
+    /// Takes a slot of the per-user limit for the identity of the client, if it has one. The
+    /// slot is released when the returned permit is dropped.
+    async fn acquire(
+        &self,
+        source: &mut SocksStream,
+        identity: Option<&str>,
+    ) -> Result<ConnectionPermit> {
+        let (limits, user) = match (&self.limits, identity) {
+            (Some(limits), Some(user)) => (limits, user),
+            _ => return Ok(ConnectionPermit::default()),
+        };
+
+        match limits.acquire_user(user).await {
+            Ok(permit) => Ok(permit),
+            Err(error) => {
+                self.reply(source, Socks6Reply::from(&error), &Address::unspecified(), vec![]).await?;
+                Err(error)
+            }
+        }
+    }
+
+    /// Executes a CONNECT or BIND request, and returns the stream to relay to.
+    async fn execute(
+        &self,
+        source: &mut SocksStream,
+        request: Socks6Request,
+        initial_data: Vec<u8>,
+    ) -> Result<SocksStream> {
+        match request.command {
+            Socks6Command::Connect => self.connect(source, request, initial_data).await,
+            Socks6Command::Bind => self.bind(source, request, initial_data).await,
+            _ => {
+                self.reply(source, Socks6Reply::CommandNotSupported, &Address::unspecified(), vec![]).await?;
+                let reason = format!("{:?} can't be set up as a TCP stream.", request.command);
+                bail!(Error::CommandNotSupported(reason))
+            }
+        }
+    }
+
+    /// Connects to the destination on behalf of the client, through the chain if there is one.
+    async fn connect(
+        &self,
+        source: &mut SocksStream,
+        request: Socks6Request,
+        initial_data: Vec<u8>,
+    ) -> Result<SocksStream> {
+        // The initial data is forwarded before the connect completes, where possible.
+        let chain = request.chain(&self.static_links)?;
+        if let Some(chain) = &chain {
+            Span::current().record("chain_index", chain.index);
+        }
+        let links = chain.as_ref().map(|chain| chain.remaining().to_vec()).unwrap_or_default();
+        let start = Instant::now();
+        let connected = self
+            .timeouts
+            .connect(async {
+                if let Some(mut chain) = chain {
+                    let tls = self.chain_tls.as_ref();
+                    let destination = chain.connect(request.destination.clone(), &initial_data, tls).await?;
+                    Ok((destination, vec![]))
+                } else {
+                    let options = stack_options(&request);
+                    let connect = s6_stack::connect(&request.destination, &initial_data, &options);
+                    let (destination, options) = connect.await?;
+                    Ok((destination.into(), options))
+                }
+            })
+            .await;
+
+        let (destination, stack_options) = match connected {
+            Ok(connected) => connected,
+            Err(error) => {
+                // Tell the client why, before closing the connection.
+                let reply = Socks6Reply::from(&error);
+                self.reply(source, reply, &Address::unspecified(), vec![]).await?;
+                return Err(error);
+            }
+        };
+        self.metrics.connected(start.elapsed(), &links, &destination);
+
+        // Notify source that the connection has been set up, from which address, and which stack options were applied.
+        let binding = Address::Ip(destination.local_addr()?);
+        let options = stack_options.into_iter().map(StackOption::wrap).collect();
+        self.reply(source, Socks6Reply::Success, &binding, options).await?;
+        source.flush().await?;
+
+        Ok(destination)
+    }
+
+    /// Accepts a single inbound connection on behalf of the client, on the requested address
+    /// (or on the interface the client reached us on, if unspecified). The first reply tells the
+    /// client where the proxy listens, the second reply who connected. The initial data is sent
+    /// to whoever connected.
+    ///
+    /// [socks6-draft11] https://tools.ietf.org/html/draft-olteanu-intarea-socks-6-11#section-8.2
+    async fn bind(
+        &self,
+        source: &mut SocksStream,
+        request: Socks6Request,
+        initial_data: Vec<u8>,
+    ) -> Result<SocksStream> {
+        let address = match &request.destination {
+            Address::Ip(address) if !address.ip().is_unspecified() => *address,
+            destination => SocketAddr::new(source.local_addr()?.ip(), destination.port()),
+        };
+
+        let (listener, stack_options) = s6_stack::listen(address, &stack_options(&request))?;
+        let binding = Address::Ip(listener.local_addr()?);
+
+        let options = stack_options.into_iter().map(StackOption::wrap).collect();
+        self.reply(source, Socks6Reply::Success, &binding, options).await?;
+        source.flush().await?;
+
+        let accepted = self.timeouts.connect(async { Ok(listener.accept().await?) }).await;
+        let (mut incoming, peer_addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                // Tell the client, before closing the connection.
+                self.reply(source, Socks6Reply::from(&error), &Address::unspecified(), vec![]).await?;
+                return Err(error);
+            }
+        };
+        incoming.write_all(&initial_data).await?;
+
+        // Notify source that the inbound connection has been established.
+        self.reply(source, Socks6Reply::Success, &Address::Ip(peer_addr), vec![]).await?;
+        source.flush().await?;
+
+        Ok(incoming.into())
+    }
+
+    /// Relays UDP datagrams for the client, until it closes the TCP connection.
+    ///
+    /// [socks6-draft11] https://tools.ietf.org/html/draft-olteanu-intarea-socks-6-11#section-8.3
+    async fn udp_associate(
+        &self,
+        source: &mut SocksStream,
+        identity: Option<&str>,
+    ) -> Result<()> {
+        let socket = UdpSocket::bind(SocketAddr::new(source.local_addr()?.ip(), 0)).await?;
+        let binding = Address::Ip(socket.local_addr()?);
+
+        let mut association = [0; 8];
+        getrandom::getrandom(&mut association)?;
+        let association = u64::from_be_bytes(association);
+
+        self.reply(source, Socks6Reply::Success, &binding, vec![]).await?;
+
+        let client_ip = source.peer_addr()?.ip();
+        let access_control = self.access_control.as_deref();
+        s6_udp::relay(&socket, source, association, client_ip, access_control, identity).await
+    }
+
+    /// Does nothing, besides what the authentication reply already covered: refreshing the
+    /// session, or handing out idempotence tokens.
+    async fn noop(
+        &self,
+        source: &mut SocksStream,
+    ) -> Result<()> {
+        self.reply(source, Socks6Reply::Success, &Address::unspecified(), vec![]).await?;
+        source.flush().await?;
+
+        Ok(())
+    }
+
+    /// Sends a reply to the client, and reports it.
+    async fn reply(
+        &self,
+        source: &mut SocksStream,
+        reply: Socks6Reply,
+        binding: &Address,
+        options: Vec<SocksOption>,
+    ) -> Result<()> {
+        self.metrics.reply(reply.clone() as u16);
+        socks6::write_reply(source, reply, binding, options).await
+    }
+}
+
+/// The stack options of the request.
+fn stack_options(request: &Socks6Request) -> Vec<StackOption> {
+    request
+        .options
+        .iter()
+        .filter_map(|o| match o {
+            SocksOption::Stack(stack) => Some(stack.clone()),
+            _ => None,
+        })
+        .collect()
 }
 
 #[async_trait]
@@ -33,14 +518,10 @@ impl SocksHandler for Socks6Handler {
     ///
     async fn accept_request(
         &self,
-        source: &mut TcpStream,
+        source: &mut SocksStream,
     ) -> Result<()> {
-        let mut destination = self.setup(source).await?;
-
-        // Start bidirectional copy, after this the connection closes.
-        tokio::io::copy_bidirectional(source, &mut destination).await?;
-
-        Ok(())
+        let client = source.peer_addr()?;
+        self.metrics.scope(client, self.serve(source)).await
     }
 
     ///
@@ -48,12 +529,13 @@ impl SocksHandler for Socks6Handler {
     ///
     async fn refuse_request(
         &self,
-        source: &mut TcpStream,
+        source: &mut SocksStream,
     ) -> Result<()> {
         // Notify source that the connection is refused.
-        socks6::write_reply(source, Socks6Reply::ConnectionRefused).await?;
-
-        Ok(())
+        let client = source.peer_addr()?;
+        let binding = Address::unspecified();
+        let reply = self.reply(source, Socks6Reply::ConnectionRefused, &binding, vec![]);
+        self.metrics.scope(client, reply).await
     }
 
     ///
@@ -61,42 +543,422 @@ impl SocksHandler for Socks6Handler {
     ///
     async fn setup(
         &self,
-        source: &mut TcpStream,
-    ) -> Result<TcpStream> {
-        // Receive SOCKS request, and allow unauthenticated access.
-        let request = socks6::read_request(source).await?;
-        socks6::write_no_authentication(source).await?;
+        source: &mut SocksStream,
+    ) -> Result<SocksStream> {
+        let handshake = self.timeouts.handshake(self.handshake(source)).await;
+        let (request, initial_data, _) = self.metrics.handshake(handshake)?;
 
-        let destination = request.destination.to_string();
-        let chain = request.chain(&self.static_links)?;
+        self.execute(source, request, initial_data).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl::Rule;
+    use crate::auth::StaticAuthenticator;
+    use crate::constants::SOCKS_MAX_INITIAL_DATA;
+    use crate::tls::test_pki;
+    use crate::util::{spawn_proxy, spawn_tls_proxy};
+    use crate::{Credentials, Socks5Handler, Socks6Client};
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+    #[tokio::test]
+    pub async fn authenticate() -> Result<()> {
+        let users = vec![("alice", "secret")].into_iter().collect();
+        let handler = Socks6Handler::default().with_authenticator(StaticAuthenticator::new(users));
+        let proxy_addr = spawn_proxy(handler).await?;
+
+        let target = TcpListener::bind("127.0.0.1:0").await?;
+        let destination = target.local_addr()?.to_string();
+
+        let client = Socks6Client::new(proxy_addr.to_string(), Some(Credentials::new("alice", "guess"))).await?;
+        assert!(client.connect(destination.clone(), None, None).await.is_err());
+
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        assert!(client.connect(destination.clone(), None, None).await.is_err());
+
+        let client = Socks6Client::new(proxy_addr.to_string(), Some(Credentials::new("alice", "secret"))).await?;
+        assert!(client.connect(destination, None, None).await.is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn initial_data() -> Result<()> {
+        let link_addr = spawn_proxy(Socks6Handler::default()).await?;
+        let links = vec![ProxyAddress::new(6, link_addr.ip().to_string(), link_addr.port(), None)];
+        let proxy_addr = spawn_proxy(Socks6Handler::new(links)).await?;
+
+        let target = TcpListener::bind("127.0.0.1:0").await?;
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+
+        let initial_data = Some(b"hello".to_vec());
+        let (mut outgoing, _) = client.connect(target.local_addr()?.to_string(), initial_data, None).await?;
+
+        let (mut incoming, _) = target.accept().await?;
+        outgoing.write_all(b", world").await?;
+        let mut buffer = [0; 12];
+        incoming.read_exact(&mut buffer).await?;
+        assert_eq!(&buffer, b"hello, world");
+
+        let oversized = Some(vec![0; SOCKS_MAX_INITIAL_DATA + 1]);
+        assert!(client.connect(target.local_addr()?.to_string(), oversized, None).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn binding() -> Result<()> {
+        let proxy_addr = spawn_proxy(Socks6Handler::default()).await?;
+        let target = TcpListener::bind("127.0.0.1:0").await?;
+
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        let (_, binding) = client.connect(target.local_addr()?.to_string(), None, None).await?;
+
+        // The proxy reports the address it connected from.
+        let (_, peer_addr) = target.accept().await?;
+        assert_eq!(binding, Address::Ip(peer_addr));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn mixed_chain() -> Result<()> {
+        let last_addr = spawn_proxy(Socks5Handler::default()).await?;
+        let third_addr = spawn_proxy(Socks6Handler::default()).await?;
+        let second_addr = spawn_proxy(Socks5Handler::default()).await?;
+
+        // The first link (SOCKS5) can't carry the chain, so the third link (SOCKS6) is reached by tunneling.
+        let links = vec![
+            ProxyAddress::new(5, second_addr.ip().to_string(), second_addr.port(), None),
+            ProxyAddress::new(6, third_addr.ip().to_string(), third_addr.port(), None),
+            ProxyAddress::new(5, last_addr.ip().to_string(), last_addr.port(), None),
+        ];
+        let proxy_addr = spawn_proxy(Socks6Handler::new(links)).await?;
+
+        let target = TcpListener::bind("127.0.0.1:0").await?;
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        let (mut outgoing, _) = client.connect(target.local_addr()?.to_string(), None, None).await?;
+
+        let (mut incoming, _) = target.accept().await?;
+        outgoing.write_all(b"ping").await?;
+        let mut buffer = [0; 4];
+        incoming.read_exact(&mut buffer).await?;
+        assert_eq!(&buffer, b"ping");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn authenticated_chain() -> Result<()> {
+        let users = vec![("alice", "p@ss:word")].into_iter().collect();
+        let last = Socks5Handler::default().with_authenticator(StaticAuthenticator::new(users));
+        let last_addr = spawn_proxy(last).await?;
+        let second_addr = spawn_proxy(Socks6Handler::default()).await?;
+
+        // The second link (SOCKS6) continues the chain, with the credentials of the last link.
+        let credentials = Some(Credentials::new("alice", "p@ss:word"));
+        let links = vec![
+            ProxyAddress::new(6, second_addr.ip().to_string(), second_addr.port(), None),
+            ProxyAddress::new(5, last_addr.ip().to_string(), last_addr.port(), credentials),
+        ];
+        let proxy_addr = spawn_proxy(Socks6Handler::new(links)).await?;
+
+        let target = TcpListener::bind("127.0.0.1:0").await?;
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        let (mut outgoing, _) = client.connect(target.local_addr()?.to_string(), None, None).await?;
+
+        let (mut incoming, _) = target.accept().await?;
+        outgoing.write_all(b"ping").await?;
+        let mut buffer = [0; 4];
+        incoming.read_exact(&mut buffer).await?;
+        assert_eq!(&buffer, b"ping");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn tls_chain() -> Result<()> {
+        let (acceptor, connector, _) = test_pki()?;
+        let users = vec![("alice", "secret")].into_iter().collect();
+        let link = Socks6Handler::default().with_authenticator(StaticAuthenticator::new(users));
+        let link_addr = spawn_tls_proxy(link, acceptor).await?;
+        let links = vec![ProxyAddress::new(6, String::from("localhost"), link_addr.port(), None).with_tls()];
+        let target = TcpListener::bind("127.0.0.1:0").await?;
+
+        // The link only accepts TLS, and knows the proxy by its client certificate.
+        let proxy_addr = spawn_proxy(Socks6Handler::new(links.clone()).with_chain_tls(connector)).await?;
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        let (mut outgoing, _) = client.connect(target.local_addr()?.to_string(), None, None).await?;
+
+        let (mut incoming, _) = target.accept().await?;
+        outgoing.write_all(b"ping").await?;
+        let mut buffer = [0; 4];
+        incoming.read_exact(&mut buffer).await?;
+        assert_eq!(&buffer, b"ping");
+
+        let proxy_addr = spawn_proxy(Socks6Handler::new(links)).await?;
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        assert!(client.connect(target.local_addr()?.to_string(), None, None).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn stack_options() -> Result<()> {
+        use crate::socks6::options::{StackLeg, StackLevel, StackValue};
+        use crate::Address;
+        use std::convert::TryFrom;
+
+        let proxy_addr = spawn_proxy(Socks6Handler::default()).await?;
+        let target = TcpListener::bind("127.0.0.1:0").await?;
+
+        // Only options that apply to the proxy-remote leg, and are supported for CONNECT, are accepted.
+        let ttl = StackOption::new(StackLeg::Both, StackLevel::Ip, StackValue::Ttl(42));
+        let tos = StackOption::new(StackLeg::ClientProxy, StackLevel::Ip, StackValue::IpTos(0x10));
+        let backlog = StackOption::new(StackLeg::ProxyRemote, StackLevel::Tcp, StackValue::ListenBacklog(8));
+        let options = vec![ttl.wrap(), tos.wrap(), backlog.wrap()];
+
+        let destination = Address::try_from(target.local_addr()?)?;
+        let request = Socks6Request::new(crate::constants::SOCKS_CMD_CONNECT, destination, 0, options, None);
+
+        let mut stream = TcpStream::connect(proxy_addr).await?;
+        stream.write_all(&request.into_socks_bytes()).await?;
+        socks6::read_authentication_reply(&mut stream).await?;
+        let (_, options) = socks6::read_reply(&mut stream).await?;
+
+        let accepted: Vec<_> = options
+            .into_iter()
+            .filter_map(|o| match o {
+                SocksOption::Stack(stack) => Some(stack),
+                _ => None,
+            })
+            .collect();
+        let expected = StackOption::new(StackLeg::ProxyRemote, StackLevel::Ip, StackValue::Ttl(42));
+        assert_eq!(accepted, vec![expected]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn session_reuse() -> Result<()> {
+        use crate::auth::CallbackAuthenticator;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counter = attempts.clone();
+        let authenticator = CallbackAuthenticator::new(move |credentials: Credentials| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async move { Ok((credentials.password == b"secret").then(|| String::from("alice"))) }
+        });
+
+        let proxy_addr = spawn_proxy(Socks6Handler::default().with_authenticator(authenticator)).await?;
+        let target = TcpListener::bind("127.0.0.1:0").await?;
+        let destination = target.local_addr()?.to_string();
+
+        // Only the first request authenticates, the others refer to the session.
+        let client = Socks6Client::new(proxy_addr.to_string(), Some(Credentials::new("alice", "secret")))
+            .await?
+            .with_idempotence(2);
+        for _ in 0..3 {
+            client.connect(destination.clone(), Some(b"hello".to_vec()), None).await?;
+        }
+
+        assert!(client.session_id().is_some());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
 
-        let mut destination = if let Some(mut chain) = chain {
-            if let Some(next) = chain.next_link() {
-                let next = next.clone();
+        Ok(())
+    }
 
-                let proxy_addr = format!("{}:{}", next.host, next.port);
-                let client = Socks6Client::new(proxy_addr, next.credentials).await?;
+    #[tokio::test]
+    pub async fn idempotence_replay() -> Result<()> {
+        use crate::Address;
+        use std::convert::TryFrom;
 
-                let (outgoing, _) = client.connect(destination, None, Some(chain.as_options())).await?;
-                outgoing
-            } else {
-                TcpStream::connect(destination).await?
+        let proxy_addr = spawn_proxy(Socks6Handler::default()).await?;
+        let target = TcpListener::bind("127.0.0.1:0").await?;
+        let destination = Address::try_from(target.local_addr()?)?;
+
+        let send = |options: Vec<SocksOption>| {
+            let command = crate::constants::SOCKS_CMD_CONNECT;
+            let request = Socks6Request::new(command, destination.clone(), 0, options, None);
+            async move {
+                let mut stream = TcpStream::connect(proxy_addr).await?;
+                stream.write_all(&request.into_socks_bytes()).await?;
+                socks6::read_authentication_reply(&mut stream).await
             }
-        } else {
-            TcpStream::connect(destination).await?
         };
 
-        // Send initial data
-        if request.initial_data_length > 0 {
-            let mut initial_data = vec![0; request.initial_data_length as usize];
-            source.read_exact(&mut initial_data).await?;
-            destination.write(&initial_data).await?;
+        let reply = send(vec![
+            SessionOption::Request.wrap(),
+            IdempotenceOption::Request { window_size: 4 }.wrap(),
+        ])
+        .await?;
+
+        let (mut id, mut window) = (None, None);
+        for option in reply {
+            match option {
+                SocksOption::Session(SessionOption::Id(session_id)) => id = Some(session_id),
+                SocksOption::Idempotence(IdempotenceOption::Window { base, .. }) => window = Some(base),
+                _ => {}
+            }
         }
+        let (id, base) = (id.unwrap(), window.unwrap());
 
-        // Notify source that the connection has been set up.
-        socks6::write_reply(source, Socks6Reply::Success).await?;
-        source.flush().await?;
+        let spend = vec![
+            SessionOption::Id(id.clone()).wrap(),
+            IdempotenceOption::Expenditure { token: base }.wrap(),
+        ];
+        assert!(send(spend.clone()).await.is_ok());
+        assert!(send(spend).await.is_err());
 
-        Ok(destination)
+        let unknown = vec![SessionOption::Id(vec![0; 16]).wrap()];
+        assert!(send(unknown).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn bind() -> Result<()> {
+        let proxy_addr = spawn_proxy(Socks6Handler::default()).await?;
+
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        let initial_data = Some(b"hello".to_vec());
+        let (binding, accepted) = client.bind("127.0.0.1:0".to_string(), initial_data, None).await?;
+
+        let mut peer = TcpStream::connect(binding.to_string()).await?;
+        let (mut stream, peer_addr) = accepted.await?;
+        assert_eq!(peer_addr.to_string(), peer.local_addr()?.to_string());
+
+        let mut buffer = [0; 5];
+        peer.read_exact(&mut buffer).await?;
+        assert_eq!(&buffer, b"hello");
+
+        stream.write_all(b"ping").await?;
+        let mut buffer = [0; 4];
+        peer.read_exact(&mut buffer).await?;
+        assert_eq!(&buffer, b"ping");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn udp_associate() -> Result<()> {
+        let proxy_addr = spawn_proxy(Socks6Handler::default()).await?;
+
+        let echo = UdpSocket::bind("127.0.0.1:0").await?;
+        let echo_addr = echo.local_addr()?;
+        tokio::spawn(async move {
+            let mut buffer = [0; 64];
+            while let Ok((length, from)) = echo.recv_from(&mut buffer).await {
+                let _ = echo.send_to(&buffer[..length], from).await;
+            }
+        });
+
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        let datagram = client.udp_associate().await?;
+
+        datagram.send_to(b"ping", echo_addr.to_string()).await?;
+        let mut buffer = [0; 64];
+        let (length, from) = datagram.recv_from(&mut buffer).await?;
+        assert_eq!(&buffer[..length], b"ping");
+        assert_eq!(from.to_string(), echo_addr.to_string());
+
+        // Nothing listens on the port anymore, the ICMP error is reported by the proxy.
+        let closed_addr = UdpSocket::bind("127.0.0.1:0").await?.local_addr()?;
+        datagram.send_to(b"ping", closed_addr.to_string()).await?;
+        assert!(datagram.recv_from(&mut buffer).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn udp_not_allowed() -> Result<()> {
+        let denied = UdpSocket::bind("127.0.0.1:0").await?;
+        let denied_addr = denied.local_addr()?;
+        let allowed = UdpSocket::bind("127.0.0.1:0").await?;
+        let allowed_addr = allowed.local_addr()?;
+
+        let port = denied_addr.port();
+        let rule = Rule::deny().with_destination("127.0.0.1".parse()?).with_ports(port..=port);
+        let handler = Socks6Handler::default().with_access_control(AccessControl::new(vec![rule]));
+        let proxy_addr = spawn_proxy(handler).await?;
+
+        // The association is allowed, but datagrams to the denied destination are dropped.
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        let datagram = client.udp_associate().await?;
+        datagram.send_to(b"denied", denied_addr.to_string()).await?;
+        datagram.send_to(b"allowed", allowed_addr.to_string()).await?;
+
+        let mut buffer = [0; 64];
+        let (length, _) = allowed.recv_from(&mut buffer).await?;
+        assert_eq!(&buffer[..length], b"allowed");
+        assert!(denied.try_recv_from(&mut buffer).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn end_session() -> Result<()> {
+        let proxy_addr = spawn_proxy(Socks6Handler::default()).await?;
+
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?.with_session();
+        client.noop().await?;
+
+        let session_id = client.session_id().unwrap();
+        client.end_session().await?;
+        assert!(client.session_id().is_none());
+
+        // The proxy no longer knows the session, so the next request starts a new one.
+        client.noop().await?;
+        assert_ne!(client.session_id(), Some(session_id));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn connection_refused() -> Result<()> {
+        let proxy_addr = spawn_proxy(Socks6Handler::default()).await?;
+
+        // Nothing listens on the port anymore, so the proxy's connect is refused.
+        let destination = TcpListener::bind("127.0.0.1:0").await?.local_addr()?.to_string();
+
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        let error = client.connect(destination, None, None).await.unwrap_err();
+        match error.downcast_ref::<Error>() {
+            Some(Error::Refused(reply)) => assert_eq!(*reply, Socks6Reply::ConnectionRefused as u8),
+            _ => panic!("Unexpected error: {}", error),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn timeouts() -> Result<()> {
+        // The link accepts connections, but never replies.
+        let link = TcpListener::bind("127.0.0.1:0").await?;
+        let link_addr = link.local_addr()?;
+        let links = vec![ProxyAddress::new(6, link_addr.ip().to_string(), link_addr.port(), None)];
+
+        let timeouts = Timeouts::new()
+            .with_handshake(Duration::from_millis(50))
+            .with_connect(Duration::from_millis(50));
+        let proxy_addr = spawn_proxy(Socks6Handler::new(links).with_timeouts(timeouts)).await?;
+
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        let error = client.connect("127.0.0.1:1".to_string(), None, None).await.unwrap_err();
+        match error.downcast_ref::<Error>() {
+            Some(Error::Refused(reply)) => assert_eq!(*reply, Socks6Reply::ConnectionAttemptTimeOut as u8),
+            _ => panic!("Unexpected error: {}", error),
+        }
+
+        // Clients that don't send a request are disconnected.
+        let mut stream = TcpStream::connect(proxy_addr).await?;
+        assert_eq!(stream.read(&mut [0; 1]).await?, 0);
+
+        Ok(())
     }
 }
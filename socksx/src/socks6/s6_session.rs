@@ -0,0 +1,201 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Sessions that aren't used for this long are forgotten.
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+/// Upper bound on the number of tokens in an idempotence window.
+const MAX_WINDOW_SIZE: u32 = 1024;
+/// Length, in bytes, of the session IDs handed out.
+const SESSION_ID_LENGTH: usize = 16;
+/// Upper bound on the number of sessions kept, past it the least recently used are forgotten.
+const MAX_SESSIONS: usize = 4096;
+
+/// Keeps track of the sessions of a proxy, and of the idempotence tokens of each session.
+///
+/// [socks6-draft11] https://tools.ietf.org/html/draft-olteanu-intarea-socks-6-11#section-9
+#[derive(Debug)]
+pub(crate) struct SessionManager {
+    sessions: Mutex<HashMap<Vec<u8>, Session>>,
+    capacity: usize,
+}
+
+impl Default for SessionManager {
+    fn default() -> Self {
+        Self::new(MAX_SESSIONS)
+    }
+}
+
+#[derive(Debug)]
+struct Session {
+    identity: Option<String>,
+    window: Option<TokenWindow>,
+    last_used: Instant,
+}
+
+/// A window of single-use tokens: `base` up to (but excluding) `base + size`, wrapping around.
+#[derive(Debug)]
+struct TokenWindow {
+    base: u32,
+    spent: Vec<bool>,
+}
+
+impl SessionManager {
+    /// Creates a manager that keeps at most `capacity` sessions.
+    pub fn new(capacity: usize) -> Self {
+        SessionManager {
+            sessions: Mutex::new(HashMap::new()),
+            capacity,
+        }
+    }
+
+    /// Starts a session for the (authenticated) client, and returns its ID.
+    pub fn create(
+        &self,
+        identity: Option<String>,
+    ) -> Result<Vec<u8>> {
+        let mut id = vec![0; SESSION_ID_LENGTH];
+        getrandom::getrandom(&mut id)?;
+
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, s| s.last_used.elapsed() < SESSION_IDLE_TIMEOUT);
+        while sessions.len() >= self.capacity {
+            let oldest = sessions.iter().min_by_key(|(_, s)| s.last_used).map(|(id, _)| id.clone());
+            match oldest {
+                Some(oldest) => sessions.remove(&oldest),
+                None => break,
+            };
+        }
+        sessions.insert(
+            id.clone(),
+            Session {
+                identity,
+                window: None,
+                last_used: Instant::now(),
+            },
+        );
+
+        Ok(id)
+    }
+
+    /// Returns the identity the session was started for, or `None` if there's no such session.
+    pub fn resume(
+        &self,
+        id: &[u8],
+    ) -> Option<Option<String>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get_mut(id) {
+            Some(session) if session.last_used.elapsed() < SESSION_IDLE_TIMEOUT => {
+                session.last_used = Instant::now();
+                Some(session.identity.clone())
+            }
+            Some(_) => {
+                sessions.remove(id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Ends the session, unspent tokens can't be used afterwards.
+    pub fn teardown(
+        &self,
+        id: &[u8],
+    ) {
+        self.sessions.lock().unwrap().remove(id);
+    }
+
+    /// Assigns a fresh window of tokens to the session, replacing the previous one.
+    /// Returns the base and size of the window, which may be smaller than requested.
+    pub fn issue_window(
+        &self,
+        id: &[u8],
+        requested_size: u32,
+    ) -> Result<Option<(u32, u32)>> {
+        let size = requested_size.min(MAX_WINDOW_SIZE);
+        if size == 0 {
+            return Ok(None);
+        }
+
+        let mut base = [0; 4];
+        getrandom::getrandom(&mut base)?;
+        let base = u32::from_be_bytes(base);
+
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(session) = sessions.get_mut(id) {
+            let spent = vec![false; size as usize];
+            session.window = Some(TokenWindow { base, spent });
+
+            Ok(Some((base, size)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Spends a token of the session. Tokens outside the window, or already spent, are rejected.
+    pub fn spend(
+        &self,
+        id: &[u8],
+        token: u32,
+    ) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        let window = match sessions.get_mut(id).and_then(|s| s.window.as_mut()) {
+            Some(window) => window,
+            None => return false,
+        };
+
+        let offset = token.wrapping_sub(window.base) as usize;
+        match window.spent.get_mut(offset) {
+            Some(spent) if !*spent => {
+                *spent = true;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn spend_tokens() -> Result<()> {
+        let sessions = SessionManager::default();
+        let id = sessions.create(Some(String::from("alice")))?;
+        assert_eq!(sessions.resume(&id), Some(Some(String::from("alice"))));
+
+        // Without a window, there's nothing to spend.
+        assert!(!sessions.spend(&id, 0));
+
+        let (base, size) = sessions.issue_window(&id, 4)?.unwrap();
+        assert_eq!(size, 4);
+        assert!(sessions.spend(&id, base.wrapping_add(3)));
+        assert!(!sessions.spend(&id, base.wrapping_add(3)));
+        assert!(!sessions.spend(&id, base.wrapping_add(4)));
+        assert!(!sessions.spend(&id, base.wrapping_sub(1)));
+
+        sessions.teardown(&id);
+        assert_eq!(sessions.resume(&id), None);
+        assert!(!sessions.spend(&id, base));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn capacity() -> Result<()> {
+        let sessions = SessionManager::new(2);
+        let first = sessions.create(None)?;
+        let second = sessions.create(None)?;
+        assert!(sessions.resume(&first).is_some());
+
+        // The least recently used session makes room for a new one.
+        let third = sessions.create(None)?;
+        assert!(sessions.resume(&second).is_none());
+        assert!(sessions.resume(&first).is_some());
+        assert!(sessions.resume(&third).is_some());
+
+        Ok(())
+    }
+}
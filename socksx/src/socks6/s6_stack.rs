@@ -0,0 +1,264 @@
+use crate::socks6::options::{StackLeg, StackOption, StackValue};
+use crate::util::{enable_fast_open, set_socket_option};
+use crate::{Address, Error};
+use anyhow::Result;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{self, TcpListener, TcpSocket, TcpStream};
+
+/// Backlog of BIND listeners, unless the client asks for another one.
+const DEFAULT_LISTEN_BACKLOG: u32 = 1024;
+/// Delay between connection attempts when racing addresses, as recommended by RFC 8305.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connects to the destination, applies the stack options requested for the proxy-remote
+/// leg, and sends the initial data. Returns the stream, and the options that were applied.
+#[instrument(skip_all, fields(destination = %destination.to_string()))]
+pub(crate) async fn connect(
+    destination: &Address,
+    initial_data: &[u8],
+    options: &[StackOption],
+) -> Result<(TcpStream, Vec<StackOption>)> {
+    let requested: Vec<StackOption> = options.iter().filter(|o| o.applies_to_remote()).cloned().collect();
+
+    let addresses: Vec<SocketAddr> = match net::lookup_host(destination.to_string()).await {
+        Ok(addresses) => addresses.collect(),
+        Err(_) => bail!(Error::Resolve(destination.to_string())),
+    };
+    ensure!(!addresses.is_empty(), Error::Resolve(destination.to_string()));
+
+    let happy_eyeballs = requested.iter().find(|o| o.value == StackValue::HappyEyeballs(true));
+    if let (Some(happy_eyeballs), true) = (happy_eyeballs, addresses.len() > 1) {
+        // Only the winning connection may carry the initial data, so it's sent afterwards.
+        let (mut stream, mut applied) = race(addresses, &requested).await?;
+        stream.write_all(initial_data).await?;
+        applied.push(accepted(happy_eyeballs));
+
+        Ok((stream, applied))
+    } else {
+        connect_one(addresses[0], initial_data, &requested).await
+    }
+}
+
+/// Connects to a single address, with the stack options applied to the socket.
+async fn connect_one(
+    address: SocketAddr,
+    initial_data: &[u8],
+    requested: &[StackOption],
+) -> Result<(TcpStream, Vec<StackOption>)> {
+    let wants_mptcp = requested.iter().any(|o| o.value == StackValue::Mptcp(true));
+    let (socket, mptcp) = match wants_mptcp.then(|| new_mptcp_socket(address)).flatten() {
+        Some(socket) => (socket, true),
+        None if address.is_ipv4() => (TcpSocket::new_v4()?, false),
+        None => (TcpSocket::new_v6()?, false),
+    };
+
+    let fast_open = !initial_data.is_empty() && enable_fast_open(&socket);
+
+    let mut applied = vec![];
+    for option in requested {
+        let is_applied = match option.value {
+            StackValue::IpTos(tos) => set_tos(&socket, address, tos),
+            StackValue::Ttl(ttl) => set_ttl(&socket, address, ttl),
+            StackValue::NoFragmentation(true) => set_no_fragmentation(&socket, address),
+            StackValue::TcpFastOpen(_) => fast_open,
+            StackValue::Mptcp(true) => mptcp,
+            _ => false,
+        };
+
+        if is_applied {
+            applied.push(accepted(option));
+        }
+    }
+
+    let mut stream = socket.connect(address).await.map_err(|e| Error::connect(address, e))?;
+    stream.write_all(initial_data).await?;
+
+    Ok((stream, applied))
+}
+
+/// Listens on the address for a BIND request, with the stack options that apply to a listener.
+/// Returns the listener, and the options that were applied.
+pub(crate) fn listen(
+    address: SocketAddr,
+    options: &[StackOption],
+) -> Result<(TcpListener, Vec<StackOption>)> {
+    let socket = if address.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+
+    let mut backlog = DEFAULT_LISTEN_BACKLOG;
+    let mut applied = vec![];
+    for option in options.iter().filter(|o| o.applies_to_remote()) {
+        let is_applied = match option.value {
+            StackValue::IpTos(tos) => set_tos(&socket, address, tos),
+            StackValue::Ttl(ttl) => set_ttl(&socket, address, ttl),
+            StackValue::ListenBacklog(requested) => {
+                backlog = requested as u32;
+                true
+            }
+            _ => false,
+        };
+
+        if is_applied {
+            applied.push(accepted(option));
+        }
+    }
+
+    socket.bind(address)?;
+    let listener = socket.listen(backlog)?;
+
+    Ok((listener, applied))
+}
+
+/// Races connection attempts to the addresses, with staggered starts, and returns the first
+/// that succeeds. Address families are interleaved, so that a broken one can't stall us.
+///
+/// [rfc8305] https://tools.ietf.org/html/rfc8305
+async fn race(
+    addresses: Vec<SocketAddr>,
+    requested: &[StackOption],
+) -> Result<(TcpStream, Vec<StackOption>)> {
+    let mut pending = interleave(addresses).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+
+    loop {
+        if let Some(address) = pending.next() {
+            attempts.push(connect_one(address, &[], requested));
+        }
+
+        if attempts.is_empty() {
+            break;
+        }
+
+        // Start the next attempt on failure, or when the current attempts take too long.
+        tokio::select! {
+            Some(result) = attempts.next() => match result {
+                Ok(connection) => return Ok(connection),
+                Err(error) => last_error = Some(error),
+            },
+            _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY), if pending.len() > 0 => {}
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| anyhow!("No addresses to connect to.")))
+}
+
+/// Orders the addresses such that the address families alternate, starting with the first.
+fn interleave(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let prefer_ipv6 = addresses.first().map(|a| a.is_ipv6()).unwrap_or_default();
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) = addresses.into_iter().partition(|a| a.is_ipv6() == prefer_ipv6);
+    preferred.reverse();
+    other.reverse();
+
+    let mut interleaved = vec![];
+    while !preferred.is_empty() || !other.is_empty() {
+        interleaved.extend(preferred.pop());
+        interleaved.extend(other.pop());
+    }
+
+    interleaved
+}
+
+/// The option as reported back to the client: it only applies to the proxy-remote leg.
+fn accepted(option: &StackOption) -> StackOption {
+    StackOption::new(StackLeg::ProxyRemote, option.level, option.value.clone())
+}
+
+fn set_tos(
+    socket: &TcpSocket,
+    address: SocketAddr,
+    tos: u8,
+) -> bool {
+    if address.is_ipv4() {
+        set_socket_option(socket, libc::IPPROTO_IP, libc::IP_TOS, tos as libc::c_int)
+    } else {
+        set_socket_option(socket, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos as libc::c_int)
+    }
+}
+
+fn set_ttl(
+    socket: &TcpSocket,
+    address: SocketAddr,
+    ttl: u8,
+) -> bool {
+    if address.is_ipv4() {
+        set_socket_option(socket, libc::IPPROTO_IP, libc::IP_TTL, ttl as libc::c_int)
+    } else {
+        set_socket_option(socket, libc::IPPROTO_IPV6, libc::IPV6_UNICAST_HOPS, ttl as libc::c_int)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_no_fragmentation(
+    socket: &TcpSocket,
+    address: SocketAddr,
+) -> bool {
+    if address.is_ipv4() {
+        set_socket_option(socket, libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, libc::IP_PMTUDISC_DO)
+    } else {
+        set_socket_option(
+            socket,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_DO,
+        )
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_no_fragmentation(
+    _socket: &TcpSocket,
+    _address: SocketAddr,
+) -> bool {
+    false
+}
+
+/// Creates a Multipath TCP socket, if the kernel supports it.
+#[cfg(target_os = "linux")]
+fn new_mptcp_socket(address: SocketAddr) -> Option<TcpSocket> {
+    use std::os::unix::io::FromRawFd;
+
+    let domain = if address.is_ipv4() {
+        libc::AF_INET
+    } else {
+        libc::AF_INET6
+    };
+    let flags = libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC;
+
+    let fd = unsafe { libc::socket(domain, flags, libc::IPPROTO_MPTCP) };
+    if fd < 0 {
+        debug!("Multipath TCP is unavailable: {}", std::io::Error::last_os_error());
+        return None;
+    }
+
+    Some(unsafe { TcpSocket::from_raw_fd(fd) })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn new_mptcp_socket(_address: SocketAddr) -> Option<TcpSocket> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn interleave_families() {
+        let addresses = vec![
+            "[::1]:1".parse().unwrap(),
+            "[::2]:1".parse().unwrap(),
+            "127.0.0.1:1".parse().unwrap(),
+            "127.0.0.2:1".parse().unwrap(),
+        ];
+
+        let order: Vec<String> = interleave(addresses).iter().map(|a| a.to_string()).collect();
+        assert_eq!(order, vec!["[::1]:1", "127.0.0.1:1", "[::2]:1", "127.0.0.2:1"]);
+    }
+}
@@ -0,0 +1,119 @@
+use crate::constants::SOCKS_METADATA_TRACE_CONTEXT;
+use crate::socks6::options::{MetadataOption, SocksOption};
+use opentelemetry::context::FutureExt;
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::Context;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use std::collections::HashMap;
+use std::future::Future;
+use tracing::{Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+const TRACEPARENT: &str = "traceparent";
+const TRACESTATE: &str = "tracestate";
+
+/// Returns the trace context of the current span as a metadata option, so that the proxy can
+/// continue the trace. The value is the `traceparent`, followed by the `tracestate` on a line of
+/// its own if there is one. Without a trace, there's no option.
+pub(crate) fn inject() -> Option<SocksOption> {
+    let mut context = Span::current().context();
+    if !context.span().span_context().is_valid() {
+        // Without an OpenTelemetry layer, spans have no context. The trace of the client, if
+        // this is a proxy, is still passed on.
+        context = Context::current();
+    }
+
+    let mut carrier = HashMap::new();
+    TraceContextPropagator::new().inject_context(&context, &mut carrier);
+
+    let traceparent = carrier.remove(TRACEPARENT)?;
+    let value = match carrier.remove(TRACESTATE).filter(|s| !s.is_empty()) {
+        Some(tracestate) => format!("{}\n{}", traceparent, tracestate),
+        None => traceparent,
+    };
+
+    Some(MetadataOption::new(SOCKS_METADATA_TRACE_CONTEXT, value).wrap())
+}
+
+/// Reads the trace context the client sent along with its request, if any.
+pub(crate) fn extract(metadata: &HashMap<u16, String>) -> Option<Context> {
+    let value = metadata.get(&SOCKS_METADATA_TRACE_CONTEXT)?;
+
+    let mut carrier = HashMap::new();
+    let mut lines = value.splitn(2, '\n');
+    carrier.insert(TRACEPARENT.to_string(), lines.next()?.to_string());
+    if let Some(tracestate) = lines.next() {
+        carrier.insert(TRACESTATE.to_string(), tracestate.to_string());
+    }
+
+    let context = TraceContextPropagator::new().extract_with_context(&Context::new(), &carrier);
+    Some(context).filter(|c| c.span().span_context().is_valid())
+}
+
+/// Runs the rest of a request in the trace of the client, if it sent one. The current span
+/// already has children, e.g., the one of reading the request, so it can't join that trace.
+/// Instead, a new span does, linking back to the current one.
+pub(crate) async fn continue_trace<F: Future>(
+    metadata: &HashMap<u16, String>,
+    request: F,
+) -> F::Output {
+    let parent = match extract(metadata) {
+        Some(parent) => parent,
+        None => return request.await,
+    };
+
+    let trace_id = parent.span().span_context().trace_id();
+    let span = info_span!("request", %trace_id);
+    let _ = span.set_parent(parent.clone());
+    span.add_link(Span::current().context().span().span_context().clone());
+
+    request.instrument(span).with_context(parent).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    /// Returns the metadata a proxy receives, if the option is sent.
+    fn received(option: Option<SocksOption>) -> HashMap<u16, String> {
+        match option {
+            Some(SocksOption::Metadata(MetadataOption { key, value })) => vec![(key, value)].into_iter().collect(),
+            _ => HashMap::new(),
+        }
+    }
+
+    #[test]
+    pub fn propagate() -> Result<()> {
+        assert!(inject().is_none());
+
+        // The trace of the current span is injected, and extracted as is.
+        let tracer = SdkTracerProvider::builder().build().tracer("socksx");
+        let subscriber = tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+        let (span_context, metadata) = tracing::subscriber::with_default(subscriber, || {
+            let span = info_span!("client");
+            let _entered = span.enter();
+
+            (span.context().span().span_context().clone(), received(inject()))
+        });
+        assert_eq!(metadata[&SOCKS_METADATA_TRACE_CONTEXT].len(), 55);
+
+        let parent = extract(&metadata).unwrap();
+        assert_eq!(parent.span().span_context().trace_id(), span_context.trace_id());
+        assert_eq!(parent.span().span_context().span_id(), span_context.span_id());
+
+        // A proxy that doesn't trace passes the context of its client on.
+        let _attached = parent.attach();
+        assert_eq!(received(inject()), metadata);
+
+        let mut invalid = HashMap::new();
+        invalid.insert(SOCKS_METADATA_TRACE_CONTEXT, String::from("00-00-00-00"));
+        assert!(extract(&invalid).is_none());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,518 @@
+use crate::acl::{AccessControl, AccessRequest, Command};
+use crate::addresses::{self, Address};
+use crate::constants::*;
+use crate::SocksStream;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+
+const MAX_DATAGRAM_SIZE: usize = 65535;
+/// Upper bound on the number of destinations a single association relays to.
+const MAX_FLOWS: usize = 256;
+/// Version, message type, message length, and association ID.
+const HEADER_LENGTH: usize = 12;
+
+/// Why a datagram couldn't be delivered, as reported by an ICMP error.
+///
+/// [socks6-draft11] https://tools.ietf.org/html/draft-olteanu-intarea-socks-6-11#section-8.3
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UdpError {
+    NetworkUnreachable = 0x01,
+    HostUnreachable = 0x02,
+    TtlExpired = 0x03,
+    DatagramTooBig = 0x04,
+}
+
+impl UdpError {
+    pub fn from_u8(error: u8) -> Option<Self> {
+        match error {
+            0x01 => Some(UdpError::NetworkUnreachable),
+            0x02 => Some(UdpError::HostUnreachable),
+            0x03 => Some(UdpError::TtlExpired),
+            0x04 => Some(UdpError::DatagramTooBig),
+            _ => None,
+        }
+    }
+
+    /// Maps the error of a (connected) UDP socket, which is how the OS surfaces ICMP errors.
+    fn from_io_error(error: &io::Error) -> Option<Self> {
+        match error.raw_os_error()? {
+            libc::ENETUNREACH => Some(UdpError::NetworkUnreachable),
+            // A closed port (ICMP port unreachable) is reported as a refused connection.
+            libc::EHOSTUNREACH | libc::ECONNREFUSED => Some(UdpError::HostUnreachable),
+            libc::EMSGSIZE => Some(UdpError::DatagramTooBig),
+            _ => None,
+        }
+    }
+}
+
+/// The messages of a UDP association. All start with a common header: version, message
+/// type, message length (including the header), and the ID of the association. Datagrams
+/// are sent over UDP, the other messages over the TCP connection of the association.
+///
+/// [socks6-draft11] https://tools.ietf.org/html/draft-olteanu-intarea-socks-6-11#section-8.3
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum UdpMessage {
+    AssociationInit {
+        association: u64,
+    },
+    AssociationAck {
+        association: u64,
+    },
+    Datagram {
+        association: u64,
+        address: Address,
+        payload: Vec<u8>,
+    },
+    Error {
+        association: u64,
+        address: Address,
+        error: UdpError,
+    },
+}
+
+impl UdpMessage {
+    pub fn association(&self) -> u64 {
+        match self {
+            UdpMessage::AssociationInit { association }
+            | UdpMessage::AssociationAck { association }
+            | UdpMessage::Datagram { association, .. }
+            | UdpMessage::Error { association, .. } => *association,
+        }
+    }
+
+    /// Encodes the message, failing if it doesn't fit the 16-bit length field.
+    pub fn into_socks_bytes(self) -> Result<Vec<u8>> {
+        let association = self.association();
+        let (kind, body) = match self {
+            UdpMessage::AssociationInit { .. } => (SOCKS_UDP_MSG_ASSOC_INIT, vec![]),
+            UdpMessage::AssociationAck { .. } => (SOCKS_UDP_MSG_ASSOC_ACK, vec![]),
+            UdpMessage::Datagram { address, payload, .. } => {
+                let mut body = address.as_socks_bytes();
+                body.extend(payload);
+
+                (SOCKS_UDP_MSG_DATAGRAM, body)
+            }
+            UdpMessage::Error { address, error, .. } => {
+                let mut body = vec![error as u8];
+                body.extend(address.as_socks_bytes());
+
+                (SOCKS_UDP_MSG_ERROR, body)
+            }
+        };
+
+        let length = HEADER_LENGTH + body.len();
+        ensure!(length <= u16::MAX as usize, "UDP message is too long: {} bytes.", length);
+
+        let mut data = vec![SOCKS_VER_6, kind];
+        data.extend((length as u16).to_be_bytes());
+        data.extend(association.to_be_bytes());
+        data.extend(body);
+
+        Ok(data)
+    }
+
+    pub async fn from_socks_bytes(bytes: &[u8]) -> Result<Self> {
+        ensure!(bytes.len() >= HEADER_LENGTH, "UDP message is too short to hold a header.");
+        ensure!(bytes[0] == SOCKS_VER_6, "UDP message has a different SOCKS version: {}.", bytes[0]);
+
+        let kind = bytes[1];
+        let length = u16::from_be_bytes([bytes[2], bytes[3]]) as usize;
+        ensure!(length == bytes.len(), "UDP message length doesn't match: {}.", length);
+
+        let association = u64::from_be_bytes(bytes[4..HEADER_LENGTH].try_into()?);
+        let mut body = &bytes[HEADER_LENGTH..];
+
+        let message = match kind {
+            SOCKS_UDP_MSG_ASSOC_INIT => UdpMessage::AssociationInit { association },
+            SOCKS_UDP_MSG_ASSOC_ACK => UdpMessage::AssociationAck { association },
+            SOCKS_UDP_MSG_DATAGRAM => {
+                let address = addresses::read_address(&mut body).await?;
+                let payload = body.to_vec();
+
+                UdpMessage::Datagram {
+                    association,
+                    address,
+                    payload,
+                }
+            }
+            SOCKS_UDP_MSG_ERROR => {
+                ensure!(!body.is_empty(), "UDP error message is truncated.");
+                let error = UdpError::from_u8(body[0]).ok_or_else(|| anyhow!("Unknown UDP error: {}.", body[0]))?;
+
+                let mut body = &body[1..];
+                let address = addresses::read_address(&mut body).await?;
+
+                UdpMessage::Error {
+                    association,
+                    address,
+                    error,
+                }
+            }
+            _ => bail!("Unknown UDP message type: {}.", kind),
+        };
+
+        Ok(message)
+    }
+}
+
+/// Reads a single message from the TCP connection of an association.
+pub(crate) async fn read_message<S>(stream: &mut S) -> Result<UdpMessage>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut message = vec![0; 4];
+    stream.read_exact(&mut message).await?;
+
+    let length = u16::from_be_bytes([message[2], message[3]]) as usize;
+    ensure!(length >= HEADER_LENGTH, "UDP message is too short to hold a header.");
+
+    message.resize(length, 0);
+    stream.read_exact(&mut message[4..]).await?;
+
+    UdpMessage::from_socks_bytes(&message).await
+}
+
+/// Writes a single message to the TCP connection of an association.
+pub(crate) async fn write_message<S>(
+    stream: &mut S,
+    message: UdpMessage,
+) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    stream.write_all(&message.into_socks_bytes()?).await?;
+
+    Ok(())
+}
+
+/// A UDP association with a SOCKS6 proxy. Datagrams are relayed by the proxy,
+/// for as long as this value (and thus the TCP connection of the association) is alive.
+///
+/// [socks6-draft11] https://tools.ietf.org/html/draft-olteanu-intarea-socks-6-11#section-8.3
+pub struct Socks6Datagram {
+    socket: UdpSocket,
+    relay_addr: SocketAddr,
+    association: u64,
+    errors: Mutex<mpsc::Receiver<UdpMessage>>,
+    control: JoinHandle<()>,
+}
+
+impl Socks6Datagram {
+    /// Wraps a local socket, the relay it sends to, and the TCP connection of the association.
+    pub(crate) fn new(
+        socket: UdpSocket,
+        relay_addr: SocketAddr,
+        association: u64,
+        mut control: SocksStream,
+    ) -> Self {
+        // The proxy reports undeliverable datagrams over TCP, these surface in `recv_from`.
+        let (errors_tx, errors) = mpsc::channel(16);
+        let control = tokio::spawn(async move {
+            while let Ok(message) = read_message(&mut control).await {
+                if let UdpMessage::Error { .. } = message {
+                    if errors_tx.send(message).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        Socks6Datagram {
+            socket,
+            relay_addr,
+            association,
+            errors: Mutex::new(errors),
+            control,
+        }
+    }
+
+    /// The address of the UDP relay at the proxy.
+    pub fn relay_addr(&self) -> SocketAddr {
+        self.relay_addr
+    }
+
+    /// The local address datagrams are sent from.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Sends a datagram to the destination, through the proxy. Returns the number of payload bytes sent.
+    pub async fn send_to<A>(
+        &self,
+        buf: &[u8],
+        destination: A,
+    ) -> Result<usize>
+    where
+        A: TryInto<Address, Error = anyhow::Error>,
+    {
+        let address: Address = destination.try_into()?;
+        let max_length = u16::MAX as usize - HEADER_LENGTH - address.as_socks_bytes().len();
+        ensure!(buf.len() <= max_length, "Datagram is too large: {} bytes, at most {} fit.", buf.len(), max_length);
+
+        let datagram = UdpMessage::Datagram {
+            association: self.association,
+            address,
+            payload: buf.to_vec(),
+        };
+
+        self.socket.send_to(&datagram.into_socks_bytes()?, self.relay_addr).await?;
+
+        Ok(buf.len())
+    }
+
+    /// Receives a datagram relayed by the proxy. Returns the number of payload bytes, and the sender.
+    /// Fails if the proxy reports that an earlier datagram couldn't be delivered.
+    pub async fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> Result<(usize, Address)> {
+        let mut datagram = vec![0; MAX_DATAGRAM_SIZE];
+        let mut errors = self.errors.lock().await;
+
+        loop {
+            let (length, from) = tokio::select! {
+                received = self.socket.recv_from(&mut datagram) => received?,
+                Some(error) = errors.recv() => {
+                    if let UdpMessage::Error { address, error, .. } = error {
+                        bail!("Datagram to {} couldn't be delivered: {:?}.", address.to_string(), error);
+                    }
+                    continue;
+                }
+            };
+
+            if from != self.relay_addr {
+                continue;
+            }
+
+            match UdpMessage::from_socks_bytes(&datagram[..length]).await {
+                Ok(UdpMessage::Datagram {
+                    association,
+                    address,
+                    payload,
+                }) if association == self.association => {
+                    let length = payload.len().min(buf.len());
+                    buf[..length].copy_from_slice(&payload[..length]);
+
+                    return Ok((length, address));
+                }
+                _ => continue,
+            }
+        }
+    }
+}
+
+impl Drop for Socks6Datagram {
+    fn drop(&mut self) {
+        // Closes the TCP connection, and with that the association.
+        self.control.abort();
+    }
+}
+
+/// Sockets, connected to a single destination each, that datagrams are relayed through. A
+/// connected socket is told about ICMP errors, so these can be reported back to the client.
+#[derive(Default)]
+struct Flows {
+    sockets: HashMap<SocketAddr, (Arc<UdpSocket>, JoinHandle<()>)>,
+}
+
+impl Flows {
+    /// Returns the socket for the destination, replies and errors are sent to `events`.
+    async fn get_or_connect(
+        &mut self,
+        destination: SocketAddr,
+        events: &mpsc::Sender<(SocketAddr, io::Result<Vec<u8>>)>,
+    ) -> Result<Arc<UdpSocket>> {
+        if let Some((socket, _)) = self.sockets.get(&destination) {
+            return Ok(socket.clone());
+        }
+
+        ensure!(self.sockets.len() < MAX_FLOWS, "Association relays to too many destinations.");
+
+        let local_ip = if destination.is_ipv4() {
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        } else {
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        };
+
+        let socket = Arc::new(UdpSocket::bind(SocketAddr::new(local_ip, 0)).await?);
+        socket.connect(destination).await?;
+
+        let receiver = socket.clone();
+        let events = events.clone();
+        let task = tokio::spawn(async move {
+            let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+            loop {
+                let event = receiver.recv(&mut buffer).await.map(|length| buffer[..length].to_vec());
+                if events.send((destination, event)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        self.sockets.insert(destination, (socket.clone(), task));
+
+        Ok(socket)
+    }
+}
+
+impl Drop for Flows {
+    fn drop(&mut self) {
+        for (_, task) in self.sockets.values() {
+            task.abort();
+        }
+    }
+}
+
+/// Relays datagrams for an association, until the client closes the TCP connection. The first
+/// valid datagram from the client is acknowledged, and undeliverable datagrams are reported.
+/// Datagrams to destinations the access control rules deny are dropped.
+pub(crate) async fn relay(
+    socket: &UdpSocket,
+    control: &mut SocksStream,
+    association: u64,
+    client_ip: IpAddr,
+    access_control: Option<&AccessControl>,
+    user: Option<&str>,
+) -> Result<()> {
+    write_message(control, UdpMessage::AssociationInit { association }).await?;
+
+    let mut client_addr = None;
+    let mut flows = Flows::default();
+    let (events_tx, mut events) = mpsc::channel(64);
+
+    let mut datagram = vec![0; MAX_DATAGRAM_SIZE];
+    let mut control_buffer = [0; 512];
+
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut datagram) => {
+                let (length, from) = received?;
+                if from.ip() != client_ip || client_addr.map(|c| c != from).unwrap_or(false) {
+                    continue;
+                }
+
+                let (destination, payload) = match UdpMessage::from_socks_bytes(&datagram[..length]).await {
+                    Ok(UdpMessage::Datagram { association: id, address, payload }) if id == association => {
+                        (address, payload)
+                    }
+                    Ok(_) => {
+                        debug!("Dropping datagram from {}: not for association {}.", from, association);
+                        continue;
+                    }
+                    Err(error) => {
+                        debug!("Dropping datagram from {}: {}", from, error);
+                        continue;
+                    }
+                };
+
+                if client_addr.is_none() {
+                    client_addr = Some(from);
+                    write_message(control, UdpMessage::AssociationAck { association }).await?;
+                }
+
+                if let Some(access_control) = access_control {
+                    let request = AccessRequest {
+                        source: client_ip,
+                        user,
+                        destination: Some(&destination),
+                        command: Command::UdpAssociate,
+                    };
+                    if let Err(error) = access_control.authorize(&request).await {
+                        debug!("Dropping datagram from {}: {}", from, error);
+                        continue;
+                    }
+                }
+
+                // Delivery is best-effort, a single undeliverable datagram shouldn't end the association.
+                let sent = match crate::resolve_addr(destination.to_string()).await {
+                    Ok(resolved) => match flows.get_or_connect(resolved, &events_tx).await {
+                        Ok(flow) => flow.send(&payload).await.map_err(anyhow::Error::from),
+                        Err(error) => Err(error),
+                    },
+                    Err(error) => Err(error),
+                };
+
+                if let Err(error) = sent {
+                    debug!("Dropping datagram for {}: {}", destination.to_string(), error);
+
+                    let error = error.downcast_ref::<io::Error>().and_then(UdpError::from_io_error);
+                    if let Some(error) = error {
+                        write_message(control, UdpMessage::Error { association, address: destination, error }).await?;
+                    }
+                }
+            }
+            Some((from, event)) = events.recv() => match event {
+                Ok(payload) => {
+                    if let Some(client_addr) = client_addr {
+                        let reply = UdpMessage::Datagram { association, address: Address::Ip(from), payload };
+                        match reply.into_socks_bytes() {
+                            Ok(reply) => socket.send_to(&reply, client_addr).await?,
+                            Err(error) => {
+                                debug!("Dropping datagram from {}: {}", from, error);
+                                continue;
+                            }
+                        };
+                    }
+                }
+                Err(error) => {
+                    if let Some(error) = UdpError::from_io_error(&error) {
+                        let address = Address::Ip(from);
+                        write_message(control, UdpMessage::Error { association, address, error }).await?;
+                    }
+                }
+            },
+            read = control.read(&mut control_buffer) => {
+                // Only the proxy sends messages over TCP, the association ends when the client closes it.
+                if read? == 0 {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    pub async fn roundtrip_messages() -> Result<()> {
+        let messages = vec![
+            UdpMessage::AssociationInit { association: 42 },
+            UdpMessage::Datagram {
+                association: 42,
+                address: Address::new("example.com", 53),
+                payload: b"payload".to_vec(),
+            },
+            UdpMessage::Error {
+                association: 42,
+                address: Address::new("127.0.0.1", 53),
+                error: UdpError::HostUnreachable,
+            },
+        ];
+
+        for message in messages {
+            let bytes = message.clone().into_socks_bytes()?;
+            assert_eq!(read_message(&mut &bytes[..]).await?, message);
+        }
+
+        // The length field is 16 bits, longer messages can't be framed.
+        let oversized = UdpMessage::Datagram {
+            association: 42,
+            address: Address::new("127.0.0.1", 53),
+            payload: vec![0; u16::MAX as usize],
+        };
+        assert!(oversized.into_socks_bytes().is_err());
+
+        Ok(())
+    }
+}